@@ -0,0 +1,220 @@
+// Assembling a monitor from its parts, and running it
+use crate::config::{self, Config, ScanConfig};
+use crate::history::History;
+use crate::parsers::{self, Parser};
+use crate::source::{self, DemoSource, TransactionSource};
+use crate::storage::{FileStorage, NullStorage, Storage};
+use crate::price::{self, PriceSource};
+use crate::{health, tokens, FeedMetrics, LiveTransaction, MetaprotocolMonitor};
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, watch, RwLock};
+use tokio::task::JoinHandle;
+
+/// Block summaries buffered for live feed subscribers.
+const BLOCK_CHANNEL_CAPACITY: usize = 16;
+/// How often shutdown checks whether the live feed clients have gone.
+const CLIENT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Builds a [`MetaprotocolMonitor`] for embedding. Anything not set falls
+/// back to the defaults of [`Config`].
+pub struct MonitorBuilder {
+    source: Option<Arc<dyn TransactionSource>>,
+    backend: config::BackendConfig,
+    parsers: Vec<Parser>,
+    storage: Arc<dyn Storage>,
+    scan: ScanConfig,
+    channel_capacity: usize,
+    demo: bool,
+    prices: Option<Arc<dyn PriceSource>>,
+    price: config::PriceConfig,
+}
+
+impl Default for MonitorBuilder {
+    fn default() -> Self {
+        Self {
+            source: None,
+            backend: config::BackendConfig::default(),
+            parsers: parsers::REGISTRY.to_vec(),
+            storage: Arc::new(NullStorage),
+            scan: ScanConfig::default(),
+            channel_capacity: config::FeedConfig::default().broadcast_capacity,
+            demo: false,
+            prices: None,
+            price: config::PriceConfig::default(),
+        }
+    }
+}
+
+impl MonitorBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Everything `config` describes: backend, scan policy, channel
+    /// capacity, state file, price source and demo mode.
+    pub fn from_config(config: &Config) -> Self {
+        let storage: Arc<dyn Storage> = match &config.storage.path {
+            Some(path) => Arc::new(FileStorage::new(path)),
+            None => Arc::new(NullStorage),
+        };
+
+        Self {
+            backend: config.backend.clone(),
+            storage,
+            scan: config.scan.clone(),
+            channel_capacity: config.feed.broadcast_capacity,
+            demo: config.server.demo,
+            price: config.price.clone(),
+            ..Self::default()
+        }
+    }
+
+    /// Fetch from `source` rather than the configured backend.
+    pub fn source(mut self, source: impl TransactionSource + 'static) -> Self {
+        self.source = Some(Arc::new(source));
+        self
+    }
+
+    /// Parsers run on every transaction, replacing the built-in set.
+    pub fn parsers(mut self, parsers: impl IntoIterator<Item = Parser>) -> Self {
+        self.parsers = parsers.into_iter().collect();
+        self
+    }
+
+    pub fn storage(mut self, storage: impl Storage + 'static) -> Self {
+        self.storage = Arc::new(storage);
+        self
+    }
+
+    pub fn scan_policy(mut self, scan: ScanConfig) -> Self {
+        self.scan = scan;
+        self
+    }
+
+    /// Live feed events buffered before slow subscribers start lagging.
+    pub fn channel_capacity(mut self, capacity: usize) -> Self {
+        self.channel_capacity = capacity;
+        self
+    }
+
+    /// Price USD values with `prices` rather than the configured source.
+    pub fn price_source(mut self, prices: impl PriceSource + 'static) -> Self {
+        self.prices = Some(Arc::new(prices));
+        self
+    }
+
+    /// Replace the source with [`DemoSource`], which generates simulated
+    /// transactions.
+    pub fn demo(mut self, demo: bool) -> Self {
+        self.demo = demo;
+        self
+    }
+
+    /// Validate the settings and restore any saved state. Nothing runs
+    /// until [`MonitorHandle::start`].
+    pub fn build(self) -> anyhow::Result<MonitorHandle> {
+        let (monitor, _) = self.into_monitor()?;
+        let (shutdown, _) = watch::channel(false);
+
+        Ok(MonitorHandle {
+            monitor: Arc::new(monitor),
+            shutdown,
+            tasks: Vec::new(),
+        })
+    }
+
+    pub(crate) fn into_monitor(self) -> anyhow::Result<(MetaprotocolMonitor, broadcast::Receiver<LiveTransaction>)> {
+        self.scan.validate()?;
+        if self.channel_capacity == 0 {
+            anyhow::bail!("channel capacity must be at least 1");
+        }
+        if self.parsers.is_empty() {
+            anyhow::bail!("at least one parser is required");
+        }
+
+        let snapshot = self.storage.load()?.unwrap_or_default();
+        let mut tokens = tokens::TokenRegistry::new();
+        for tx in &snapshot.history {
+            tokens.apply(tx);
+        }
+
+        let (tx, rx) = broadcast::channel(self.channel_capacity);
+        let source = match self.source {
+            _ if self.demo => Arc::new(DemoSource::new()),
+            Some(source) => source,
+            None => source::from_config(&self.backend)?,
+        };
+        let prices = match self.prices {
+            Some(prices) => Some(prices),
+            None => price::from_config(&self.price)?,
+        };
+
+        let monitor = MetaprotocolMonitor {
+            source,
+            parsers: self.parsers,
+            storage: self.storage,
+            scan: self.scan.clone(),
+            channel_capacity: self.channel_capacity,
+            demo: self.demo,
+            tx_broadcaster: tx,
+            block_broadcaster: broadcast::channel(BLOCK_CHANNEL_CAPACITY).0,
+            stats: Arc::new(RwLock::new(snapshot.stats)),
+            history: RwLock::new(History::new(snapshot.history)),
+            last_seq: AtomicU64::new(snapshot.last_seq),
+            feed_metrics: Arc::new(FeedMetrics::default()),
+            tokens: RwLock::new(tokens),
+            prices,
+            watchlist: RwLock::new(snapshot.watchlist),
+            sync: RwLock::new(health::SyncState {
+                demo: self.demo,
+                mempool: health::ScanStatus::new(self.scan.mempool_interval()),
+                blocks: health::ScanStatus::new(self.scan.block_interval()),
+                synced_height: snapshot.synced_height,
+            }),
+            closing: watch::channel(false).0,
+        };
+
+        Ok((monitor, rx))
+    }
+}
+
+/// A built monitor and the tasks driving it.
+pub struct MonitorHandle {
+    monitor: Arc<MetaprotocolMonitor>,
+    shutdown: watch::Sender<bool>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl MonitorHandle {
+    pub fn monitor(&self) -> Arc<MetaprotocolMonitor> {
+        self.monitor.clone()
+    }
+
+    /// Spawn the scanners, or the demo generator. Calling it again while
+    /// running does nothing.
+    pub fn start(&mut self) {
+        if self.tasks.is_empty() {
+            self.tasks = self.monitor.clone().spawn_tasks(self.shutdown.subscribe());
+        }
+    }
+
+    /// Stop the scanners once the event in hand is processed and save
+    /// state, then close the live feed and wait for its subscribers to
+    /// disconnect. Callers wanting a deadline should wrap this in a timeout.
+    pub async fn shutdown(mut self) -> anyhow::Result<()> {
+        let _ = self.shutdown.send(true);
+        for task in self.tasks.drain(..) {
+            let _ = task.await;
+        }
+        let saved = self.monitor.save().await;
+
+        self.monitor.close_feeds();
+        let feed = self.monitor.feed_metrics();
+        while feed.snapshot().connected_clients > 0 {
+            tokio::time::sleep(CLIENT_POLL_INTERVAL).await;
+        }
+        saved
+    }
+}
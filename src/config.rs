@@ -0,0 +1,470 @@
+// Runtime settings: defaults, then a TOML file, then environment overrides
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Prefix of every environment variable that overrides a setting.
+pub const ENV_PREFIX: &str = "BTC_MONITOR_";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub backend: BackendConfig,
+    pub scan: ScanConfig,
+    pub feed: FeedConfig,
+    pub storage: StorageConfig,
+    pub price: PriceConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub port: u16,
+    /// Generate simulated transactions instead of scanning the backend.
+    pub demo: bool,
+    /// How long a graceful shutdown may take before the process exits anyway.
+    pub shutdown_timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    Esplora,
+    Rpc,
+    /// Transactions and blocks read from a fixture file.
+    Replay,
+    /// Blocks read from a bitcoind datadir's block files.
+    Blockfile,
+    /// Transactions and blocks relayed by a peer on the P2P network.
+    P2p,
+    /// An Electrum server such as electrs or Fulcrum, over TCP or TLS.
+    Electrum,
+}
+
+/// Chain the backend is on, which sets the address format of decoded
+/// transactions and watched addresses, and a P2P peer's message magic and
+/// default port.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Testnet,
+    Testnet4,
+    Signet,
+    Regtest,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackendConfig {
+    pub kind: BackendKind,
+    /// Base URL for esplora and rpc, `host[:port]` of the p2p peer, or
+    /// `tcp://` or `ssl://` address of the Electrum server.
+    pub url: String,
+    pub rpc_user: Option<String>,
+    pub rpc_pass: Option<String>,
+    /// Fixture file for the replay backend, or datadir for blockfile.
+    pub path: Option<PathBuf>,
+    /// First block the blockfile backend reads when there is no checkpoint.
+    pub start_height: Option<u32>,
+    pub network: Network,
+    /// Addresses the electrum backend subscribes to.
+    pub addresses: Vec<String>,
+}
+
+/// Which mempool transactions a polled backend fetches.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MempoolSync {
+    /// The newest `mempool_tx_limit` transactions on each pass.
+    #[default]
+    Recent,
+    /// Every transaction in the mempool, diffing the full txid set against
+    /// the previous pass to find new and removed transactions.
+    Full,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScanConfig {
+    pub mempool_interval_secs: u64,
+    pub block_interval_secs: u64,
+    pub mempool_sync: MempoolSync,
+    /// Mempool transactions fetched per scan in recent mode.
+    pub mempool_tx_limit: usize,
+    /// Most blocks fetched to catch up after downtime; older missed blocks
+    /// are skipped.
+    pub max_catchup_blocks: u32,
+    /// Transactions fetched at once.
+    pub fetch_concurrency: usize,
+    /// Pause between fetches, to stay under rate limits.
+    pub request_delay_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeedConfig {
+    /// Events buffered for live feed subscribers before slow ones lag.
+    pub broadcast_capacity: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// JSON file holding stats, history and the watchlist between runs;
+    /// nothing is kept when unset.
+    pub path: Option<PathBuf>,
+}
+
+/// Where BTC/USD prices come from; without one, USD values stay empty.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PriceKind {
+    #[default]
+    None,
+    /// A fixed `btc_usd`.
+    Static,
+    /// A CSV or JSON price history.
+    File,
+    /// A mempool.space-style price API.
+    Http,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PriceConfig {
+    pub kind: PriceKind,
+    pub btc_usd: Option<f64>,
+    pub path: Option<PathBuf>,
+    pub url: String,
+    /// Token floor prices in sats per whole token, keyed `brc20:TICK` or
+    /// `runes:<rune id or name>`.
+    pub floors: HashMap<String, f64>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            port: 8000,
+            demo: false,
+            shutdown_timeout_secs: 10,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+}
+
+impl Default for BackendConfig {
+    fn default() -> Self {
+        Self {
+            kind: BackendKind::Esplora,
+            url: "https://blockstream.info/api".to_string(),
+            rpc_user: None,
+            rpc_pass: None,
+            path: None,
+            start_height: None,
+            network: Network::Mainnet,
+            addresses: Vec::new(),
+        }
+    }
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        Self {
+            mempool_interval_secs: 60,
+            block_interval_secs: 300,
+            mempool_sync: MempoolSync::Recent,
+            mempool_tx_limit: 5,
+            max_catchup_blocks: 144,
+            fetch_concurrency: 4,
+            request_delay_ms: 200,
+        }
+    }
+}
+
+impl Default for PriceConfig {
+    fn default() -> Self {
+        Self {
+            kind: PriceKind::None,
+            btc_usd: None,
+            path: None,
+            url: "https://mempool.space/api/v1".to_string(),
+            floors: HashMap::new(),
+        }
+    }
+}
+
+impl PriceConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match self.kind {
+            PriceKind::None => {}
+            PriceKind::Static => {
+                if !self.btc_usd.is_some_and(|p| p > 0.0) {
+                    anyhow::bail!("price.btc_usd must be positive for the static price source");
+                }
+            }
+            PriceKind::File => {
+                if self.path.is_none() {
+                    anyhow::bail!("price.path is required for the file price source");
+                }
+            }
+            PriceKind::Http => {
+                if !self.url.starts_with("http://") && !self.url.starts_with("https://") {
+                    anyhow::bail!("price.url must be an http(s) URL, got {:?}", self.url);
+                }
+            }
+        }
+        if let Some((token, _)) = self.floors.iter().find(|(_, floor)| !floor.is_finite() || **floor < 0.0) {
+            anyhow::bail!("price.floors.{:?} must be a non-negative number of sats", token);
+        }
+        Ok(())
+    }
+}
+
+impl Default for FeedConfig {
+    fn default() -> Self {
+        Self { broadcast_capacity: 1000 }
+    }
+}
+
+impl BackendKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            BackendKind::Esplora => "esplora",
+            BackendKind::Rpc => "rpc",
+            BackendKind::Replay => "replay",
+            BackendKind::Blockfile => "blockfile",
+            BackendKind::P2p => "p2p",
+            BackendKind::Electrum => "electrum",
+        }
+    }
+}
+
+impl Network {
+    pub fn name(self) -> &'static str {
+        match self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Testnet4 => "testnet4",
+            Network::Signet => "signet",
+            Network::Regtest => "regtest",
+        }
+    }
+}
+
+impl BackendConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match self.kind {
+            BackendKind::Esplora | BackendKind::Rpc => {
+                if !self.url.starts_with("http://") && !self.url.starts_with("https://") {
+                    anyhow::bail!("backend.url must be an http(s) URL, got {:?}", self.url);
+                }
+                if self.kind == BackendKind::Rpc && (self.rpc_user.is_none() || self.rpc_pass.is_none()) {
+                    anyhow::bail!("backend.rpc_user and backend.rpc_pass are required for the rpc backend");
+                }
+            }
+            BackendKind::Replay | BackendKind::Blockfile => {
+                if self.path.is_none() {
+                    anyhow::bail!("backend.path is required for the {} backend", self.kind.name());
+                }
+            }
+            BackendKind::P2p => {
+                if self.url.is_empty() || self.url.contains("://") {
+                    anyhow::bail!("backend.url must be a host[:port] peer address for the p2p backend, got {:?}", self.url);
+                }
+            }
+            BackendKind::Electrum => {
+                if !["tcp://", "ssl://", "tls://"].iter().any(|scheme| self.url.starts_with(scheme)) {
+                    anyhow::bail!("backend.url must be a tcp:// or ssl:// address for the electrum backend, got {:?}", self.url);
+                }
+                if let Some(address) = self.addresses.iter().find(|a| crate::decode::address_script(a, self.network).is_none()) {
+                    anyhow::bail!("backend.addresses contains an invalid {} address: {:?}", self.network.name(), address);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ScanConfig {
+    pub fn mempool_interval(&self) -> Duration {
+        Duration::from_secs(self.mempool_interval_secs)
+    }
+
+    pub fn block_interval(&self) -> Duration {
+        Duration::from_secs(self.block_interval_secs)
+    }
+
+    pub fn request_delay(&self) -> Duration {
+        Duration::from_millis(self.request_delay_ms)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.mempool_interval_secs == 0 || self.block_interval_secs == 0 {
+            anyhow::bail!("scan intervals must be at least one second");
+        }
+        if self.mempool_tx_limit == 0 {
+            anyhow::bail!("scan.mempool_tx_limit must be at least 1");
+        }
+        if self.max_catchup_blocks == 0 {
+            anyhow::bail!("scan.max_catchup_blocks must be at least 1");
+        }
+        if self.fetch_concurrency == 0 {
+            anyhow::bail!("scan.fetch_concurrency must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+fn parse_env<T: std::str::FromStr>(name: &str, value: &str) -> anyhow::Result<T>
+where
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| anyhow::anyhow!("Invalid {}{}={:?}: {}", ENV_PREFIX, name, value, e))
+}
+
+impl Config {
+    /// Defaults, overlaid with `path` if given, then with the process
+    /// environment, then validated.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let mut config = match path {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        config.apply_env(|name| std::env::var(format!("{}{}", ENV_PREFIX, name)).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Cannot read config {}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| anyhow::anyhow!("Invalid config {}: {}", path.display(), e))
+    }
+
+    /// Override settings from variables named without the `BTC_MONITOR_`
+    /// prefix, e.g. `PORT` or `SCAN_MEMPOOL_INTERVAL_SECS`.
+    pub fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> anyhow::Result<()> {
+        if let Some(v) = var("PORT") {
+            self.server.port = parse_env("PORT", &v)?;
+        }
+        if let Some(v) = var("DEMO") {
+            self.server.demo = parse_env("DEMO", &v)?;
+        }
+        if let Some(v) = var("SHUTDOWN_TIMEOUT_SECS") {
+            self.server.shutdown_timeout_secs = parse_env("SHUTDOWN_TIMEOUT_SECS", &v)?;
+        }
+        if let Some(v) = var("BACKEND_KIND") {
+            self.backend.kind = match v.as_str() {
+                "esplora" => BackendKind::Esplora,
+                "rpc" => BackendKind::Rpc,
+                "replay" => BackendKind::Replay,
+                "blockfile" => BackendKind::Blockfile,
+                "p2p" => BackendKind::P2p,
+                "electrum" => BackendKind::Electrum,
+                _ => anyhow::bail!("Invalid {}BACKEND_KIND={:?}: expected esplora, rpc, replay, blockfile, p2p or electrum", ENV_PREFIX, v),
+            };
+        }
+        if let Some(v) = var("BACKEND_URL") {
+            self.backend.url = v;
+        }
+        if let Some(v) = var("BACKEND_PATH") {
+            self.backend.path = Some(PathBuf::from(v));
+        }
+        if let Some(v) = var("BACKEND_START_HEIGHT") {
+            self.backend.start_height = Some(parse_env("BACKEND_START_HEIGHT", &v)?);
+        }
+        if let Some(v) = var("BACKEND_NETWORK") {
+            self.backend.network = match v.as_str() {
+                "mainnet" => Network::Mainnet,
+                "testnet" => Network::Testnet,
+                "testnet4" => Network::Testnet4,
+                "signet" => Network::Signet,
+                "regtest" => Network::Regtest,
+                _ => anyhow::bail!("Invalid {}BACKEND_NETWORK={:?}: expected mainnet, testnet, testnet4, signet or regtest", ENV_PREFIX, v),
+            };
+        }
+        if let Some(v) = var("BACKEND_ADDRESSES") {
+            self.backend.addresses = v.split(',').map(str::trim).filter(|a| !a.is_empty()).map(String::from).collect();
+        }
+        if let Some(v) = var("RPC_USER") {
+            self.backend.rpc_user = Some(v);
+        }
+        if let Some(v) = var("RPC_PASS") {
+            self.backend.rpc_pass = Some(v);
+        }
+        if let Some(v) = var("SCAN_MEMPOOL_INTERVAL_SECS") {
+            self.scan.mempool_interval_secs = parse_env("SCAN_MEMPOOL_INTERVAL_SECS", &v)?;
+        }
+        if let Some(v) = var("SCAN_BLOCK_INTERVAL_SECS") {
+            self.scan.block_interval_secs = parse_env("SCAN_BLOCK_INTERVAL_SECS", &v)?;
+        }
+        if let Some(v) = var("SCAN_MEMPOOL_TX_LIMIT") {
+            self.scan.mempool_tx_limit = parse_env("SCAN_MEMPOOL_TX_LIMIT", &v)?;
+        }
+        if let Some(v) = var("SCAN_MEMPOOL_SYNC") {
+            self.scan.mempool_sync = match v.as_str() {
+                "recent" => MempoolSync::Recent,
+                "full" => MempoolSync::Full,
+                _ => anyhow::bail!("Invalid {}SCAN_MEMPOOL_SYNC={:?}: expected recent or full", ENV_PREFIX, v),
+            };
+        }
+        if let Some(v) = var("SCAN_MAX_CATCHUP_BLOCKS") {
+            self.scan.max_catchup_blocks = parse_env("SCAN_MAX_CATCHUP_BLOCKS", &v)?;
+        }
+        if let Some(v) = var("SCAN_FETCH_CONCURRENCY") {
+            self.scan.fetch_concurrency = parse_env("SCAN_FETCH_CONCURRENCY", &v)?;
+        }
+        if let Some(v) = var("SCAN_REQUEST_DELAY_MS") {
+            self.scan.request_delay_ms = parse_env("SCAN_REQUEST_DELAY_MS", &v)?;
+        }
+        if let Some(v) = var("FEED_BROADCAST_CAPACITY") {
+            self.feed.broadcast_capacity = parse_env("FEED_BROADCAST_CAPACITY", &v)?;
+        }
+        if let Some(v) = var("STORAGE_PATH") {
+            self.storage.path = Some(PathBuf::from(v));
+        }
+        if let Some(v) = var("PRICE_KIND") {
+            self.price.kind = match v.as_str() {
+                "none" => PriceKind::None,
+                "static" => PriceKind::Static,
+                "file" => PriceKind::File,
+                "http" => PriceKind::Http,
+                _ => anyhow::bail!("Invalid {}PRICE_KIND={:?}: expected none, static, file or http", ENV_PREFIX, v),
+            };
+        }
+        if let Some(v) = var("PRICE_BTC_USD") {
+            self.price.btc_usd = Some(parse_env("PRICE_BTC_USD", &v)?);
+        }
+        if let Some(v) = var("PRICE_PATH") {
+            self.price.path = Some(PathBuf::from(v));
+        }
+        if let Some(v) = var("PRICE_URL") {
+            self.price.url = v;
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.server.port == 0 {
+            anyhow::bail!("server.port must be non-zero");
+        }
+        self.backend.validate()?;
+        self.scan.validate()?;
+        if self.feed.broadcast_capacity == 0 {
+            anyhow::bail!("feed.broadcast_capacity must be at least 1");
+        }
+        self.price.validate()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,525 @@
+// Raw transaction and PSBT decoding into the same shape Esplora returns
+use crate::config::Network;
+use crate::{Input, Output, Transaction, TxStatus};
+use sha2::{Digest, Sha256};
+
+pub fn sha256d(data: &[u8]) -> [u8; 32] {
+    let first = Sha256::digest(data);
+    Sha256::digest(first).into()
+}
+
+/// Display form of a hash: byte-reversed hex.
+pub fn hash_to_hex(hash: &[u8]) -> String {
+    let mut bytes = hash.to_vec();
+    bytes.reverse();
+    hex::encode(bytes)
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    pub fn bytes(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        if self.remaining() < len {
+            anyhow::bail!("Unexpected end of data");
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into()?))
+    }
+
+    pub fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    pub fn u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into()?))
+    }
+
+    /// Bitcoin's CompactSize length prefix.
+    pub fn compact_size(&mut self) -> anyhow::Result<u64> {
+        Ok(match self.u8()? {
+            0xfd => self.u16()? as u64,
+            0xfe => self.u32()? as u64,
+            0xff => self.u64()?,
+            n => n as u64,
+        })
+    }
+
+    pub fn var_bytes(&mut self) -> anyhow::Result<&'a [u8]> {
+        let len = self.compact_size()?;
+        self.bytes(usize::try_from(len)?)
+    }
+}
+
+/// Parse one serialized transaction from the reader, handling the segwit
+/// marker. The txid is computed over the non-witness serialization, and
+/// output addresses are encoded for `network`.
+pub fn read_transaction(reader: &mut Reader, network: Network) -> anyhow::Result<Transaction> {
+    let start = reader.position();
+    let version = reader.bytes(4)?;
+
+    let mut segwit = false;
+    if reader.data.get(reader.pos) == Some(&0) && reader.data.get(reader.pos + 1) == Some(&1) {
+        reader.bytes(2)?;
+        segwit = true;
+    }
+
+    let body_start = reader.position();
+    let input_count = reader.compact_size()?;
+    let mut vin = Vec::new();
+    for _ in 0..input_count {
+        let prev_hash = reader.bytes(32)?;
+        let vout = reader.u32()?;
+        let _script_sig = reader.var_bytes()?;
+        let _sequence = reader.u32()?;
+        vin.push(Input {
+            txid: hash_to_hex(prev_hash),
+            vout,
+            witness: None,
+            prevout: None,
+        });
+    }
+
+    let output_count = reader.compact_size()?;
+    let mut vout = Vec::new();
+    for _ in 0..output_count {
+        let value = reader.u64()?;
+        let script = reader.var_bytes()?;
+        vout.push(Output {
+            scriptpubkey: hex::encode(script),
+            scriptpubkey_address: script_address(script, network),
+            value,
+        });
+    }
+    let body_end = reader.position();
+
+    if segwit {
+        for input in vin.iter_mut() {
+            let items = reader.compact_size()?;
+            let mut witness = Vec::new();
+            for _ in 0..items {
+                witness.push(hex::encode(reader.var_bytes()?));
+            }
+            input.witness = Some(witness);
+        }
+    }
+
+    let locktime = reader.bytes(4)?;
+    let end = reader.position();
+
+    let mut legacy = Vec::with_capacity(body_end - body_start + 8);
+    legacy.extend_from_slice(version);
+    legacy.extend_from_slice(&reader.data[body_start..body_end]);
+    legacy.extend_from_slice(locktime);
+
+    let size = (end - start) as u32;
+    let base_size = legacy.len() as u32;
+
+    Ok(Transaction {
+        txid: hash_to_hex(&sha256d(&legacy)),
+        size,
+        weight: Some(base_size * 3 + size),
+        fee: None,
+        status: TxStatus {
+            confirmed: false,
+            block_height: None,
+            block_time: None,
+            block_index: None,
+        },
+        vout,
+        vin,
+    })
+}
+
+/// Decode a complete raw transaction.
+pub fn decode_transaction(raw: &[u8], network: Network) -> anyhow::Result<Transaction> {
+    let mut reader = Reader::new(raw);
+    let tx = read_transaction(&mut reader, network)?;
+    if reader.remaining() != 0 {
+        anyhow::bail!("Trailing data after transaction");
+    }
+    Ok(tx)
+}
+
+pub fn decode_transaction_hex(raw_hex: &str, network: Network) -> anyhow::Result<Transaction> {
+    let raw = hex::decode(raw_hex.trim()).map_err(|_| anyhow::anyhow!("Transaction is not valid hex"))?;
+    decode_transaction(&raw, network)
+}
+
+/// Read a block: its hash, from the 80-byte header, and its transactions.
+pub fn read_block(reader: &mut Reader, network: Network) -> anyhow::Result<(String, Vec<Transaction>)> {
+    let header = reader.bytes(80)?;
+    let hash = hash_to_hex(&sha256d(header));
+    let count = reader.compact_size()?;
+    let mut txs = Vec::with_capacity(count.min(10_000) as usize);
+    for _ in 0..count {
+        txs.push(read_transaction(reader, network)?);
+    }
+    Ok((hash, txs))
+}
+
+/// Decode a complete raw block, stamping each transaction with `height`.
+pub fn decode_block(raw: &[u8], height: Option<u32>, network: Network) -> anyhow::Result<(String, Vec<Transaction>)> {
+    let mut reader = Reader::new(raw);
+    let (hash, mut txs) = read_block(&mut reader, network)?;
+    if reader.remaining() != 0 {
+        anyhow::bail!("Trailing data after block");
+    }
+    for tx in &mut txs {
+        tx.status.confirmed = true;
+        tx.status.block_height = height;
+    }
+    Ok((hash, txs))
+}
+
+const PSBT_MAGIC: &[u8] = b"psbt\xff";
+const PSBT_GLOBAL_UNSIGNED_TX: u8 = 0x00;
+const PSBT_IN_NON_WITNESS_UTXO: u8 = 0x00;
+const PSBT_IN_WITNESS_UTXO: u8 = 0x01;
+const PSBT_IN_FINAL_SCRIPTWITNESS: u8 = 0x08;
+const PSBT_IN_TAP_LEAF_SCRIPT: u8 = 0x15;
+
+/// Read one PSBT key-value map, returning (key, value) pairs.
+fn read_psbt_map<'a>(reader: &mut Reader<'a>) -> anyhow::Result<Vec<(&'a [u8], &'a [u8])>> {
+    let mut entries = Vec::new();
+    loop {
+        let key = reader.var_bytes()?;
+        if key.is_empty() {
+            return Ok(entries);
+        }
+        let value = reader.var_bytes()?;
+        entries.push((key, value));
+    }
+}
+
+/// Decode a version 0 PSBT (base64 or hex) into its unsigned transaction,
+/// filling prevouts from the UTXO fields and witnesses from finalized inputs.
+/// For unfinalized taproot inputs the tapscript leaf is used as the witness,
+/// so inscription envelopes in a reveal are still visible to the parsers.
+pub fn decode_psbt(encoded: &str, network: Network) -> anyhow::Result<Transaction> {
+    use base64::Engine;
+
+    let encoded = encoded.trim();
+    let raw = match hex::decode(encoded) {
+        Ok(raw) => raw,
+        Err(_) => base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(|_| anyhow::anyhow!("PSBT is neither valid base64 nor hex"))?,
+    };
+
+    let mut reader = Reader::new(&raw);
+    if reader.bytes(PSBT_MAGIC.len()).ok() != Some(PSBT_MAGIC) {
+        anyhow::bail!("Missing PSBT magic bytes");
+    }
+
+    let globals = read_psbt_map(&mut reader)?;
+    let unsigned = globals
+        .iter()
+        .find(|(key, _)| key == &[PSBT_GLOBAL_UNSIGNED_TX])
+        .map(|(_, value)| *value)
+        .ok_or_else(|| anyhow::anyhow!("PSBT has no unsigned transaction (only version 0 is supported)"))?;
+    let mut tx = decode_transaction(unsigned, network)?;
+
+    for input in tx.vin.iter_mut() {
+        for (key, value) in read_psbt_map(&mut reader)? {
+            match key[0] {
+                PSBT_IN_WITNESS_UTXO => {
+                    let mut utxo = Reader::new(value);
+                    let amount = utxo.u64()?;
+                    let script = utxo.var_bytes()?;
+                    input.prevout = Some(Output {
+                        scriptpubkey: hex::encode(script),
+                        scriptpubkey_address: script_address(script, network),
+                        value: amount,
+                    });
+                }
+                PSBT_IN_NON_WITNESS_UTXO if input.prevout.is_none() => {
+                    let prev = decode_transaction(value, network)?;
+                    input.prevout = prev.vout.get(input.vout as usize).cloned();
+                }
+                PSBT_IN_FINAL_SCRIPTWITNESS => {
+                    let mut witness = Reader::new(value);
+                    let items = witness.compact_size()?;
+                    let mut stack = Vec::new();
+                    for _ in 0..items {
+                        stack.push(hex::encode(witness.var_bytes()?));
+                    }
+                    input.witness = Some(stack);
+                }
+                PSBT_IN_TAP_LEAF_SCRIPT if input.witness.is_none() => {
+                    // Value is the script followed by its leaf version byte
+                    let script = &value[..value.len().saturating_sub(1)];
+                    input.witness = Some(vec![hex::encode(script), hex::encode(&key[1..])]);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(tx)
+}
+
+/// Address on `network` for a standard output script, if it has one.
+pub fn script_address(script: &[u8], network: Network) -> Option<String> {
+    let (hrp, p2pkh, p2sh) = address_prefixes(network);
+    match script {
+        // P2PKH
+        [0x76, 0xa9, 0x14, hash @ .., 0x88, 0xac] if hash.len() == 20 => Some(base58check(p2pkh, hash)),
+        // P2SH
+        [0xa9, 0x14, hash @ .., 0x87] if hash.len() == 20 => Some(base58check(p2sh, hash)),
+        // Segwit v0..v16
+        [version, len, program @ ..]
+            if (*version == 0x00 || (0x51..=0x60).contains(version))
+                && *len as usize == program.len()
+                && (2..=40).contains(&program.len()) =>
+        {
+            let version = if *version == 0 { 0 } else { version - 0x50 };
+            if version == 0 && program.len() != 20 && program.len() != 32 {
+                return None;
+            }
+            Some(segwit_address(hrp, version, program))
+        }
+        _ => None,
+    }
+}
+
+/// Bech32 prefix and base58 P2PKH and P2SH version bytes of `network`.
+fn address_prefixes(network: Network) -> (&'static str, u8, u8) {
+    match network {
+        Network::Mainnet => ("bc", 0x00, 0x05),
+        Network::Testnet | Network::Testnet4 | Network::Signet => ("tb", 0x6f, 0xc4),
+        Network::Regtest => ("bcrt", 0x6f, 0xc4),
+    }
+}
+
+/// Output script for an address on `network`; the inverse of
+/// `script_address`.
+pub fn address_script(address: &str, network: Network) -> Option<Vec<u8>> {
+    let (hrp, p2pkh, p2sh) = address_prefixes(network);
+    if address.to_ascii_lowercase().starts_with(&format!("{}1", hrp)) {
+        let (version, program) = decode_segwit_address(hrp, address)?;
+        let op = if version == 0 { 0 } else { 0x50 + version };
+        return Some([&[op, program.len() as u8][..], &program].concat());
+    }
+    let data = decode_base58check(address)?;
+    match (*data.first()?, data.len()) {
+        (version, 21) if version == p2pkh => Some([&[0x76, 0xa9, 0x14][..], &data[1..], &[0x88, 0xac]].concat()),
+        (version, 21) if version == p2sh => Some([&[0xa9, 0x14][..], &data[1..], &[0x87]].concat()),
+        _ => None,
+    }
+}
+
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+const BECH32_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+fn base58check(version: u8, payload: &[u8]) -> String {
+    let mut data = vec![version];
+    data.extend_from_slice(payload);
+    let checksum = sha256d(&data);
+    data.extend_from_slice(&checksum[..4]);
+
+    let mut digits: Vec<u8> = Vec::new();
+    for &byte in &data {
+        let mut carry = byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+
+    let zeros = data.iter().take_while(|b| **b == 0).count();
+    std::iter::repeat_n(b'1', zeros)
+        .chain(digits.iter().rev().map(|d| BASE58_ALPHABET[*d as usize]))
+        .map(|c| c as char)
+        .collect()
+}
+
+/// Version byte and payload of a base58check string, checksum verified.
+fn decode_base58check(encoded: &str) -> Option<Vec<u8>> {
+    // Little-endian base 256 digits
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in encoded.bytes() {
+        let mut carry = BASE58_ALPHABET.iter().position(|a| *a == c)? as u32;
+        for byte in bytes.iter_mut() {
+            carry += (*byte as u32) * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+
+    let zeros = encoded.bytes().take_while(|c| *c == b'1').count();
+    let mut data = vec![0u8; zeros];
+    data.extend(bytes.iter().rev());
+    let (payload, checksum) = data.split_at(data.len().checked_sub(4)?);
+    (sha256d(payload)[..4] == *checksum).then(|| payload.to_vec())
+}
+
+fn bech32_hrp_expand(hrp: &str) -> Vec<u8> {
+    let mut values: Vec<u8> = hrp.bytes().map(|b| b >> 5).collect();
+    values.push(0);
+    values.extend(hrp.bytes().map(|b| b & 31));
+    values
+}
+
+fn bech32_polymod(values: &[u8]) -> u32 {
+    const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut chk = 1u32;
+    for &value in values {
+        let top = chk >> 25;
+        chk = (chk & 0x1ffffff) << 5 ^ value as u32;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= generator;
+            }
+        }
+    }
+    chk
+}
+
+/// Bech32 for witness version 0, bech32m for everything newer (BIP 350).
+fn segwit_address(hrp: &str, version: u8, program: &[u8]) -> String {
+    let constant = if version == 0 { 1 } else { 0x2bc830a3 };
+
+    let mut data = vec![version];
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for &byte in program {
+        acc = (acc << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            data.push(((acc >> bits) & 31) as u8);
+        }
+    }
+    if bits > 0 {
+        data.push(((acc << (5 - bits)) & 31) as u8);
+    }
+
+    let mut values = bech32_hrp_expand(hrp);
+    values.extend_from_slice(&data);
+    values.extend_from_slice(&[0; 6]);
+    let polymod = bech32_polymod(&values) ^ constant;
+
+    let mut address = format!("{}1", hrp);
+    for value in data {
+        address.push(BECH32_CHARSET[value as usize] as char);
+    }
+    for i in 0..6 {
+        address.push(BECH32_CHARSET[((polymod >> (5 * (5 - i))) & 31) as usize] as char);
+    }
+    address
+}
+
+/// Witness version and program of a segwit address, checking the bech32 or
+/// bech32m checksum that version calls for.
+fn decode_segwit_address(hrp: &str, address: &str) -> Option<(u8, Vec<u8>)> {
+    let address = address.to_ascii_lowercase();
+    let data = address.strip_prefix(hrp)?.strip_prefix('1')?;
+    let values: Vec<u8> = data
+        .bytes()
+        .map(|c| BECH32_CHARSET.iter().position(|a| *a == c).map(|v| v as u8))
+        .collect::<Option<_>>()?;
+    if values.len() < 7 {
+        return None;
+    }
+
+    let version = values[0];
+    let constant = if version == 0 { 1 } else { 0x2bc830a3 };
+    let mut checked = bech32_hrp_expand(hrp);
+    checked.extend_from_slice(&values);
+    if bech32_polymod(&checked) != constant {
+        return None;
+    }
+
+    let mut program = Vec::new();
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for &value in &values[1..values.len() - 6] {
+        acc = (acc << 5 | value as u32) & 0xfff;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            program.push((acc >> bits) as u8);
+        }
+    }
+    if bits >= 5 || acc & ((1 << bits) - 1) != 0 {
+        return None;
+    }
+    if version > 16 || !(2..=40).contains(&program.len()) || (version == 0 && program.len() != 20 && program.len() != 32) {
+        return None;
+    }
+    Some((version, program))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_round_trip_on_each_network() {
+        let scripts = [
+            [&[0x00, 0x14][..], &[7; 20]].concat(),
+            [&[0x51, 0x20][..], &[7; 32]].concat(),
+            [&[0x76, 0xa9, 0x14][..], &[7; 20], &[0x88, 0xac]].concat(),
+            [&[0xa9, 0x14][..], &[7; 20], &[0x87]].concat(),
+        ];
+
+        for (network, segwit_prefix, legacy_prefixes) in [
+            (Network::Mainnet, "bc1", ["1", "3"]),
+            (Network::Testnet, "tb1", ["mn", "2"]),
+            (Network::Testnet4, "tb1", ["mn", "2"]),
+            (Network::Signet, "tb1", ["mn", "2"]),
+            (Network::Regtest, "bcrt1", ["mn", "2"]),
+        ] {
+            for (i, script) in scripts.iter().enumerate() {
+                let address = script_address(script, network).unwrap();
+                let prefixed = match i {
+                    0 | 1 => address.starts_with(segwit_prefix),
+                    _ => address.starts_with(|c| legacy_prefixes[i - 2].contains(c)),
+                };
+                assert!(prefixed, "{} on {:?}", address, network);
+                assert_eq!(address_script(&address, network).as_ref(), Some(script));
+                assert_eq!(address_script(&address.to_uppercase(), network).as_ref(), Some(script).filter(|_| i < 2));
+            }
+        }
+
+        let mainnet = script_address(&scripts[0], Network::Mainnet).unwrap();
+        assert_eq!(address_script(&mainnet, Network::Testnet), None);
+        let testnet = script_address(&scripts[0], Network::Testnet).unwrap();
+        assert_eq!(address_script(&testnet, Network::Regtest), None);
+        let legacy = script_address(&scripts[2], Network::Testnet).unwrap();
+        assert_eq!(address_script(&legacy, Network::Mainnet), None);
+    }
+}
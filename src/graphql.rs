@@ -0,0 +1,476 @@
+// GraphQL schema over retained history, tokens, stats and the live feed
+use crate::history::{self, ActivityRecord, HistoryQuery, Page};
+use crate::tokens::{Brc20Token, RuneToken};
+use crate::{Activity, LiveTransaction, MetaprotocolMonitor, ProtocolStats, ReplayRequest, StateChange};
+use async_graphql::{Context, EmptyMutation, Enum, InputObject, Json, Object, Result, Schema, Subscription};
+use futures_util::{Stream, StreamExt};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+
+pub type MonitorSchema = Schema<QueryRoot, EmptyMutation, SubscriptionRoot>;
+
+pub fn build_schema(monitor: Arc<MetaprotocolMonitor>) -> MonitorSchema {
+    Schema::build(QueryRoot, EmptyMutation, SubscriptionRoot)
+        .data(monitor)
+        .finish()
+}
+
+fn monitor<'a>(ctx: &Context<'a>) -> &'a Arc<MetaprotocolMonitor> {
+    ctx.data_unchecked::<Arc<MetaprotocolMonitor>>()
+}
+
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+#[graphql(remote = "history::SortKey")]
+pub enum SortKey {
+    Time,
+    Importance,
+    Value,
+}
+
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+#[graphql(remote = "history::SortOrder")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+/// The same filters as the REST history routes.
+#[derive(InputObject, Default)]
+pub struct HistoryFilter {
+    pub protocol: Option<String>,
+    pub operation: Option<String>,
+    /// BRC-20 `tick` or rune name.
+    pub ticker: Option<String>,
+    pub address: Option<String>,
+    pub min_height: Option<u32>,
+    pub max_height: Option<u32>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub sort: Option<SortKey>,
+    pub order: Option<SortOrder>,
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+}
+
+impl From<HistoryFilter> for HistoryQuery {
+    fn from(filter: HistoryFilter) -> Self {
+        Self {
+            protocol: filter.protocol,
+            operation: filter.operation,
+            ticker: filter.ticker,
+            address: filter.address,
+            min_height: filter.min_height,
+            max_height: filter.max_height,
+            since: filter.since,
+            until: filter.until,
+            sort: filter.sort.map(Into::into).unwrap_or_default(),
+            order: filter.order.map(Into::into).unwrap_or_default(),
+            limit: filter.limit,
+            cursor: filter.cursor,
+            ..Default::default()
+        }
+    }
+}
+
+#[Object]
+impl LiveTransaction {
+    async fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    async fn txid(&self) -> &str {
+        &self.txid
+    }
+
+    async fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    async fn protocols(&self) -> &[String] {
+        &self.protocols
+    }
+
+    async fn total_value(&self) -> u64 {
+        self.total_value
+    }
+
+    async fn total_value_usd(&self) -> Option<f64> {
+        self.total_value_usd
+    }
+
+    /// Left the mempool without (yet) being confirmed.
+    async fn removed(&self) -> bool {
+        self.removed
+    }
+
+    async fn activities(&self, protocol: Option<String>, min_importance: Option<u8>) -> Vec<&Activity> {
+        self.activities
+            .iter()
+            .filter(|a| protocol.as_ref().is_none_or(|p| a.protocol.eq_ignore_ascii_case(p)))
+            .filter(|a| min_importance.is_none_or(|min| a.importance >= min))
+            .collect()
+    }
+
+    async fn fee_rate(&self) -> f64 {
+        self.fee_rate
+    }
+
+    async fn size(&self) -> u32 {
+        self.size
+    }
+
+    async fn block_height(&self) -> Option<u32> {
+        self.block_height
+    }
+
+    async fn addresses(&self) -> &[String] {
+        &self.addresses
+    }
+
+    async fn watched(&self) -> &[String] {
+        &self.watched
+    }
+}
+
+#[Object]
+impl Activity {
+    async fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    async fn operation(&self) -> &str {
+        &self.operation
+    }
+
+    async fn output(&self) -> usize {
+        self.output
+    }
+
+    /// Protocol-specific fields, as in the REST API.
+    async fn data(&self) -> Json<&HashMap<String, serde_json::Value>> {
+        Json(&self.data)
+    }
+
+    async fn changes(&self) -> &[StateChange] {
+        &self.changes
+    }
+
+    async fn description(&self) -> &str {
+        &self.description
+    }
+
+    async fn value_usd(&self) -> Option<f64> {
+        self.value_usd
+    }
+
+    async fn importance(&self) -> u8 {
+        self.importance
+    }
+}
+
+#[Object]
+impl StateChange {
+    async fn field(&self) -> &str {
+        &self.field
+    }
+
+    async fn before(&self) -> Option<&str> {
+        self.before.as_deref()
+    }
+
+    async fn after(&self) -> &str {
+        &self.after
+    }
+
+    async fn change_type(&self) -> &str {
+        &self.change_type
+    }
+}
+
+#[Object]
+impl ProtocolStats {
+    async fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    async fn total_txs(&self) -> u64 {
+        self.total_txs
+    }
+
+    async fn total_volume(&self) -> u64 {
+        self.total_volume
+    }
+
+    async fn active_tokens(&self) -> u32 {
+        self.active_tokens
+    }
+
+    async fn last_activity(&self) -> u64 {
+        self.last_activity
+    }
+}
+
+#[Object]
+impl ActivityRecord {
+    async fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    async fn txid(&self) -> &str {
+        &self.txid
+    }
+
+    async fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    async fn block_height(&self) -> Option<u32> {
+        self.block_height
+    }
+
+    async fn activity(&self) -> &Activity {
+        &self.activity
+    }
+
+    /// The transaction this activity came from, while it is still retained.
+    async fn transaction(&self, ctx: &Context<'_>) -> Option<LiveTransaction> {
+        monitor(ctx).transaction(&self.txid).await
+    }
+}
+
+#[Object(name = "TransactionPage")]
+impl Page<LiveTransaction> {
+    async fn items(&self) -> &[LiveTransaction] {
+        &self.items
+    }
+
+    async fn next_cursor(&self) -> Option<&str> {
+        self.next_cursor.as_deref()
+    }
+}
+
+#[Object(name = "ActivityPage")]
+impl Page<ActivityRecord> {
+    async fn items(&self) -> &[ActivityRecord] {
+        &self.items
+    }
+
+    async fn next_cursor(&self) -> Option<&str> {
+        self.next_cursor.as_deref()
+    }
+}
+
+fn recent(records: &std::collections::VecDeque<ActivityRecord>, limit: Option<usize>) -> Vec<&ActivityRecord> {
+    records.iter().take(limit.unwrap_or(records.len())).collect()
+}
+
+#[Object]
+impl Brc20Token {
+    async fn tick(&self) -> &str {
+        &self.tick
+    }
+
+    async fn deploy_txid(&self) -> Option<&str> {
+        self.deploy_txid.as_deref()
+    }
+
+    async fn deployed_at(&self) -> Option<u64> {
+        self.deployed_at
+    }
+
+    async fn max_supply(&self) -> Option<f64> {
+        self.max_supply
+    }
+
+    async fn mint_limit(&self) -> Option<f64> {
+        self.mint_limit
+    }
+
+    async fn decimals(&self) -> Option<u64> {
+        self.decimals
+    }
+
+    async fn minted_supply(&self) -> f64 {
+        self.minted
+    }
+
+    async fn mint_count(&self) -> u64 {
+        self.mint_count
+    }
+
+    /// Mints above the limit or past the max supply, which add nothing.
+    async fn invalid_mints(&self) -> u64 {
+        self.invalid_mints
+    }
+
+    /// Mints counted without limit checks because the deploy was not seen.
+    async fn unvalidated_mints(&self) -> u64 {
+        self.unvalidated_mints
+    }
+
+    async fn transfer_count(&self) -> u64 {
+        self.transfer_count
+    }
+
+    async fn mint_progress_pct(&self) -> Option<f64> {
+        self.mint_progress()
+    }
+
+    async fn holders(&self) -> usize {
+        self.holders.len()
+    }
+
+    /// Most recent activity first.
+    async fn recent_activity(&self, limit: Option<usize>) -> Vec<&ActivityRecord> {
+        recent(&self.recent_activity, limit)
+    }
+}
+
+/// Rune amounts are u128 and exceed GraphQL's Int, so they are strings.
+#[Object]
+impl RuneToken {
+    async fn rune_id(&self) -> Option<&str> {
+        self.rune_id.as_deref()
+    }
+
+    async fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    async fn etch_txid(&self) -> Option<&str> {
+        self.etch_txid.as_deref()
+    }
+
+    async fn etched_at(&self) -> Option<u64> {
+        self.etched_at
+    }
+
+    async fn divisibility(&self) -> Option<u64> {
+        self.divisibility
+    }
+
+    async fn symbol(&self) -> Option<&str> {
+        self.symbol.as_deref()
+    }
+
+    async fn premine(&self) -> String {
+        self.premine.to_string()
+    }
+
+    async fn cap(&self) -> Option<String> {
+        self.cap.map(|c| c.to_string())
+    }
+
+    async fn mint_limit(&self) -> Option<String> {
+        self.mint_limit.map(|l| l.to_string())
+    }
+
+    async fn terms(&self) -> Option<Json<&serde_json::Value>> {
+        self.terms.as_ref().map(Json)
+    }
+
+    #[graphql(name = "mintedSupply")]
+    async fn minted_supply_string(&self) -> String {
+        RuneToken::minted_supply(self).to_string()
+    }
+
+    async fn mint_count(&self) -> u64 {
+        self.mint_count
+    }
+
+    async fn transfer_count(&self) -> u64 {
+        self.transfer_count
+    }
+
+    async fn mint_progress_pct(&self) -> Option<f64> {
+        self.mint_progress()
+    }
+
+    async fn holders(&self) -> usize {
+        self.holders.len()
+    }
+
+    /// Most recent activity first.
+    async fn recent_activity(&self, limit: Option<usize>) -> Vec<&ActivityRecord> {
+        recent(&self.recent_activity, limit)
+    }
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// Per-protocol statistics, optionally for a single protocol.
+    async fn stats(&self, ctx: &Context<'_>, protocol: Option<String>) -> Vec<ProtocolStats> {
+        let mut stats: Vec<ProtocolStats> = monitor(ctx)
+            .get_stats()
+            .await
+            .into_values()
+            .filter(|s| protocol.as_ref().is_none_or(|p| s.protocol.eq_ignore_ascii_case(p)))
+            .collect();
+        stats.sort_by(|a, b| a.protocol.cmp(&b.protocol));
+        stats
+    }
+
+    async fn transaction(&self, ctx: &Context<'_>, txid: String) -> Option<LiveTransaction> {
+        monitor(ctx).transaction(&txid).await
+    }
+
+    async fn transactions(&self, ctx: &Context<'_>, #[graphql(default)] filter: HistoryFilter) -> Result<Page<LiveTransaction>> {
+        Ok(monitor(ctx).query_transactions(&filter.into()).await?)
+    }
+
+    async fn activities(&self, ctx: &Context<'_>, #[graphql(default)] filter: HistoryFilter) -> Result<Page<ActivityRecord>> {
+        Ok(monitor(ctx).query_activities(&filter.into()).await?)
+    }
+
+    async fn brc20(&self, ctx: &Context<'_>, tick: String) -> Option<Brc20Token> {
+        monitor(ctx).brc20_token(&tick).await
+    }
+
+    /// A rune by id (`block:tx`) or name, spacers optional.
+    async fn rune(&self, ctx: &Context<'_>, name_or_id: String) -> Option<RuneToken> {
+        monitor(ctx).rune_token(&name_or_id).await
+    }
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// Transactions as they are broadcast on the live feed. `sinceSeq`
+    /// replays retained transactions first, as on `/ws`.
+    async fn transactions(
+        &self,
+        ctx: &Context<'_>,
+        protocols: Option<Vec<String>>,
+        since_seq: Option<u64>,
+    ) -> impl Stream<Item = LiveTransaction> {
+        let monitor = monitor(ctx).clone();
+        let replay = ReplayRequest { since_seq, since: None };
+        let (backlog, rx) = monitor.subscribe(&replay).await;
+
+        let live = futures_util::stream::unfold(rx, move |mut rx| {
+            let metrics = monitor.feed_metrics();
+            async move {
+                loop {
+                    match rx.recv().await {
+                        Ok(tx) => return Some((tx, rx)),
+                        Err(RecvError::Lagged(skipped)) => metrics.record_lag(skipped),
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            }
+        });
+
+        futures_util::stream::iter(backlog)
+            .chain(live)
+            .filter(move |tx| {
+                let keep = protocols.as_ref().is_none_or(|protocols| {
+                    protocols.iter().any(|p| tx.protocols.iter().any(|tp| tp.eq_ignore_ascii_case(p)))
+                });
+                std::future::ready(keep)
+            })
+    }
+}
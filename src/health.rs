@@ -0,0 +1,197 @@
+// Scanner bookkeeping and the health and readiness report built from it
+use crate::FeedMetricsSnapshot;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// A scan is stale once this many of its intervals pass without success.
+pub const STALE_SCAN_INTERVALS: u64 = 3;
+/// A synced height further than this behind the backend tip is degraded.
+pub const MAX_BLOCKS_BEHIND: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Healthy,
+    Degraded,
+    Unhealthy,
+}
+
+/// Outcome of the recent runs of one scanner. Times are unix timestamps.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct ScanStatus {
+    pub interval_secs: u64,
+    pub last_attempt: Option<u64>,
+    pub last_success: Option<u64>,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+}
+
+impl ScanStatus {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval_secs: interval.as_secs(),
+            ..Default::default()
+        }
+    }
+
+    pub fn record(&mut self, result: &anyhow::Result<()>, now: u64) {
+        self.last_attempt = Some(now);
+        match result {
+            Ok(()) => {
+                self.last_success = Some(now);
+                self.consecutive_failures = 0;
+                self.last_error = None;
+            }
+            Err(e) => {
+                self.consecutive_failures += 1;
+                self.last_error = Some(e.to_string());
+            }
+        }
+    }
+
+    pub fn is_stale(&self, now: u64) -> bool {
+        match self.last_success {
+            Some(at) => now.saturating_sub(at) > self.interval_secs * STALE_SCAN_INTERVALS,
+            None => true,
+        }
+    }
+}
+
+/// What the monitor knows about its own progress.
+#[derive(Debug, Clone, Default)]
+pub struct SyncState {
+    pub demo: bool,
+    pub mempool: ScanStatus,
+    pub blocks: ScanStatus,
+    /// Height of the newest block scanned.
+    pub synced_height: Option<u32>,
+}
+
+impl SyncState {
+    /// Ready once both scanners have completed a pass; demo mode has
+    /// nothing to sync.
+    pub fn is_ready(&self) -> bool {
+        self.demo || (self.mempool.last_success.is_some() && self.blocks.last_success.is_some())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct BackendStatus {
+    pub kind: String,
+    pub url: String,
+    pub reachable: bool,
+    pub tip_height: Option<u32>,
+    pub latency_ms: Option<u64>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SyncStatus {
+    pub synced_height: Option<u32>,
+    pub backend_tip_height: Option<u32>,
+    pub blocks_behind: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ScanReport {
+    pub mempool: ScanStatus,
+    pub blocks: ScanStatus,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct BroadcastStatus {
+    /// Events queued for the slowest subscriber.
+    pub backlog: usize,
+    pub capacity: usize,
+    pub subscribers: usize,
+}
+
+/// Body of `/api/health`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    /// Why the status is not healthy.
+    pub issues: Vec<String>,
+    pub version: String,
+    /// "live" or "demo".
+    pub mode: String,
+    pub ready: bool,
+    /// Protocols with a registered parser.
+    pub protocols: Vec<String>,
+    /// Absent in demo mode, which never contacts a backend.
+    pub backend: Option<BackendStatus>,
+    pub sync: SyncStatus,
+    pub scans: ScanReport,
+    pub broadcast: BroadcastStatus,
+    pub feed: FeedMetricsSnapshot,
+}
+
+impl HealthReport {
+    pub fn new(
+        state: SyncState,
+        backend: Option<BackendStatus>,
+        protocols: Vec<String>,
+        broadcast: BroadcastStatus,
+        feed: FeedMetricsSnapshot,
+        now: u64,
+    ) -> Self {
+        let backend_tip_height = backend.as_ref().and_then(|b| b.tip_height);
+        let blocks_behind = match (state.synced_height, backend_tip_height) {
+            (Some(synced), Some(tip)) => Some(tip.saturating_sub(synced)),
+            _ => None,
+        };
+
+        let mut issues = Vec::new();
+        let mut status = HealthStatus::Healthy;
+
+        if !state.demo {
+            if let Some(backend) = backend.as_ref().filter(|b| !b.reachable) {
+                status = HealthStatus::Unhealthy;
+                issues.push(format!(
+                    "Backend {} unreachable: {}",
+                    backend.url,
+                    backend.error.as_deref().unwrap_or("unknown error")
+                ));
+            }
+            for (name, scan) in [("mempool", &state.mempool), ("block", &state.blocks)] {
+                if scan.consecutive_failures > 0 {
+                    issues.push(format!("Last {} {} scan(s) failed", scan.consecutive_failures, name));
+                }
+                if scan.last_success.is_some() && scan.is_stale(now) {
+                    issues.push(format!("No successful {} scan in {}s", name, scan.interval_secs * STALE_SCAN_INTERVALS));
+                }
+            }
+            if !state.is_ready() {
+                issues.push("Initial sync has not completed".to_string());
+            }
+            if blocks_behind.is_some_and(|behind| behind > MAX_BLOCKS_BEHIND) {
+                issues.push(format!("{} blocks behind the backend tip", blocks_behind.unwrap_or(0)));
+            }
+            if status == HealthStatus::Healthy && !issues.is_empty() {
+                status = HealthStatus::Degraded;
+            }
+        }
+
+        Self {
+            status,
+            issues,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            mode: if state.demo { "demo" } else { "live" }.to_string(),
+            ready: state.is_ready(),
+            protocols,
+            backend,
+            sync: SyncStatus {
+                synced_height: state.synced_height,
+                backend_tip_height,
+                blocks_behind,
+            },
+            scans: ScanReport {
+                mempool: state.mempool,
+                blocks: state.blocks,
+            },
+            broadcast,
+            feed,
+        }
+    }
+}
@@ -0,0 +1,306 @@
+// Queries over the transactions the monitor has retained
+use crate::runestone::normalize_name;
+use crate::{Activity, LiveTransaction, HISTORY_CAPACITY};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+pub const MAX_PAGE_SIZE: usize = 500;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    #[default]
+    Time,
+    Importance,
+    Value,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// The most recent published transactions, oldest first, indexed by txid so
+/// that each is published only once.
+#[derive(Debug, Default)]
+pub(crate) struct History {
+    records: VecDeque<LiveTransaction>,
+    seqs: HashMap<String, u64>,
+}
+
+impl History {
+    /// Keeps the last `HISTORY_CAPACITY` of `records`, which are in `seq`
+    /// order.
+    pub(crate) fn new(records: impl IntoIterator<Item = LiveTransaction>) -> Self {
+        let mut history = Self::default();
+        for tx in records {
+            history.push(tx);
+        }
+        history
+    }
+
+    pub(crate) fn iter(&self) -> impl DoubleEndedIterator<Item = &LiveTransaction> {
+        self.records.iter()
+    }
+
+    pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = &mut LiveTransaction> {
+        self.records.iter_mut()
+    }
+
+    pub(crate) fn contains(&self, txid: &str) -> bool {
+        self.seqs.contains_key(txid)
+    }
+
+    pub(crate) fn get(&self, txid: &str) -> Option<&LiveTransaction> {
+        self.records.get(self.position(txid)?)
+    }
+
+    pub(crate) fn get_mut(&mut self, txid: &str) -> Option<&mut LiveTransaction> {
+        let position = self.position(txid)?;
+        self.records.get_mut(position)
+    }
+
+    fn position(&self, txid: &str) -> Option<usize> {
+        let seq = self.seqs.get(txid)?;
+        self.records.binary_search_by_key(seq, |tx| tx.seq).ok()
+    }
+
+    /// Append a transaction, dropping the oldest once full.
+    pub(crate) fn push(&mut self, tx: LiveTransaction) {
+        if self.records.len() == HISTORY_CAPACITY {
+            if let Some(oldest) = self.records.pop_front() {
+                if self.seqs.get(&oldest.txid) == Some(&oldest.seq) {
+                    self.seqs.remove(&oldest.txid);
+                }
+            }
+        }
+        self.seqs.insert(tx.txid.clone(), tx.seq);
+        self.records.push_back(tx);
+    }
+}
+
+/// Filters shared by `/api/transactions` and `/api/activities`. Time bounds
+/// are unix timestamps, height bounds are inclusive. `ticker` matches a
+/// BRC-20 `tick`, or a rune name or id, including mints and edicts of it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct HistoryQuery {
+    pub protocol: Option<String>,
+    pub operation: Option<String>,
+    pub ticker: Option<String>,
+    pub address: Option<String>,
+    pub min_height: Option<u32>,
+    pub max_height: Option<u32>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    #[serde(default)]
+    pub sort: SortKey,
+    #[serde(default)]
+    pub order: SortOrder,
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+    /// Id of the rune `ticker` names, so mints and edicts that only carry
+    /// the id match too. Filled in by the monitor from its token registry.
+    #[serde(skip)]
+    #[schemars(skip)]
+    pub(crate) ticker_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "{T}Page")]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Pass back as `cursor` to fetch the next page; absent on the last page.
+    pub next_cursor: Option<String>,
+}
+
+/// An activity together with the transaction it came from.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ActivityRecord {
+    pub seq: u64,
+    pub txid: String,
+    pub timestamp: u64,
+    pub block_height: Option<u32>,
+    #[serde(flatten)]
+    pub activity: Activity,
+}
+
+/// Position of an item in a sorted result: the sort value, then the
+/// transaction's `seq` and the activity index to break ties.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Position(u64, u64, usize);
+
+impl Position {
+    fn encode(&self) -> String {
+        format!("{}.{}.{}", self.0, self.1, self.2)
+    }
+
+    fn decode(cursor: &str) -> anyhow::Result<Self> {
+        let parts: Vec<&str> = cursor.split('.').collect();
+        if parts.len() != 3 {
+            anyhow::bail!("Invalid cursor");
+        }
+        let parse = |s: &str| s.parse::<u64>().map_err(|_| anyhow::anyhow!("Invalid cursor"));
+        Ok(Self(parse(parts[0])?, parse(parts[1])?, parse(parts[2])? as usize))
+    }
+}
+
+impl HistoryQuery {
+    fn page_size(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+    }
+
+    fn matches_transaction(&self, tx: &LiveTransaction) -> bool {
+        if let Some(address) = &self.address {
+            if !tx.addresses.iter().any(|a| a == address) {
+                return false;
+            }
+        }
+        if self.min_height.is_some() || self.max_height.is_some() {
+            let Some(height) = tx.block_height else { return false };
+            if self.min_height.is_some_and(|min| height < min) || self.max_height.is_some_and(|max| height > max) {
+                return false;
+            }
+        }
+        if self.since.is_some_and(|since| tx.timestamp < since) || self.until.is_some_and(|until| tx.timestamp > until) {
+            return false;
+        }
+        true
+    }
+
+    fn matches_activity(&self, activity: &Activity) -> bool {
+        if let Some(protocol) = &self.protocol {
+            if !activity.protocol.eq_ignore_ascii_case(protocol) {
+                return false;
+            }
+        }
+        if let Some(operation) = &self.operation {
+            if !activity.operation.eq_ignore_ascii_case(operation) {
+                return false;
+            }
+        }
+        if let Some(ticker) = &self.ticker {
+            if !self.matches_ticker(activity, ticker) {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn matches_ticker(&self, activity: &Activity, ticker: &str) -> bool {
+        let data = &activity.data;
+        let str_field = |key: &str| data.get(key).and_then(|v| v.as_str());
+        let is_id = |id: &str| id == ticker || self.ticker_id.as_deref() == Some(id);
+
+        if str_field("tick").is_some_and(|tick| tick.eq_ignore_ascii_case(ticker)) {
+            return true;
+        }
+        let name = normalize_name(ticker);
+        if !name.is_empty() && str_field("rune").is_some_and(|rune| normalize_name(rune) == name) {
+            return true;
+        }
+        if str_field("rune_id").is_some_and(is_id) {
+            return true;
+        }
+        data.get("edicts")
+            .and_then(|edicts| edicts.as_array())
+            .is_some_and(|edicts| edicts.iter().any(|e| e.get("rune_id").and_then(|id| id.as_str()).is_some_and(is_id)))
+    }
+
+    fn has_activity_filters(&self) -> bool {
+        self.protocol.is_some() || self.operation.is_some() || self.ticker.is_some()
+    }
+
+    fn sort_value(&self, tx: &LiveTransaction, activity: Option<&Activity>) -> u64 {
+        match self.sort {
+            SortKey::Time => tx.timestamp,
+            SortKey::Importance => match activity {
+                Some(a) => a.importance as u64,
+                None => tx.activities.iter().map(|a| a.importance as u64).max().unwrap_or(0),
+            },
+            SortKey::Value => tx.total_value,
+        }
+    }
+
+    /// Sort positioned items, drop everything up to the cursor and cut a page.
+    fn paginate<T>(&self, mut items: Vec<(Position, T)>) -> anyhow::Result<Page<T>> {
+        let cursor = self.cursor.as_deref().map(Position::decode).transpose()?;
+
+        items.sort_by(|a, b| match self.order {
+            SortOrder::Asc => a.0.cmp(&b.0),
+            SortOrder::Desc => b.0.cmp(&a.0),
+        });
+
+        if let Some(cursor) = cursor {
+            items.retain(|(pos, _)| match self.order {
+                SortOrder::Asc => *pos > cursor,
+                SortOrder::Desc => *pos < cursor,
+            });
+        }
+
+        let limit = self.page_size();
+        let next_cursor = if items.len() > limit {
+            items.truncate(limit);
+            items.last().map(|(pos, _)| pos.encode())
+        } else {
+            None
+        };
+
+        Ok(Page {
+            items: items.into_iter().map(|(_, item)| item).collect(),
+            next_cursor,
+        })
+    }
+}
+
+pub fn query_transactions<'a>(
+    history: impl Iterator<Item = &'a LiveTransaction>,
+    query: &HistoryQuery,
+) -> anyhow::Result<Page<LiveTransaction>> {
+    let items = history
+        .filter(|tx| query.matches_transaction(tx))
+        .filter(|tx| !query.has_activity_filters() || tx.activities.iter().any(|a| query.matches_activity(a)))
+        .map(|tx| (Position(query.sort_value(tx, None), tx.seq, 0), tx))
+        .collect();
+
+    let page = query.paginate(items)?;
+    Ok(Page {
+        items: page.items.into_iter().cloned().collect(),
+        next_cursor: page.next_cursor,
+    })
+}
+
+pub fn query_activities<'a>(
+    history: impl Iterator<Item = &'a LiveTransaction>,
+    query: &HistoryQuery,
+) -> anyhow::Result<Page<ActivityRecord>> {
+    let mut items = Vec::new();
+
+    for tx in history.filter(|tx| query.matches_transaction(tx)) {
+        for (idx, activity) in tx.activities.iter().enumerate() {
+            if query.matches_activity(activity) {
+                items.push((Position(query.sort_value(tx, Some(activity)), tx.seq, idx), (tx, activity)));
+            }
+        }
+    }
+
+    let page = query.paginate(items)?;
+    Ok(Page {
+        items: page
+            .items
+            .into_iter()
+            .map(|(tx, activity)| ActivityRecord {
+                seq: tx.seq,
+                txid: tx.txid.clone(),
+                timestamp: tx.timestamp,
+                block_height: tx.block_height,
+                activity: activity.clone(),
+            })
+            .collect(),
+        next_cursor: page.next_cursor,
+    })
+}
@@ -0,0 +1,364 @@
+// Read-only access to a LevelDB directory, enough to load bitcoind's block
+// index from a copied datadir without linking LevelDB itself
+use std::collections::HashMap;
+use std::path::Path;
+
+const TABLE_MAGIC: u64 = 0xdb47_7524_8b80_fb57;
+const FOOTER_LEN: usize = 48;
+const BLOCK_TRAILER_LEN: usize = 5;
+const LOG_BLOCK_LEN: usize = 32 * 1024;
+const LOG_HEADER_LEN: usize = 7;
+
+const TYPE_DELETION: u8 = 0;
+const TYPE_VALUE: u8 = 1;
+
+/// The newest value for every live key starting with `prefix`, merged from
+/// all tables and write-ahead logs in `dir`. Meant for databases that are
+/// not open elsewhere, such as a copy taken while the node was stopped.
+pub fn read(dir: &Path, prefix: &[u8]) -> anyhow::Result<HashMap<Vec<u8>, Vec<u8>>> {
+    let mut entries: HashMap<Vec<u8>, (u64, Option<Vec<u8>>)> = HashMap::new();
+    let mut merge = |key: &[u8], seq: u64, value: Option<&[u8]>| {
+        if !key.starts_with(prefix) {
+            return;
+        }
+        match entries.get(key) {
+            Some((newest, _)) if *newest >= seq => {}
+            _ => {
+                entries.insert(key.to_vec(), (seq, value.map(<[u8]>::to_vec)));
+            }
+        }
+    };
+
+    let listing = std::fs::read_dir(dir).map_err(|e| anyhow::anyhow!("Cannot read {}: {}", dir.display(), e))?;
+    for entry in listing {
+        let path = entry?.path();
+        let data = match path.extension().and_then(|e| e.to_str()) {
+            Some("ldb" | "sst" | "log") => std::fs::read(&path)?,
+            _ => continue,
+        };
+        let result = if path.extension().is_some_and(|e| e == "log") {
+            read_log(&data, &mut merge)
+        } else {
+            read_table(&data, &mut merge)
+        };
+        result.map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+    }
+
+    Ok(entries
+        .into_iter()
+        .filter_map(|(key, (_, value))| Some((key, value?)))
+        .collect())
+}
+
+fn varint(data: &[u8], pos: &mut usize) -> anyhow::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *data.get(*pos).ok_or_else(|| anyhow::anyhow!("Truncated varint"))?;
+        *pos += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    anyhow::bail!("Varint too long")
+}
+
+fn slice<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> anyhow::Result<&'a [u8]> {
+    let end = pos.checked_add(len).ok_or_else(|| anyhow::anyhow!("Entry length overflow"))?;
+    let bytes = data.get(*pos..end).ok_or_else(|| anyhow::anyhow!("Truncated entry"))?;
+    *pos = end;
+    Ok(bytes)
+}
+
+fn length_prefixed<'a>(data: &'a [u8], pos: &mut usize) -> anyhow::Result<&'a [u8]> {
+    let len = varint(data, pos)? as usize;
+    slice(data, pos, len)
+}
+
+/// Offset and size of a block within a table file.
+fn block_handle(data: &[u8], pos: &mut usize) -> anyhow::Result<(usize, usize)> {
+    Ok((varint(data, pos)? as usize, varint(data, pos)? as usize))
+}
+
+/// A table block's contents, decompressed if needed.
+fn table_block(file: &[u8], (offset, size): (usize, usize)) -> anyhow::Result<Vec<u8>> {
+    let raw = offset
+        .checked_add(size)
+        .and_then(|end| end.checked_add(BLOCK_TRAILER_LEN))
+        .and_then(|end| file.get(offset..end))
+        .ok_or_else(|| anyhow::anyhow!("Block handle out of range"))?;
+    match raw[size] {
+        0 => Ok(raw[..size].to_vec()),
+        1 => Ok(snap::raw::Decoder::new().decompress_vec(&raw[..size])?),
+        kind => anyhow::bail!("Unknown block compression {}", kind),
+    }
+}
+
+/// The key/value entries of a table block, expanding shared key prefixes.
+fn block_entries(block: &[u8]) -> anyhow::Result<Vec<(Vec<u8>, &[u8])>> {
+    let restarts_at = block.len().checked_sub(4).ok_or_else(|| anyhow::anyhow!("Block too short"))?;
+    let restarts = u32::from_le_bytes(block[restarts_at..].try_into()?) as usize;
+    let end = restarts
+        .checked_mul(4)
+        .and_then(|len| restarts_at.checked_sub(len))
+        .ok_or_else(|| anyhow::anyhow!("Bad restart count"))?;
+
+    let mut entries = Vec::new();
+    let mut key: Vec<u8> = Vec::new();
+    let mut pos = 0;
+    while pos < end {
+        let shared = varint(block, &mut pos)? as usize;
+        let unshared = varint(block, &mut pos)? as usize;
+        let value_len = varint(block, &mut pos)? as usize;
+        if shared > key.len() {
+            anyhow::bail!("Bad shared key length");
+        }
+        key.truncate(shared);
+        key.extend_from_slice(slice(block, &mut pos, unshared)?);
+        entries.push((key.clone(), slice(block, &mut pos, value_len)?));
+    }
+    Ok(entries)
+}
+
+fn read_table(file: &[u8], merge: &mut impl FnMut(&[u8], u64, Option<&[u8]>)) -> anyhow::Result<()> {
+    let footer = file
+        .len()
+        .checked_sub(FOOTER_LEN)
+        .map(|at| &file[at..])
+        .ok_or_else(|| anyhow::anyhow!("Table too short"))?;
+    if u64::from_le_bytes(footer[40..].try_into()?) != TABLE_MAGIC {
+        anyhow::bail!("Not a LevelDB table");
+    }
+    let mut pos = 0;
+    block_handle(footer, &mut pos)?; // metaindex
+    let index = table_block(file, block_handle(footer, &mut pos)?)?;
+
+    for (_, handle) in block_entries(&index)? {
+        let block = table_block(file, block_handle(handle, &mut 0)?)?;
+        for (internal_key, value) in block_entries(&block)? {
+            let split = internal_key
+                .len()
+                .checked_sub(8)
+                .ok_or_else(|| anyhow::anyhow!("Internal key too short"))?;
+            let tag = u64::from_le_bytes(internal_key[split..].try_into()?);
+            let value = match (tag & 0xff) as u8 {
+                TYPE_VALUE => Some(value),
+                TYPE_DELETION => None,
+                kind => anyhow::bail!("Unknown entry type {}", kind),
+            };
+            merge(&internal_key[..split], tag >> 8, value);
+        }
+    }
+    Ok(())
+}
+
+/// Replay the write batches in a write-ahead log. A torn record at the end,
+/// left by a crash, ends the log.
+fn read_log(file: &[u8], merge: &mut impl FnMut(&[u8], u64, Option<&[u8]>)) -> anyhow::Result<()> {
+    let mut record = Vec::new();
+    let mut pos = 0;
+
+    while pos + LOG_HEADER_LEN <= file.len() {
+        let block_left = LOG_BLOCK_LEN - pos % LOG_BLOCK_LEN;
+        if block_left < LOG_HEADER_LEN {
+            pos += block_left;
+            continue;
+        }
+        let len = u16::from_le_bytes([file[pos + 4], file[pos + 5]]) as usize;
+        let kind = file[pos + 6];
+        let Some(fragment) = file.get(pos + LOG_HEADER_LEN..pos + LOG_HEADER_LEN + len) else {
+            break;
+        };
+        pos += LOG_HEADER_LEN + len;
+
+        match kind {
+            // Full record, or first, middle and last fragments
+            1 => read_batch(fragment, merge)?,
+            2 => record = fragment.to_vec(),
+            3 => record.extend_from_slice(fragment),
+            4 => {
+                record.extend_from_slice(fragment);
+                read_batch(&record, merge)?;
+                record.clear();
+            }
+            // Preallocated, zeroed space
+            _ => pos += block_left.saturating_sub(LOG_HEADER_LEN + len),
+        }
+    }
+    Ok(())
+}
+
+fn read_batch(batch: &[u8], merge: &mut impl FnMut(&[u8], u64, Option<&[u8]>)) -> anyhow::Result<()> {
+    if batch.len() < 12 {
+        anyhow::bail!("Write batch too short");
+    }
+    let seq = u64::from_le_bytes(batch[..8].try_into()?);
+    let count = u32::from_le_bytes(batch[8..12].try_into()?) as u64;
+
+    let mut pos = 12;
+    for i in 0..count {
+        let seq = seq.checked_add(i).ok_or_else(|| anyhow::anyhow!("Sequence number overflow"))?;
+        let kind = *batch.get(pos).ok_or_else(|| anyhow::anyhow!("Truncated write batch"))?;
+        pos += 1;
+        let key = length_prefixed(batch, &mut pos)?;
+        match kind {
+            TYPE_VALUE => merge(key, seq, Some(length_prefixed(batch, &mut pos)?)),
+            TYPE_DELETION => merge(key, seq, None),
+            kind => anyhow::bail!("Unknown write batch entry {}", kind),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    /// A table block sharing key prefixes between entries, with a single
+    /// restart point.
+    fn block(entries: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut last: &[u8] = &[];
+        for (key, value) in entries {
+            let shared = key.iter().zip(last).take_while(|(a, b)| a == b).count();
+            put_varint(&mut out, shared as u64);
+            put_varint(&mut out, (key.len() - shared) as u64);
+            put_varint(&mut out, value.len() as u64);
+            out.extend(&key[shared..]);
+            out.extend(value);
+            last = key;
+        }
+        out.extend(0u32.to_le_bytes());
+        out.extend(1u32.to_le_bytes());
+        out
+    }
+
+    fn internal_key(key: &[u8], seq: u64, kind: u8) -> Vec<u8> {
+        [key, &((seq << 8) | u64::from(kind)).to_le_bytes()].concat()
+    }
+
+    /// Append a block with its trailer (checksum left zero) and return its
+    /// handle.
+    fn push_block(file: &mut Vec<u8>, contents: &[u8], compressed: bool) -> Vec<u8> {
+        let stored = match compressed {
+            true => snap::raw::Encoder::new().compress_vec(contents).unwrap(),
+            false => contents.to_vec(),
+        };
+        let mut handle = Vec::new();
+        put_varint(&mut handle, file.len() as u64);
+        put_varint(&mut handle, stored.len() as u64);
+        file.extend(&stored);
+        file.push(u8::from(compressed));
+        file.extend([0; 4]);
+        handle
+    }
+
+    /// A table with one data block per entry list, the second compressed.
+    fn table(blocks: &[Vec<(Vec<u8>, Vec<u8>)>]) -> Vec<u8> {
+        let mut file = Vec::new();
+        let mut index = Vec::new();
+        for (i, entries) in blocks.iter().enumerate() {
+            let handle = push_block(&mut file, &block(entries), i == 1);
+            index.push((entries.last().unwrap().0.clone(), handle));
+        }
+        let metaindex = push_block(&mut file, &block(&[]), false);
+        let index = push_block(&mut file, &block(&index), false);
+
+        let mut footer = [metaindex, index].concat();
+        footer.resize(40, 0);
+        footer.extend(TABLE_MAGIC.to_le_bytes());
+        file.extend(footer);
+        file
+    }
+
+    fn batch(seq: u64, entries: &[(&[u8], Option<&[u8]>)]) -> Vec<u8> {
+        let mut out = seq.to_le_bytes().to_vec();
+        out.extend((entries.len() as u32).to_le_bytes());
+        for (key, value) in entries {
+            out.push(if value.is_some() { TYPE_VALUE } else { TYPE_DELETION });
+            put_varint(&mut out, key.len() as u64);
+            out.extend(*key);
+            if let Some(value) = value {
+                put_varint(&mut out, value.len() as u64);
+                out.extend(*value);
+            }
+        }
+        out
+    }
+
+    /// A log holding `record` split into first, middle and last fragments.
+    fn fragmented_log(record: &[u8]) -> Vec<u8> {
+        let third = record.len() / 3;
+        let mut log = Vec::new();
+        for (kind, fragment) in [(2, &record[..third]), (3, &record[third..2 * third]), (4, &record[2 * third..])] {
+            log.extend([0; 4]);
+            log.extend((fragment.len() as u16).to_le_bytes());
+            log.push(kind);
+            log.extend(fragment);
+        }
+        log
+    }
+
+    #[test]
+    fn merges_tables_and_logs() {
+        let dir = std::env::temp_dir().join(format!("leveldb-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let blocks = [
+            vec![
+                (internal_key(b"bAAAA", 1, TYPE_VALUE), b"one".to_vec()),
+                (internal_key(b"bAAAB", 2, TYPE_VALUE), b"two".to_vec()),
+            ],
+            vec![
+                (internal_key(b"bAAAC", 20, TYPE_VALUE), b"three".to_vec()),
+                (internal_key(b"bAAAD", 4, TYPE_DELETION), Vec::new()),
+                (internal_key(b"fAAAA", 4, TYPE_VALUE), b"other".to_vec()),
+            ],
+        ];
+        std::fs::write(dir.join("000005.ldb"), table(&blocks)).unwrap();
+        let updates = batch(
+            10,
+            &[(b"bAAAB", None), (b"bAAAC", Some(b"older")), (b"bAAAD", Some(b"four")), (b"bAAAA", Some(b"newer"))],
+        );
+        // Sequence numbers, not file order, decide which value wins
+        std::fs::write(dir.join("000006.log"), fragmented_log(&updates)).unwrap();
+        std::fs::write(dir.join("CURRENT"), b"MANIFEST-000004\n").unwrap();
+
+        let entries = read(&dir, b"b").unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut entries: Vec<_> = entries.into_iter().collect();
+        entries.sort();
+        assert_eq!(
+            entries,
+            [
+                (b"bAAAA".to_vec(), b"newer".to_vec()),
+                (b"bAAAC".to_vec(), b"three".to_vec()),
+                (b"bAAAD".to_vec(), b"four".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_corrupt_lengths() {
+        let mut end = usize::MAX;
+        assert!(slice(&[1, 2, 3], &mut end, 2).is_err());
+        assert!(slice(&[1, 2, 3], &mut 2, usize::MAX).is_err());
+        assert!(table_block(&[0; 16], (usize::MAX - 2, 1)).is_err());
+        assert!(table_block(&[0; 16], (4, usize::MAX)).is_err());
+        assert!(block_entries(&u32::MAX.to_le_bytes()).is_err());
+        assert!(read_batch(&batch(u64::MAX, &[(b"a", None), (b"b", None)]), &mut |_, _, _| {}).is_err());
+
+        let mut table = table(&[vec![(internal_key(b"b", 1, TYPE_VALUE), b"x".to_vec())]]);
+        let len = table.len();
+        table[len - 8] ^= 1;
+        assert!(read_table(&table, &mut |_, _, _| {}).is_err());
+    }
+}
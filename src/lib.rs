@@ -1,20 +1,80 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use tokio::sync::broadcast;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::Duration;
+use tokio::sync::{watch, RwLock};
+use tokio::task::JoinHandle;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+pub mod builder;
+pub mod config;
+pub mod decode;
+pub mod graphql;
+pub mod health;
+pub mod history;
+pub mod leveldb;
+pub mod lint;
+pub mod metrics;
+pub mod openapi;
+pub mod price;
+pub mod runestone;
+pub mod source;
+pub mod storage;
+pub mod telemetry;
+pub mod tokens;
+
+/// Number of recent transactions retained for replay and history queries.
+pub const HISTORY_CAPACITY: usize = 10_000;
+
+/// Importance added to activities touching a watched address.
+pub const WATCHLIST_BOOST: u8 = 3;
+pub const MAX_IMPORTANCE: u8 = 10;
+
+/// How long `/api/health` waits for the backend to report its tip.
+pub const BACKEND_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Transactions per page of Esplora's `/block/:hash/txs` listing.
+pub const ESPLORA_BLOCK_PAGE: usize = 25;
+
+/// The next item of `stream`, or `None` once shutdown is signalled.
+async fn next_or_shutdown<S: futures_util::Stream + Unpin>(stream: &mut S, shutdown: &mut watch::Receiver<bool>) -> Option<S::Item> {
+    use futures_util::StreamExt;
+    
+    tokio::select! {
+        item = stream.next() => item,
+        _ = shutdown.wait_for(|stop| *stop) => None,
+    }
+}
+
+fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Transaction {
     pub txid: String,
     pub size: u32,
+    #[serde(default)]
+    pub weight: Option<u32>,
     pub fee: Option<u64>,
     pub status: TxStatus,
     pub vout: Vec<Output>,
     pub vin: Vec<Input>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl Transaction {
+    /// Virtual size in vbytes; the raw size when the weight is unknown.
+    pub fn vsize(&self) -> u32 {
+        self.weight.map(|w| w.div_ceil(4)).unwrap_or(self.size)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Input {
     pub txid: String,
     pub vout: u32,
@@ -22,23 +82,27 @@ pub struct Input {
     pub prevout: Option<Output>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct TxStatus {
     pub confirmed: bool,
     #[serde(default)]
     pub block_height: Option<u32>,
     #[serde(default)]
     pub block_time: Option<u64>,
+    /// Position of the transaction in its block; a rune it etches gets the
+    /// id `block_height:block_index`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block_index: Option<u32>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Output {
     pub scriptpubkey: String,
     pub scriptpubkey_address: Option<String>,
     pub value: u64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Activity {
     pub protocol: String,
     pub operation: String,
@@ -50,7 +114,7 @@ pub struct Activity {
     pub importance: u8,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct StateChange {
     pub field: String,
     pub before: Option<String>,
@@ -59,7 +123,7 @@ pub struct StateChange {
     pub change_type: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct ProtocolStats {
     pub protocol: String,
     pub total_txs: u64,
@@ -68,23 +132,93 @@ pub struct ProtocolStats {
     pub last_activity: u64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct LiveTransaction {
+    /// Monotonically increasing sequence number assigned on broadcast.
+    #[serde(default)]
+    pub seq: u64,
     pub txid: String,
     pub timestamp: u64,
     pub protocols: Vec<String>,
     pub total_value: u64,
+    /// `total_value` in USD at the price when the transaction was seen, or
+    /// when its block was mined; needs a price source.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_value_usd: Option<f64>,
     pub activities: Vec<Activity>,
+    /// Sats per vbyte.
     pub fee_rate: f64,
     pub size: u32,
+    #[serde(default)]
+    pub block_height: Option<u32>,
+    /// Left the mempool unconfirmed: replaced, evicted, or mined in a block
+    /// not processed yet. Cleared when it confirms.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub removed: bool,
+    /// Every input (prevout) and output address the transaction touches.
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    /// Addresses from the watchlist that this transaction touches.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub watched: Vec<String>,
+}
+
+/// Why a transaction couldn't be fetched. Returned inside `anyhow::Error`;
+/// use `SourceError::classify` to recover it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SourceError {
+    InvalidTxid,
+    NotFound,
+    RateLimited,
+    Unavailable(String),
+}
+
+impl std::fmt::Display for SourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SourceError::InvalidTxid => write!(f, "Invalid txid: expected 64 hex characters"),
+            SourceError::NotFound => write!(f, "Transaction not found"),
+            SourceError::RateLimited => write!(f, "Upstream rate limit exceeded"),
+            SourceError::Unavailable(reason) => write!(f, "Upstream unavailable: {}", reason),
+        }
+    }
 }
 
+impl std::error::Error for SourceError {}
+
+impl SourceError {
+    /// Machine-readable error code.
+    pub fn code(&self) -> &'static str {
+        match self {
+            SourceError::InvalidTxid => "invalid_txid",
+            SourceError::NotFound => "not_found",
+            SourceError::RateLimited => "rate_limited",
+            SourceError::Unavailable(_) => "upstream_unavailable",
+        }
+    }
+    
+    /// The `SourceError` behind an error, if any.
+    pub fn classify(err: &anyhow::Error) -> Option<&SourceError> {
+        err.downcast_ref::<SourceError>()
+    }
+}
+
+pub fn is_valid_txid(txid: &str) -> bool {
+    txid.len() == 64 && txid.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Client for an Esplora REST API.
+#[derive(Clone)]
 pub struct BitcoinClient {
     client: reqwest::Client,
     base_url: String,
-    use_rpc: bool,
-    rpc_user: String,
-    rpc_pass: String,
+    network: config::Network,
+}
+
+impl Default for BitcoinClient {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl BitcoinClient {
@@ -92,81 +226,181 @@ impl BitcoinClient {
         Self {
             client: reqwest::Client::new(),
             base_url: "https://blockstream.info/api".to_string(),
-            use_rpc: false,
-            rpc_user: String::new(),
-            rpc_pass: String::new(),
+            network: config::Network::Mainnet,
         }
     }
     
-    pub fn new_rpc(user: &str, pass: &str) -> Self {
+    pub fn from_config(backend: &config::BackendConfig) -> Self {
         Self {
             client: reqwest::Client::new(),
-            base_url: "http://127.0.0.1:8332".to_string(),
-            use_rpc: true,
-            rpc_user: user.to_string(),
-            rpc_pass: pass.to_string(),
+            base_url: backend.url.trim_end_matches('/').to_string(),
+            network: backend.network,
         }
     }
-
+    
+    /// GET from the upstream API, recording request count, latency and errors.
+    #[tracing::instrument(level = "debug", skip(self), fields(backend = "esplora"))]
+    async fn get(&self, endpoint: &'static str, url: &str) -> reqwest::Result<reqwest::Response> {
+        let m = metrics::metrics();
+        let labels = ["esplora", endpoint];
+        m.upstream_requests.with_label_values(&labels).inc();
+        
+        let timer = m.upstream_duration.with_label_values(&labels).start_timer();
+        let result = self.client.get(url).send().await;
+        timer.observe_duration();
+        
+        let kind = match &result {
+            Ok(resp) if resp.status() == reqwest::StatusCode::TOO_MANY_REQUESTS => Some("rate_limited"),
+            Ok(resp) if resp.status().is_server_error() => Some("http_5xx"),
+            Ok(resp) if resp.status().is_client_error() => Some("http_4xx"),
+            Ok(_) => None,
+            Err(e) if e.is_timeout() => Some("timeout"),
+            Err(_) => Some("network"),
+        };
+        if let Some(kind) = kind {
+            m.upstream_errors.with_label_values(&["esplora", endpoint, kind]).inc();
+        }
+        
+        result
+    }
+    
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+    
+    pub fn network(&self) -> config::Network {
+        self.network
+    }
+    
+    pub async fn get_tip_height(&self) -> anyhow::Result<u32> {
+        let url = format!("{}/blocks/tip/height", self.base_url);
+        let resp = self.get("tip_height", &url).await?;
+        
+        if !resp.status().is_success() {
+            anyhow::bail!("HTTP {}", resp.status());
+        }
+        
+        Ok(resp.text().await?.trim().parse()?)
+    }
+    
     pub async fn get_transaction(&self, txid: &str) -> anyhow::Result<Transaction> {
+        if !is_valid_txid(txid) {
+            return Err(SourceError::InvalidTxid.into());
+        }
+        
         let url = format!("{}/tx/{}", self.base_url, txid);
-        let resp = self.client.get(&url).send().await?;
+        let resp = self.get("tx", &url).await
+            .map_err(|e| SourceError::Unavailable(e.to_string()))?;
         
-        if !resp.status().is_success() {
-            anyhow::bail!("Transaction not found");
+        let status = resp.status();
+        if status == reqwest::StatusCode::NOT_FOUND || status == reqwest::StatusCode::BAD_REQUEST {
+            return Err(SourceError::NotFound.into());
+        }
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            return Err(SourceError::RateLimited.into());
+        }
+        if !status.is_success() {
+            return Err(SourceError::Unavailable(format!("HTTP {}", status)).into());
         }
         
-        let tx: Transaction = resp.json().await?;
+        let tx: Transaction = resp.json().await
+            .map_err(|e| SourceError::Unavailable(format!("unexpected response: {}", e)))?;
         Ok(tx)
     }
 
-    pub async fn get_mempool_txs(&self) -> anyhow::Result<Vec<String>> {
+    /// Every txid in the mempool.
+    pub async fn get_mempool_txids(&self) -> anyhow::Result<Vec<String>> {
+        let url = format!("{}/mempool/txids", self.base_url);
+        let resp = self.get("mempool_txids", &url).await?;
+        
+        if resp.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            return Err(SourceError::RateLimited.into());
+        }
+        if !resp.status().is_success() {
+            anyhow::bail!("HTTP {} listing mempool transactions", resp.status());
+        }
+        
+        Ok(resp.json().await?)
+    }
+    
+    /// The `limit` most recent mempool txids.
+    pub async fn get_mempool_txs(&self, limit: usize) -> anyhow::Result<Vec<String>> {
         let url = format!("{}/mempool/recent", self.base_url);
-        let resp = self.client.get(&url).send().await?;
+        let resp = self.get("mempool_recent", &url).await?;
         
         if !resp.status().is_success() {
-            return Ok(Vec::new());
+            anyhow::bail!("HTTP {} listing mempool transactions", resp.status());
         }
         
         let recent_txs: Vec<serde_json::Value> = resp.json().await?;
         let txids: Vec<String> = recent_txs
             .into_iter()
             .filter_map(|tx| tx.get("txid").and_then(|t| t.as_str()).map(String::from))
-            .take(5)
+            .take(limit)
             .collect();
         
         Ok(txids)
     }
 
-    pub async fn get_recent_blocks(&self) -> anyhow::Result<Vec<String>> {
+    /// Height and hash of the newest block.
+    pub async fn get_tip(&self) -> anyhow::Result<(u32, String)> {
         let url = format!("{}/blocks", self.base_url);
-        let resp = self.client.get(&url).send().await?;
+        let resp = self.get("blocks", &url).await?;
         
         if !resp.status().is_success() {
-            return Ok(Vec::new());
+            anyhow::bail!("HTTP {} listing recent blocks", resp.status());
         }
         
         let blocks: Vec<serde_json::Value> = resp.json().await?;
+        let block = blocks.first().ok_or_else(|| anyhow::anyhow!("No blocks returned"))?;
+        let height = block.get("height").and_then(|h| h.as_u64()).ok_or_else(|| anyhow::anyhow!("Block without height"))?;
+        let hash = block.get("id").and_then(|h| h.as_str()).ok_or_else(|| anyhow::anyhow!("Block without id"))?;
         
-        let mut all_txids = Vec::new();
-        
-        if let Some(block) = blocks.first() {
-            if let Some(hash) = block.get("id").and_then(|h| h.as_str()) {
-                let txs_url = format!("{}/block/{}/txs", self.base_url, hash);
-                if let Ok(resp) = self.client.get(&txs_url).send().await {
-                    if let Ok(txs) = resp.json::<Vec<serde_json::Value>>().await {
-                        let txids: Vec<String> = txs
-                            .into_iter()
-                            .filter_map(|tx| tx.get("txid").and_then(|t| t.as_str()).map(String::from))
-                            .take(10)
-                            .collect();
-                        all_txids.extend(txids);
-                    }
-                }
-            }
+        Ok((height as u32, hash.to_string()))
+    }
+    
+    /// Hash of the best-chain block at `height`.
+    pub async fn get_block_hash(&self, height: u32) -> anyhow::Result<String> {
+        let url = format!("{}/block-height/{}", self.base_url, height);
+        let resp = self.get("block_height", &url).await?;
+        
+        if !resp.status().is_success() {
+            anyhow::bail!("HTTP {} looking up block {}", resp.status(), height);
         }
         
-        Ok(all_txids)
+        Ok(resp.text().await?.trim().to_string())
+    }
+    
+    /// Number of transactions in a block.
+    pub async fn get_block_tx_count(&self, hash: &str) -> anyhow::Result<usize> {
+        let url = format!("{}/block/{}", self.base_url, hash);
+        let resp = self.get("block", &url).await?;
+        
+        if !resp.status().is_success() {
+            anyhow::bail!("HTTP {} fetching block {}", resp.status(), hash);
+        }
+        
+        let block: serde_json::Value = resp.json().await?;
+        block.get("tx_count")
+            .and_then(|c| c.as_u64())
+            .map(|c| c as usize)
+            .ok_or_else(|| anyhow::anyhow!("Block without tx_count"))
+    }
+    
+    /// One page of a block's transactions, starting at `start` (a multiple
+    /// of `ESPLORA_BLOCK_PAGE`).
+    pub async fn get_block_txs_page(&self, hash: &str, start: usize) -> anyhow::Result<Vec<Transaction>> {
+        let url = format!("{}/block/{}/txs/{}", self.base_url, hash, start);
+        let resp = self.get("block_txs", &url).await?;
+        
+        if resp.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            return Err(SourceError::RateLimited.into());
+        }
+        if !resp.status().is_success() {
+            anyhow::bail!("HTTP {} listing block transactions", resp.status());
+        }
+        
+        Ok(resp.json().await?)
     }
 }
 
@@ -175,12 +409,53 @@ pub mod parsers {
     use super::*;
     use regex::Regex;
     
+    /// A protocol parser: the protocol it reports and the function that
+    /// extracts its activities from a transaction.
+    #[derive(Clone, Copy)]
+    pub struct Parser {
+        pub protocol: &'static str,
+        pub parse: fn(&Transaction) -> Vec<Activity>,
+    }
+    
+    /// Every parser, in the order their activities are reported.
+    pub const REGISTRY: &[Parser] = &[
+        Parser { protocol: "brc20", parse: parse_brc20 },
+        Parser { protocol: "stamps", parse: parse_stamps },
+        Parser { protocol: "runes", parse: parse_runes },
+    ];
+    
+    /// Run every registered parser, returning the protocols that matched
+    /// and all of their activities.
+    pub fn parse_all(tx: &Transaction) -> (Vec<String>, Vec<Activity>) {
+        parse_with(REGISTRY, tx)
+    }
+    
+    /// Run the given parsers in order, like `parse_all`.
+    pub fn parse_with(parsers: &[Parser], tx: &Transaction) -> (Vec<String>, Vec<Activity>) {
+        let mut protocols = Vec::new();
+        let mut activities = Vec::new();
+        
+        for parser in parsers {
+            let found = metrics::metrics().time_parser(parser.protocol, || (parser.parse)(tx));
+            if !found.is_empty() {
+                protocols.push(parser.protocol.to_string());
+                activities.extend(found);
+            }
+        }
+        
+        (protocols, activities)
+    }
+    
     pub fn parse_brc20(tx: &Transaction) -> Vec<Activity> {
         let mut activities = Vec::new();
         
         for (idx, input) in tx.vin.iter().enumerate() {
             if let Some(witness) = &input.witness {
-                if let Some(activity) = extract_brc20_from_witness(witness, idx) {
+                if let Some(mut activity) = extract_brc20_from_witness(witness, idx) {
+                    // A new inscription lands on the first sat, i.e. the first output
+                    if let Some(address) = output_address(tx, 0) {
+                        activity.data.insert("address".to_string(), address);
+                    }
                     activities.push(activity);
                 }
             }
@@ -202,17 +477,19 @@ pub mod parsers {
     }
     
     pub fn parse_runes(tx: &Transaction) -> Vec<Activity> {
-        let mut activities = Vec::new();
+        let (Some(idx), Some(artifact)) = (runestone::runestone_output(tx), runestone::decipher(tx)) else {
+            return Vec::new();
+        };
         
-        for (idx, out) in tx.vout.iter().enumerate() {
-            if out.scriptpubkey.starts_with("6a5d") {
-                if let Some(activity) = extract_runes_from_output(out, idx) {
-                    activities.push(activity);
-                }
-            }
+        match artifact {
+            runestone::Artifact::Cenotaph(cenotaph) => vec![cenotaph_activity(&cenotaph, idx)],
+            runestone::Artifact::Runestone(runestone) => extract_runes_from_runestone(tx, &runestone, idx),
         }
-        
-        activities
+    }
+    
+    /// Address of the output at `idx`, if it has one.
+    fn output_address(tx: &Transaction, idx: usize) -> Option<serde_json::Value> {
+        tx.vout.get(idx)?.scriptpubkey_address.clone().map(serde_json::Value::String)
     }
     
     fn extract_brc20_from_witness(witness: &[String], idx: usize) -> Option<Activity> {
@@ -257,6 +534,9 @@ pub mod parsers {
         if let Some(lim) = brc20_data.get("lim") {
             data.insert("limit".to_string(), lim.clone());
         }
+        if let Some(dec) = brc20_data.get("dec").and_then(|d| d.as_str().map_or_else(|| d.as_u64(), |d| d.parse().ok())) {
+            data.insert("decimals".to_string(), dec.into());
+        }
         
         let importance = match op.as_str() {
             "deploy" => 8,
@@ -303,247 +583,650 @@ pub mod parsers {
         None
     }
     
-    fn extract_runes_from_output(_out: &Output, idx: usize) -> Option<Activity> {
+    fn extract_runes_from_runestone(tx: &Transaction, runestone: &runestone::Runestone, idx: usize) -> Vec<Activity> {
+        let mut activities = Vec::new();
+        
+        // Unallocated runes go to the pointer, or else the first non-OP_RETURN output
+        let default_output = runestone.pointer.map(|p| p as usize).or_else(|| {
+            tx.vout.iter().position(|o| !o.scriptpubkey.starts_with("6a"))
+        });
+        let default_address = default_output.and_then(|o| output_address(tx, o));
+        
+        if let Some(etching) = &runestone.etching {
+            let name = etching.rune.clone().unwrap_or_else(|| "(reserved)".to_string());
+            
+            let mut data = HashMap::new();
+            data.insert("rune".to_string(), serde_json::Value::String(name.clone()));
+            if let (Some(block), Some(index)) = (tx.status.block_height, tx.status.block_index) {
+                let id = runestone::RuneId { block: block.into(), tx: index };
+                data.insert("rune_id".to_string(), serde_json::Value::String(id.to_string()));
+            }
+            if let Some(divisibility) = etching.divisibility {
+                data.insert("divisibility".to_string(), divisibility.into());
+            }
+            if let Some(symbol) = etching.symbol {
+                data.insert("symbol".to_string(), serde_json::Value::String(symbol.to_string()));
+            }
+            if let Some(premine) = etching.premine {
+                data.insert("premine".to_string(), serde_json::Value::String(premine.to_string()));
+            }
+            if let Some(terms) = &etching.terms {
+                if let Some(cap) = terms.cap {
+                    data.insert("cap".to_string(), serde_json::Value::String(cap.to_string()));
+                }
+                if let Some(amount) = terms.amount {
+                    data.insert("limit".to_string(), serde_json::Value::String(amount.to_string()));
+                }
+                data.insert("terms".to_string(), serde_json::json!({
+                    "cap": terms.cap.map(|c| c.to_string()),
+                    "amount": terms.amount.map(|a| a.to_string()),
+                    "height_start": terms.height.0,
+                    "height_end": terms.height.1,
+                    "offset_start": terms.offset.0,
+                    "offset_end": terms.offset.1,
+                }));
+            }
+            data.insert("turbo".to_string(), etching.turbo.into());
+            if let Some(address) = &default_address {
+                data.insert("address".to_string(), address.clone());
+            }
+            
+            activities.push(Activity {
+                protocol: "runes".to_string(),
+                operation: "etch".to_string(),
+                output: idx,
+                data,
+                changes: vec![],
+                description: format!("New rune '{}' etched", name),
+                value_usd: None,
+                importance: 8,
+            });
+        }
+        
+        if let Some(mint) = runestone.mint {
+            let mut data = HashMap::new();
+            data.insert("rune_id".to_string(), serde_json::Value::String(mint.to_string()));
+            if let Some(address) = &default_address {
+                data.insert("address".to_string(), address.clone());
+            }
+            
+            activities.push(Activity {
+                protocol: "runes".to_string(),
+                operation: "mint".to_string(),
+                output: idx,
+                data,
+                changes: vec![],
+                description: format!("Minted rune {}", mint),
+                value_usd: None,
+                importance: 5,
+            });
+        }
+        
+        if !runestone.edicts.is_empty() || activities.is_empty() {
+            let edicts: Vec<serde_json::Value> = runestone.edicts.iter().map(|edict| {
+                serde_json::json!({
+                    "rune_id": edict.id.to_string(),
+                    "amount": edict.amount.to_string(),
+                    "output": edict.output,
+                    "address": output_address(tx, edict.output as usize),
+                })
+            }).collect();
+            
+            let mut data = HashMap::new();
+            data.insert("edicts".to_string(), serde_json::Value::Array(edicts));
+            if let Some(pointer) = runestone.pointer {
+                data.insert("pointer".to_string(), pointer.into());
+            }
+            
+            let description = match runestone.edicts.len() {
+                0 => "Runes protocol activity".to_string(),
+                1 => format!("Transfer rune {}", runestone.edicts[0].id),
+                n => format!("Transfer across {} rune edicts", n),
+            };
+            
+            activities.push(Activity {
+                protocol: "runes".to_string(),
+                operation: "transfer".to_string(),
+                output: idx,
+                data,
+                changes: vec![],
+                description,
+                value_usd: None,
+                importance: 3,
+            });
+        }
+        
+        activities
+    }
+    
+    fn cenotaph_activity(cenotaph: &runestone::Cenotaph, idx: usize) -> Activity {
         let mut data = HashMap::new();
-        data.insert("protocol".to_string(), serde_json::Value::String("runes".to_string()));
+        data.insert("cenotaph".to_string(), true.into());
+        data.insert("flaws".to_string(), serde_json::to_value(&cenotaph.flaws).unwrap_or_default());
+        if let Some(rune) = &cenotaph.etching {
+            data.insert("rune".to_string(), serde_json::Value::String(rune.clone()));
+        }
+        if let Some(mint) = cenotaph.mint {
+            data.insert("rune_id".to_string(), serde_json::Value::String(mint.to_string()));
+        }
         
-        Some(Activity {
+        Activity {
             protocol: "runes".to_string(),
-            operation: "transfer".to_string(),
+            operation: "cenotaph".to_string(),
             output: idx,
             data,
             changes: vec![],
-            description: "Runes protocol activity".to_string(),
+            description: format!("Cenotaph: input runes burned ({})", cenotaph.flaws.join(", ")),
             value_usd: None,
             importance: 7,
+        }
+    }
+}
+
+/// Message sent to live feed clients. Transactions carry their `seq`; a gap
+/// tells the client that `skipped` events after `last_seq` were not delivered
+/// because it could not keep up, so it can replay them with `since_seq`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FeedEvent {
+    Transaction(LiveTransaction),
+    Gap { skipped: u64, last_seq: u64 },
+    /// Sent just before a transaction that touches watched addresses.
+    Alert { seq: u64, txid: String, addresses: Vec<String>, description: String },
+    /// Sent once every transaction of a newly connected block is processed.
+    Block(BlockSummary),
+}
+
+/// What a connected block contained.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct BlockSummary {
+    pub height: u32,
+    pub hash: String,
+    pub tx_count: usize,
+    /// Transactions with metaprotocol activity.
+    pub metaprotocol_txs: u64,
+    /// Transactions with activity, by protocol.
+    pub protocols: BTreeMap<String, u64>,
+    /// Fees paid by transactions with activity, in sats. Transactions whose
+    /// fee is unknown are left out.
+    pub metaprotocol_fees: u64,
+    pub timestamp: u64,
+}
+
+impl FeedEvent {
+    /// The alert to send ahead of a transaction touching watched addresses.
+    pub fn alert_for(tx: &LiveTransaction) -> Option<Self> {
+        if tx.watched.is_empty() {
+            return None;
+        }
+        
+        let description = tx.activities.first()
+            .map(|a| a.description.clone())
+            .unwrap_or_else(|| "Activity detected".to_string());
+        
+        Some(FeedEvent::Alert {
+            seq: tx.seq,
+            txid: tx.txid.clone(),
+            addresses: tx.watched.clone(),
+            description,
         })
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct WatchEntry {
+    pub address: String,
+    pub label: Option<String>,
+    pub added_at: u64,
+}
+
+/// Counters describing how well live feed clients keep up.
+#[derive(Debug, Default)]
+pub struct FeedMetrics {
+    connected_clients: AtomicU64,
+    lagged_events: AtomicU64,
+    dropped_messages: AtomicU64,
+    gaps_reported: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct FeedMetricsSnapshot {
+    pub connected_clients: u64,
+    pub lagged_events: u64,
+    pub dropped_messages: u64,
+    pub gaps_reported: u64,
+}
+
+impl FeedMetrics {
+    pub fn client_connected(&self) {
+        self.connected_clients.fetch_add(1, Ordering::Relaxed);
+        metrics::metrics().ws_clients.inc();
+    }
+    
+    pub fn client_disconnected(&self) {
+        self.connected_clients.fetch_sub(1, Ordering::Relaxed);
+        metrics::metrics().ws_clients.dec();
+    }
+    
+    /// A client fell behind the broadcast channel and lost `skipped` messages.
+    pub fn record_lag(&self, skipped: u64) {
+        self.lagged_events.fetch_add(1, Ordering::Relaxed);
+        self.dropped_messages.fetch_add(skipped, Ordering::Relaxed);
+        metrics::metrics().feed_lagged_events.inc();
+        metrics::metrics().feed_dropped_messages.inc_by(skipped);
+    }
+    
+    /// A message was dropped because a client's own buffer was full.
+    pub fn record_dropped(&self) {
+        self.dropped_messages.fetch_add(1, Ordering::Relaxed);
+        metrics::metrics().feed_dropped_messages.inc();
+    }
+    
+    pub fn record_gap(&self) {
+        self.gaps_reported.fetch_add(1, Ordering::Relaxed);
+        metrics::metrics().feed_gaps.inc();
+    }
+    
+    pub fn snapshot(&self) -> FeedMetricsSnapshot {
+        FeedMetricsSnapshot {
+            connected_clients: self.connected_clients.load(Ordering::Relaxed),
+            lagged_events: self.lagged_events.load(Ordering::Relaxed),
+            dropped_messages: self.dropped_messages.load(Ordering::Relaxed),
+            gaps_reported: self.gaps_reported.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Where a client wants replay to start when it connects to the live feed.
+/// `since_seq` replays every event after that sequence number, `since`
+/// replays every event at or after that unix timestamp.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct ReplayRequest {
+    pub since_seq: Option<u64>,
+    pub since: Option<u64>,
+}
+
+impl ReplayRequest {
+    pub fn is_empty(&self) -> bool {
+        self.since_seq.is_none() && self.since.is_none()
+    }
+
+    pub fn matches(&self, tx: &LiveTransaction) -> bool {
+        if let Some(seq) = self.since_seq {
+            if tx.seq <= seq {
+                return false;
+            }
+        }
+        if let Some(since) = self.since {
+            if tx.timestamp < since {
+                return false;
+            }
+        }
+        true
+    }
+}
+
 // Live monitoring system
 pub struct MetaprotocolMonitor {
-    client: BitcoinClient,
+    source: Arc<dyn source::TransactionSource>,
+    parsers: Vec<parsers::Parser>,
+    storage: Arc<dyn storage::Storage>,
+    scan: config::ScanConfig,
+    channel_capacity: usize,
+    demo: bool,
     tx_broadcaster: broadcast::Sender<LiveTransaction>,
+    block_broadcaster: broadcast::Sender<BlockSummary>,
     stats: Arc<RwLock<HashMap<String, ProtocolStats>>>,
+    history: RwLock<history::History>,
+    last_seq: AtomicU64,
+    feed_metrics: Arc<FeedMetrics>,
+    tokens: RwLock<tokens::TokenRegistry>,
+    prices: Option<Arc<dyn price::PriceSource>>,
+    watchlist: RwLock<HashMap<String, WatchEntry>>,
+    sync: RwLock<health::SyncState>,
+    closing: watch::Sender<bool>,
 }
 
 impl MetaprotocolMonitor {
+    /// A monitor with the default settings.
     pub fn new() -> (Self, broadcast::Receiver<LiveTransaction>) {
-        let (tx, rx) = broadcast::channel(1000);
-        
-        (Self {
-            client: BitcoinClient::new(),
-            tx_broadcaster: tx,
-            stats: Arc::new(RwLock::new(HashMap::new())),
-        }, rx)
-    }
-    
-    pub async fn start_monitoring(self: Arc<Self>, demo_mode: bool) {
-        if demo_mode {
-            let demo_monitor = self.clone();
-            tokio::spawn(async move {
-                println!("Demo mode active - generating simulated transactions...");
-                let mut rng = 0u64;
-                loop {
-                    tokio::time::sleep(tokio::time::Duration::from_secs(2 + (rng % 3))).await;
-                    
-                    let num_txs = 1 + (rng % 3) as usize;
-                    for _ in 0..num_txs {
-                        demo_monitor.generate_demo_transaction(rng).await;
-                        rng = rng.wrapping_mul(1664525).wrapping_add(1013904223);
-                    }
-                }
-            });
+        Self::with_config(config::Config::default()).expect("default config is valid")
+    }
+    
+    /// A monitor using the backend, scan, feed and storage settings of
+    /// `config`, which is validated first. Use `builder::MonitorBuilder`
+    /// to supply other parts.
+    pub fn with_config(config: config::Config) -> anyhow::Result<(Self, broadcast::Receiver<LiveTransaction>)> {
+        config.validate()?;
+        builder::MonitorBuilder::from_config(&config).into_monitor()
+    }
+    
+    /// The source transactions are fetched from, shared with the analysis
+    /// endpoints.
+    pub fn source(&self) -> &dyn source::TransactionSource {
+        self.source.as_ref()
+    }
+    
+    /// Subscribe to the live feed, returning any buffered transactions the
+    /// replay request asks for. The snapshot and the subscription are taken
+    /// under the same lock as `publish`, so nothing is missed or duplicated
+    /// between the replayed events and the first live one.
+    pub async fn subscribe(&self, replay: &ReplayRequest) -> (Vec<LiveTransaction>, broadcast::Receiver<LiveTransaction>) {
+        let history = self.history.read().await;
+        let rx = self.tx_broadcaster.subscribe();
+        
+        let backlog = if replay.is_empty() {
+            Vec::new()
         } else {
-            let mempool_monitor = self.clone();
-            tokio::spawn(async move {
-                loop {
-                    if let Err(e) = mempool_monitor.scan_mempool().await {
-                        eprintln!("Mempool scan error: {}", e);
-                    }
-                    tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
+            history.iter().filter(|tx| replay.matches(tx)).cloned().collect()
+        };
+        
+        (backlog, rx)
+    }
+    
+    /// Subscribe to the summaries of newly connected blocks.
+    pub fn subscribe_blocks(&self) -> broadcast::Receiver<BlockSummary> {
+        self.block_broadcaster.subscribe()
+    }
+    
+    pub fn feed_metrics(&self) -> Arc<FeedMetrics> {
+        self.feed_metrics.clone()
+    }
+    
+    /// Turns true once the monitor is shutting down. Live feed subscribers
+    /// should deliver what they have received and disconnect.
+    pub fn closing(&self) -> watch::Receiver<bool> {
+        self.closing.subscribe()
+    }
+    
+    pub fn is_closing(&self) -> bool {
+        *self.closing.borrow()
+    }
+    
+    pub(crate) fn close_feeds(&self) {
+        self.closing.send_replace(true);
+    }
+    
+    /// Whether the initial mempool and block scans have completed and the
+    /// monitor is not shutting down.
+    pub async fn is_ready(&self) -> bool {
+        !self.is_closing() && self.sync.read().await.is_ready()
+    }
+    
+    /// Backend connectivity, scanner progress and feed state. Probes the
+    /// backend tip, so this waits on the network in live mode.
+    pub async fn health(&self) -> health::HealthReport {
+        let state = self.sync.read().await.clone();
+        let backend = if state.demo { None } else { Some(self.probe_backend().await) };
+        let broadcast = health::BroadcastStatus {
+            backlog: self.tx_broadcaster.len(),
+            capacity: self.channel_capacity,
+            subscribers: self.tx_broadcaster.receiver_count(),
+        };
+        
+        let protocols = self.parsers.iter().map(|p| p.protocol.to_string()).collect();
+        
+        health::HealthReport::new(state, backend, protocols, broadcast, self.feed_metrics.snapshot(), unix_time())
+    }
+    
+    async fn probe_backend(&self) -> health::BackendStatus {
+        let started = std::time::Instant::now();
+        let result = tokio::time::timeout(BACKEND_PROBE_TIMEOUT, self.source.tip_height()).await;
+        let latency_ms = Some(started.elapsed().as_millis() as u64);
+        
+        let (tip_height, error) = match result {
+            Ok(Ok(height)) => (Some(height), None),
+            Ok(Err(e)) => (None, Some(e.to_string())),
+            Err(_) => (None, Some(format!("no response within {}s", BACKEND_PROBE_TIMEOUT.as_secs()))),
+        };
+        
+        health::BackendStatus {
+            kind: self.source.kind().to_string(),
+            url: self.source.url().to_string(),
+            reachable: error.is_none(),
+            tip_height,
+            latency_ms,
+            error,
+        }
+    }
+    
+    /// Events still queued in the broadcast channel for its slowest subscriber.
+    pub fn broadcast_backlog(&self) -> usize {
+        self.tx_broadcaster.len()
+    }
+    
+    /// Sequence number of the most recently broadcast transaction.
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq.load(Ordering::SeqCst)
+    }
+    
+    /// Follow the source's mempool and block streams until `shutdown`
+    /// turns true. An event being processed is finished first.
+    pub(crate) fn spawn_tasks(self: Arc<Self>, shutdown: watch::Receiver<bool>) -> Vec<JoinHandle<()>> {
+        if self.demo {
+            tracing::info!("Demo mode active, generating simulated transactions");
+        }
+        vec![
+            tokio::spawn(self.clone().follow_mempool(shutdown.clone())),
+            tokio::spawn(self.follow_blocks(shutdown)),
+        ]
+    }
+    
+    async fn follow_mempool(self: Arc<Self>, mut shutdown: watch::Receiver<bool>) {
+        let mut events = self.source.clone().mempool_additions(self.scan.clone());
+        
+        while let Some(event) = next_or_shutdown(&mut events, &mut shutdown).await {
+            match event {
+                Ok(source::MempoolEvent::Added(tx)) => {
+                    metrics::metrics().txs_scanned.with_label_values(&["mempool"]).inc();
+                    self.process_transaction(tx).await;
                 }
-            });
-            
-            let block_monitor = self.clone();
-            tokio::spawn(async move {
-                loop {
-                    if let Err(e) = block_monitor.scan_recent_blocks().await {
-                        eprintln!("Block scan error: {}", e);
+                Ok(source::MempoolEvent::Removed(txid)) => {
+                    metrics::metrics().mempool_removed.inc();
+                    if let Some(tx) = self.history.write().await.get_mut(&txid).filter(|tx| tx.block_height.is_none()) {
+                        tracing::info!(%txid, "Metaprotocol transaction left the mempool");
+                        tx.removed = true;
                     }
-                    tokio::time::sleep(tokio::time::Duration::from_secs(300)).await;
                 }
-            });
+                Ok(source::MempoolEvent::Synced) => self.sync.write().await.mempool.record(&Ok(()), unix_time()),
+                Err(e) => {
+                    tracing::warn!(error = %e, "Mempool scan failed");
+                    self.sync.write().await.mempool.record(&Err(e), unix_time());
+                }
+            }
         }
     }
     
-    async fn generate_demo_transaction(&self, seed: u64) {
-        use std::collections::hash_map::DefaultHasher;
-        use std::hash::{Hash, Hasher};
-        
-        let mut hasher = DefaultHasher::new();
-        seed.hash(&mut hasher);
-        let hash = hasher.finish();
-        
-        let protocols = ["brc20", "runes", "stamps"];
-        let protocol = protocols[(hash % 3) as usize];
-        
-        let activity = match protocol {
-            "brc20" => {
-                let ops = ["deploy", "mint", "transfer"];
-                let op = ops[(hash / 3 % 3) as usize];
-                let tickers = ["ORDI", "SATS", "MEME", "PEPE", "WZRD", "BITS"];
-                let tick = tickers[(hash / 9 % 6) as usize];
-                
-                let mut data = HashMap::new();
-                data.insert("tick".to_string(), serde_json::Value::String(tick.to_string()));
-                data.insert("operation".to_string(), serde_json::Value::String(op.to_string()));
-                
-                if op != "deploy" {
-                    let amount = 1000 + (hash % 9000);
-                    data.insert("amount".to_string(), serde_json::Value::Number(amount.into()));
+    async fn follow_blocks(self: Arc<Self>, mut shutdown: watch::Receiver<bool>) {
+        let after = self.sync.read().await.synced_height;
+        let mut events = self.source.clone().block_events(self.scan.clone(), after);
+        
+        while let Some(event) = next_or_shutdown(&mut events, &mut shutdown).await {
+            match event {
+                Ok(source::BlockEvent::Connected(block)) => {
+                    tracing::debug!(height = block.height, txs = block.transactions.len(), "Block connected");
+                    let mut summary = BlockSummary {
+                        height: block.height,
+                        hash: block.hash,
+                        tx_count: block.transactions.len(),
+                        metaprotocol_txs: 0,
+                        protocols: BTreeMap::new(),
+                        metaprotocol_fees: 0,
+                        timestamp: unix_time(),
+                    };
+                    for (index, mut tx) in block.transactions.into_iter().enumerate() {
+                        metrics::metrics().txs_scanned.with_label_values(&["block"]).inc();
+                        tx.status.block_index.get_or_insert(index as u32);
+                        let fee = tx.fee;
+                        if let Some(protocols) = self.process_transaction(tx).await {
+                            summary.metaprotocol_txs += 1;
+                            summary.metaprotocol_fees += fee.unwrap_or(0);
+                            for protocol in protocols {
+                                *summary.protocols.entry(protocol).or_default() += 1;
+                            }
+                        }
+                    }
+                    
+                    tracing::info!(
+                        height = summary.height,
+                        txs = summary.tx_count,
+                        metaprotocol_txs = summary.metaprotocol_txs,
+                        fees = summary.metaprotocol_fees,
+                        "Block processed",
+                    );
+                    self.sync.write().await.synced_height = Some(summary.height);
+                    let _ = self.block_broadcaster.send(summary);
+                    
+                    // Checkpoint, so a restart resumes after this block
+                    if let Err(e) = self.save().await {
+                        tracing::warn!(error = %e, "Failed to save state");
+                    }
                 }
-                
-                let description = match op {
-                    "deploy" => format!("New BRC-20 token '{}' deployed", tick),
-                    "mint" => format!("Minted {} {} tokens", 1000 + (hash % 9000), tick),
-                    "transfer" => format!("Transfer {} {} tokens", 100 + (hash % 900), tick),
-                    _ => "Activity".to_string(),
-                };
-                
-                Activity {
-                    protocol: "brc20".to_string(),
-                    operation: op.to_string(),
-                    output: 0,
-                    data,
-                    changes: vec![],
-                    description,
-                    value_usd: None,
-                    importance: match op { "deploy" => 8, "mint" => 5, _ => 3 },
+                Ok(source::BlockEvent::Disconnected { height, hash }) => {
+                    let mut reorged = 0;
+                    for tx in self.history.write().await.iter_mut().filter(|tx| tx.block_height == Some(height)) {
+                        tx.block_height = None;
+                        reorged += 1;
+                    }
+                    tracing::info!(height, %hash, metaprotocol_txs = reorged, "Block disconnected");
+                    let mut sync = self.sync.write().await;
+                    if sync.synced_height >= Some(height) {
+                        sync.synced_height = height.checked_sub(1);
+                    }
                 }
-            },
-            "runes" => {
-                let rune_names = ["SATOSHI.NAKAMOTO", "BITCOIN.PIZZA", "GENESIS.BLOCK"];
-                let rune = rune_names[(hash / 3 % 3) as usize];
-                
-                let mut data = HashMap::new();
-                data.insert("rune".to_string(), serde_json::Value::String(rune.to_string()));
-                data.insert("amount".to_string(), serde_json::Value::Number((10000 + hash % 90000).into()));
-                
-                Activity {
-                    protocol: "runes".to_string(),
-                    operation: "transfer".to_string(),
-                    output: 0,
-                    data,
-                    changes: vec![],
-                    description: format!("{} Runes transferred", rune),
-                    value_usd: None,
-                    importance: 7,
+                Ok(source::BlockEvent::Synced) => self.sync.write().await.blocks.record(&Ok(()), unix_time()),
+                Err(e) => {
+                    tracing::warn!(error = %e, "Block scan failed");
+                    self.sync.write().await.blocks.record(&Err(e), unix_time());
                 }
-            },
-            _ => Activity {
-                protocol: "stamps".to_string(),
-                operation: "mint".to_string(),
-                output: 0,
-                data: HashMap::new(),
-                changes: vec![],
-                description: format!("STAMP #{} minted", 1000 + (hash % 9000)),
-                value_usd: None,
-                importance: 6,
-            },
+            }
+        }
+    }
+    
+    /// Write stats, history, sync progress and the watchlist to storage.
+    pub async fn save(&self) -> anyhow::Result<()> {
+        // One lock at a time, as `publish` holds the history lock while
+        // updating stats
+        let stats = self.stats.read().await.clone();
+        let history = self.history.read().await.iter().cloned().collect();
+        let synced_height = self.sync.read().await.synced_height;
+        let watchlist = self.watchlist.read().await.clone();
+        let snapshot = storage::Snapshot {
+            stats,
+            history,
+            last_seq: self.last_seq(),
+            synced_height,
+            watchlist,
         };
+        self.storage.save(&snapshot)
+    }
+    
+    /// Parse and publish a transaction, returning the protocols it has
+    /// activity for, if any.
+    #[tracing::instrument(skip_all, fields(txid = %tx.txid))]
+    async fn process_transaction(&self, tx: Transaction) -> Option<Vec<String>> {
+        let (protocols, all_activities) = parsers::parse_with(&self.parsers, &tx);
+        
+        if all_activities.is_empty() {
+            return None;
+        }
         
-        let txid = format!("{:016x}{:016x}{:016x}{:016x}", hash, hash.rotate_left(16), hash.rotate_left(32), hash.rotate_left(48));
+        tracing::info!(?protocols, activities = all_activities.len(), "Found metaprotocol activity");
+        
+        let total_value: u64 = tx.vout.iter().map(|o| o.value).sum();
+        let fee_rate = tx.fee.unwrap_or(0) as f64 / tx.vsize() as f64;
+        
+        let mut addresses: Vec<String> = tx.vin.iter()
+            .filter_map(|i| i.prevout.as_ref()?.scriptpubkey_address.clone())
+            .chain(tx.vout.iter().filter_map(|o| o.scriptpubkey_address.clone()))
+            .collect();
+        addresses.sort();
+        addresses.dedup();
         
-        let live_tx = LiveTransaction {
-            txid,
+        let mut live_tx = LiveTransaction {
+            seq: 0,
+            txid: tx.txid.clone(),
             timestamp: std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap()
                 .as_secs(),
-            protocols: vec![protocol.to_string()],
-            total_value: 10000 + (hash % 990000),
-            activities: vec![activity],
-            fee_rate: 10.0 + (hash % 140) as f64,
-            size: 200 + (hash % 800) as u32,
+            protocols,
+            total_value,
+            total_value_usd: None,
+            activities: all_activities,
+            fee_rate,
+            size: tx.size,
+            block_height: tx.status.block_height,
+            removed: false,
+            addresses,
+            watched: Vec::new(),
         };
+        if !self.history.read().await.contains(&live_tx.txid) {
+            let priced_at = tx.status.block_time.unwrap_or(live_tx.timestamp);
+            self.apply_prices(&mut live_tx, priced_at).await;
+        }
         
-        self.update_stats(&live_tx).await;
-        let _ = self.tx_broadcaster.send(live_tx);
+        let protocols = live_tx.protocols.clone();
+        self.publish(live_tx).await;
+        Some(protocols)
     }
     
-    async fn scan_mempool(&self) -> anyhow::Result<()> {
-        let txids = self.client.get_mempool_txs().await?;
-        println!("Scanning {} mempool transactions...", txids.len());
+    /// Publish a transaction once: when one already published from the
+    /// mempool confirms, its record is updated instead.
+    async fn publish(&self, mut live_tx: LiveTransaction) {
+        self.apply_watchlist(&mut live_tx).await;
         
-        for txid in txids {
-            if let Ok(tx) = self.client.get_transaction(&txid).await {
-                self.process_transaction(tx).await;
+        let mut history = self.history.write().await;
+        if let Some(record) = history.get_mut(&live_tx.txid) {
+            if live_tx.block_height.is_some() {
+                confirm(record, &live_tx, &mut *self.tokens.write().await);
             }
-            tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+            return;
         }
         
-        Ok(())
-    }
-    
-    async fn scan_recent_blocks(&self) -> anyhow::Result<()> {
-        let txids = self.client.get_recent_blocks().await?;
-        println!("Scanning {} block transactions...", txids.len());
+        live_tx.seq = self.last_seq.fetch_add(1, Ordering::SeqCst) + 1;
+        history.push(live_tx.clone());
         
-        for txid in txids {
-            if let Ok(tx) = self.client.get_transaction(&txid).await {
-                self.process_transaction(tx).await;
-            }
-            tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+        self.update_stats(&live_tx).await;
+        self.tokens.write().await.apply(&live_tx);
+        for protocol in &live_tx.protocols {
+            metrics::metrics().txs_matched.with_label_values(&[protocol]).inc();
         }
-        
-        Ok(())
+        let _ = self.tx_broadcaster.send(live_tx);
     }
     
-    async fn process_transaction(&self, tx: Transaction) {
-        let mut all_activities = Vec::new();
-        let mut protocols = Vec::new();
-        
-        let brc20 = parsers::parse_brc20(&tx);
-        if !brc20.is_empty() {
-            protocols.push("brc20".to_string());
-            all_activities.extend(brc20);
-        }
+    /// Fill in USD values at the prices of `at`, so blocks scanned late
+    /// are valued at the prices of when they were mined.
+    async fn apply_prices(&self, tx: &mut LiveTransaction, at: u64) {
+        let Some(prices) = &self.prices else { return };
+        let btc_usd = match prices.btc_usd(at).await {
+            Ok(price) => price,
+            Err(e) => {
+                tracing::warn!(error = %e, source = prices.kind(), "Price lookup failed");
+                return;
+            }
+        };
         
-        let stamps = parsers::parse_stamps(&tx);
-        if !stamps.is_empty() {
-            protocols.push("stamps".to_string());
-            all_activities.extend(stamps);
+        tx.total_value_usd = Some(price::sats_to_usd(tx.total_value as f64, btc_usd));
+        let tokens = self.tokens.read().await;
+        for activity in &mut tx.activities {
+            activity.value_usd = price::activity_sats(activity, &tokens, |token| prices.floor_sats(token, at))
+                .map(|sats| price::sats_to_usd(sats, btc_usd));
         }
-        
-        let runes = parsers::parse_runes(&tx);
-        if !runes.is_empty() {
-            protocols.push("runes".to_string());
-            all_activities.extend(runes);
+    }
+    
+    /// Flag watched addresses on the transaction and boost its importance.
+    async fn apply_watchlist(&self, tx: &mut LiveTransaction) {
+        let watchlist = self.watchlist.read().await;
+        if watchlist.is_empty() {
+            return;
         }
         
-        if !all_activities.is_empty() {
-            println!("Found {} protocol(s) in tx {}: {:?}", 
-                protocols.len(), &tx.txid[..8], protocols);
-            
-            let total_value: u64 = tx.vout.iter().map(|o| o.value).sum();
-            let fee_rate = tx.fee.unwrap_or(0) as f64 / tx.size as f64;
-            
-            let live_tx = LiveTransaction {
-                txid: tx.txid.clone(),
-                timestamp: std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs(),
-                protocols,
-                total_value,
-                activities: all_activities,
-                fee_rate,
-                size: tx.size,
-            };
-            
-            self.update_stats(&live_tx).await;
-            let _ = self.tx_broadcaster.send(live_tx);
+        tx.watched = tx.addresses.iter().filter(|a| watchlist.contains_key(*a)).cloned().collect();
+        if !tx.watched.is_empty() {
+            for activity in &mut tx.activities {
+                activity.importance = activity.importance.saturating_add(WATCHLIST_BOOST).min(MAX_IMPORTANCE);
+            }
         }
     }
     
@@ -566,42 +1249,221 @@ impl MetaprotocolMonitor {
     }
     
     pub async fn get_stats(&self) -> HashMap<String, ProtocolStats> {
-        self.stats.read().await.clone()
+        let mut stats = self.stats.read().await.clone();
+        let tokens = self.tokens.read().await;
+        
+        if let Some(stat) = stats.get_mut("brc20") {
+            stat.active_tokens = tokens.brc20_count() as u32;
+        }
+        if let Some(stat) = stats.get_mut("runes") {
+            stat.active_tokens = tokens.rune_count() as u32;
+        }
+        
+        stats
+    }
+    
+    pub async fn watch(&self, address: &str, label: Option<String>) -> WatchEntry {
+        let entry = WatchEntry {
+            address: address.to_string(),
+            label,
+            added_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        };
+        self.watchlist.write().await.insert(address.to_string(), entry.clone());
+        entry
+    }
+    
+    /// Stop watching an address, returning whether it was on the watchlist.
+    pub async fn unwatch(&self, address: &str) -> bool {
+        self.watchlist.write().await.remove(address).is_some()
+    }
+    
+    pub async fn watchlist(&self) -> HashMap<String, WatchEntry> {
+        self.watchlist.read().await.clone()
+    }
+    
+    /// Deploy parameters, supply and recent activity for a BRC-20 ticker.
+    pub async fn brc20_detail(&self, tick: &str) -> Option<serde_json::Value> {
+        self.tokens.read().await.brc20(tick).map(|t| t.detail())
+    }
+    
+    /// Etching parameters, supply and recent activity for a rune, by name or id.
+    pub async fn rune_detail(&self, name_or_id: &str) -> Option<serde_json::Value> {
+        self.tokens.read().await.rune(name_or_id).map(|r| r.detail())
+    }
+    
+    pub async fn brc20_token(&self, tick: &str) -> Option<tokens::Brc20Token> {
+        self.tokens.read().await.brc20(tick).cloned()
+    }
+    
+    pub async fn rune_token(&self, name_or_id: &str) -> Option<tokens::RuneToken> {
+        self.tokens.read().await.rune(name_or_id).cloned()
+    }
+    
+    /// Check where the inscriptions and runes carried by a transaction's
+    /// inputs will end up, flagging anything that would be burned or paid
+    /// as fee. Runes given by name must have been seen etched.
+    pub async fn lint(&self, request: &lint::LintRequest) -> anyhow::Result<lint::LintReport> {
+        let tx = load_transaction(self.source(), &request.transaction).await?;
+        Ok(lint::lint(&tx, &request.assets, &*self.tokens.read().await))
+    }
+    
+    /// A retained transaction by txid.
+    pub async fn transaction(&self, txid: &str) -> Option<LiveTransaction> {
+        self.history.read().await.get(txid).cloned()
+    }
+    
+    /// Query retained transactions, newest first unless the query says otherwise.
+    pub async fn query_transactions(&self, query: &history::HistoryQuery) -> anyhow::Result<history::Page<LiveTransaction>> {
+        let query = self.resolve_ticker(query).await;
+        let history = self.history.read().await;
+        history::query_transactions(history.iter(), &query)
+    }
+    
+    /// Query individual activities across retained transactions.
+    pub async fn query_activities(&self, query: &history::HistoryQuery) -> anyhow::Result<history::Page<history::ActivityRecord>> {
+        let query = self.resolve_ticker(query).await;
+        let history = self.history.read().await;
+        history::query_activities(history.iter(), &query)
+    }
+    
+    /// Look up the id of a rune named by `ticker`, as mints and edicts
+    /// only carry the id.
+    async fn resolve_ticker(&self, query: &history::HistoryQuery) -> history::HistoryQuery {
+        let mut query = query.clone();
+        if let Some(ticker) = &query.ticker {
+            query.ticker_id = self.tokens.read().await.rune(ticker).and_then(|rune| rune.rune_id.clone());
+        }
+        query
+    }
+}
+
+/// Note the confirmation of a transaction published from the mempool: its
+/// height, and the ids of the runes it etched.
+fn confirm(record: &mut LiveTransaction, confirmed: &LiveTransaction, tokens: &mut tokens::TokenRegistry) {
+    record.block_height = confirmed.block_height;
+    record.removed = false;
+    for (activity, etched) in record.activities.iter_mut().zip(&confirmed.activities) {
+        if activity.protocol != "runes" || activity.operation != "etch" {
+            continue;
+        }
+        let Some(id) = etched.data.get("rune_id").and_then(|id| id.as_str()) else { continue };
+        activity.data.insert("rune_id".to_string(), id.into());
+        if let Some(name) = activity.data.get("rune").and_then(|name| name.as_str()) {
+            tokens.confirm_etching(name, id);
+        }
     }
 }
 
 // Analysis functions
-pub async fn analyze_transaction(txid: &str) -> anyhow::Result<serde_json::Value> {
-    let client = BitcoinClient::new();
+
+/// Body of `POST /api/analyze`: exactly one of a txid to fetch, a raw
+/// transaction in hex, or a PSBT in base64 or hex.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct AnalyzeRequest {
+    pub txid: Option<String>,
+    pub hex: Option<String>,
+    pub psbt: Option<String>,
+}
+
+/// Largest number of txids accepted by one batch request.
+pub const MAX_BATCH_SIZE: usize = 100;
+/// Default number of transactions fetched at once during a batch.
+pub const BATCH_CONCURRENCY: usize = 4;
+
+pub async fn analyze_transaction(client: &dyn source::TransactionSource, txid: &str) -> anyhow::Result<serde_json::Value> {
     let tx = client.get_transaction(txid).await?;
     
-    let mut activities = Vec::new();
-    let mut protocols = Vec::new();
+    Ok(analyze(&tx))
+}
+
+/// Analyze many txids through one source, at most `concurrency` at a time.
+/// Results come back in the order the txids were given, each either the
+/// analysis or a typed error.
+pub async fn analyze_batch(client: &dyn source::TransactionSource, txids: &[String], concurrency: usize) -> serde_json::Value {
+    use futures_util::StreamExt;
     
-    let brc20 = parsers::parse_brc20(&tx);
-    if !brc20.is_empty() {
-        protocols.push("brc20");
-        activities.extend(brc20);
-    }
+    let results: Vec<serde_json::Value> = futures_util::stream::iter(txids.to_vec())
+        .map(|txid| {
+            async move {
+                match client.get_transaction(txid.trim()).await {
+                    Ok(tx) => serde_json::json!({
+                        "txid": txid,
+                        "ok": true,
+                        "result": analyze(&tx),
+                    }),
+                    Err(e) => serde_json::json!({
+                        "txid": txid,
+                        "ok": false,
+                        "error": {
+                            "code": SourceError::classify(&e).map(|s| s.code()).unwrap_or("internal"),
+                            "message": e.to_string(),
+                        },
+                    }),
+                }
+            }
+        })
+        .buffered(concurrency.max(1))
+        .collect()
+        .await;
     
-    let stamps = parsers::parse_stamps(&tx);
-    if !stamps.is_empty() {
-        protocols.push("stamps");
-        activities.extend(stamps);
-    }
+    let succeeded = results.iter().filter(|r| r["ok"] == true).count();
     
-    let runes = parsers::parse_runes(&tx);
-    if !runes.is_empty() {
-        protocols.push("runes");
-        activities.extend(runes);
+    serde_json::json!({
+        "succeeded": succeeded,
+        "failed": results.len() - succeeded,
+        "results": results,
+    })
+}
+
+/// Analyze a fetched, raw or partially signed transaction. Raw and PSBT
+/// inputs may not be broadcast yet, so nothing is fetched for them except
+/// missing prevouts, which are needed for the fee.
+pub async fn analyze_request(client: &dyn source::TransactionSource, request: &AnalyzeRequest) -> anyhow::Result<serde_json::Value> {
+    if let (Some(txid), None, None) = (&request.txid, &request.hex, &request.psbt) {
+        return analyze_transaction(client, txid).await;
     }
     
+    let tx = load_transaction(client, request).await?;
+    let mut result = analyze(&tx);
+    result["broadcast"] = serde_json::Value::Bool(false);
+    Ok(result)
+}
+
+
+/// Fetch or decode the transaction an analyze request refers to, with
+/// prevouts resolved as far as possible.
+async fn load_transaction(client: &dyn source::TransactionSource, request: &AnalyzeRequest) -> anyhow::Result<Transaction> {
+    let mut tx = match (&request.txid, &request.hex, &request.psbt) {
+        (Some(txid), None, None) => client.get_transaction(txid).await?,
+        (None, Some(raw), None) => decode::decode_transaction_hex(raw, client.network())?,
+        (None, None, Some(psbt)) => decode::decode_psbt(psbt, client.network())?,
+        _ => anyhow::bail!("Provide exactly one of txid, hex or psbt"),
+    };
+    
+    client.resolve_prevouts(&mut tx).await;
+    Ok(tx)
+}
+
+/// Run every parser over a transaction and summarize what it does.
+pub fn analyze(tx: &Transaction) -> serde_json::Value {
+    let (protocols, activities) = parsers::parse_all(tx);
+    
     let total_value: u64 = tx.vout.iter().map(|o| o.value).sum();
-    let fee_rate = tx.fee.unwrap_or(0) as f64 / tx.size as f64;
+    let vsize = tx.vsize();
+    let fee_rate = tx.fee.unwrap_or(0) as f64 / vsize as f64;
+    
+    let warnings: Vec<String> = activities.iter()
+        .filter(|a| a.operation == "cenotaph")
+        .map(|a| format!("{}; any runes held by the inputs will be burned", a.description))
+        .collect();
     
-    Ok(serde_json::json!({
-        "txid": txid,
+    serde_json::json!({
+        "txid": tx.txid,
         "size": tx.size,
+        "vsize": vsize,
         "fee": tx.fee,
         "fee_rate_sat_vb": fee_rate,
         "total_value_sats": total_value,
@@ -611,8 +1473,145 @@ pub async fn analyze_transaction(txid: &str) -> anyhow::Result<serde_json::Value
         "activity_count": activities.len(),
         "is_metaprotocol": !activities.is_empty(),
         "importance_score": activities.iter().map(|a| a.importance).max().unwrap_or(0),
+        "warnings": warnings,
         "timestamp": tx.status.block_time,
         "confirmed": tx.status.confirmed,
         "block_height": tx.status.block_height,
-    }))
-}
\ No newline at end of file
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use source::replay::Fixture;
+    use source::{Block, ReplaySource};
+    
+    fn monitor(fixture: Fixture) -> MetaprotocolMonitor {
+        builder::MonitorBuilder::new()
+            .source(ReplaySource::new(fixture))
+            .into_monitor()
+            .unwrap()
+            .0
+    }
+    
+    fn etching() -> runestone::Runestone {
+        runestone::Runestone {
+            etching: Some(runestone::Etching {
+                rune: Some("UNCOMMON•GOODS".to_string()),
+                divisibility: Some(2),
+                terms: Some(runestone::Terms { cap: Some(100), amount: Some(500), ..Default::default() }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+    
+    /// A transaction carrying `runestone`, confirmed at `(height, index)`.
+    fn rune_tx(seed: u8, runestone: &runestone::Runestone, confirmed: Option<(u32, u32)>) -> Transaction {
+        serde_json::from_value(serde_json::json!({
+            "txid": format!("{:02x}", seed).repeat(32),
+            "size": 250,
+            "weight": 610,
+            "fee": 1000,
+            "status": {
+                "confirmed": confirmed.is_some(),
+                "block_height": confirmed.map(|(height, _)| height),
+                "block_time": confirmed.map(|_| 1_700_000_000),
+                "block_index": confirmed.map(|(_, index)| index),
+            },
+            "vin": [],
+            "vout": [
+                { "scriptpubkey": runestone::encipher(runestone), "scriptpubkey_address": null, "value": 0 },
+                { "scriptpubkey": format!("0014{}", "22".repeat(20)), "scriptpubkey_address": "bc1qrecipient", "value": 546 },
+            ],
+        }))
+        .unwrap()
+    }
+    
+    #[tokio::test]
+    async fn mempool_transaction_is_published_once_when_it_confirms() {
+        let monitor = monitor(Fixture::default());
+        let mut feed = monitor.tx_broadcaster.subscribe();
+        let txid = rune_tx(1, &etching(), None).txid;
+        
+        monitor.process_transaction(rune_tx(1, &etching(), None)).await.unwrap();
+        let protocols = monitor.process_transaction(rune_tx(1, &etching(), Some((840000, 7)))).await;
+        assert_eq!(protocols, Some(vec!["runes".to_string()]));
+        
+        assert_eq!(monitor.last_seq(), 1);
+        assert_eq!(monitor.get_stats().await["runes"].total_txs, 1);
+        assert_eq!(feed.recv().await.unwrap().seq, 1);
+        assert!(feed.try_recv().is_err());
+        
+        let record = monitor.transaction(&txid).await.unwrap();
+        assert_eq!(record.block_height, Some(840000));
+        assert_eq!(record.activities[0].data["rune_id"], "840000:7");
+        let rune = monitor.rune_token("UNCOMMONGOODS").await.unwrap();
+        assert_eq!(rune.rune_id.as_deref(), Some("840000:7"));
+    }
+    
+    #[tokio::test]
+    async fn fee_rate_is_per_vbyte() {
+        let monitor = monitor(Fixture::default());
+        let segwit = rune_tx(1, &etching(), None);
+        let mut legacy = rune_tx(2, &etching(), None);
+        legacy.weight = None;
+        
+        monitor.process_transaction(segwit.clone()).await.unwrap();
+        monitor.process_transaction(legacy.clone()).await.unwrap();
+        
+        assert_eq!(monitor.transaction(&segwit.txid).await.unwrap().fee_rate, 1000.0 / 153.0);
+        assert_eq!(monitor.transaction(&legacy.txid).await.unwrap().fee_rate, 4.0);
+    }
+    
+    #[tokio::test]
+    async fn disconnected_block_unconfirms_its_transactions() {
+        let tx = rune_tx(1, &etching(), Some((840000, 1)));
+        let txid = tx.txid.clone();
+        let fixture = Fixture {
+            mempool: Vec::new(),
+            blocks: vec![
+                Block { height: 840000, hash: "aa".repeat(32), transactions: vec![tx] },
+                Block { height: 840000, hash: "bb".repeat(32), transactions: Vec::new() },
+            ],
+        };
+        let monitor = Arc::new(monitor(fixture));
+        let mut blocks = monitor.subscribe_blocks();
+        let (_stop, shutdown) = watch::channel(false);
+        tokio::spawn(monitor.clone().follow_blocks(shutdown));
+        
+        assert_eq!(blocks.recv().await.unwrap().metaprotocol_txs, 1);
+        assert_eq!(monitor.transaction(&txid).await.unwrap().block_height, Some(840000));
+        blocks.recv().await.unwrap();
+        assert_eq!(monitor.transaction(&txid).await.unwrap().block_height, None);
+    }
+    
+    #[tokio::test]
+    async fn rune_mints_and_transfers_are_priced_at_the_floor() {
+        let floors = HashMap::from([("runes:UNCOMMON•GOODS".to_string(), 100.0)]);
+        let monitor = builder::MonitorBuilder::new()
+            .source(ReplaySource::new(Fixture::default()))
+            .price_source(price::StaticPrice::new(60_000.0).with_floors(floors))
+            .into_monitor()
+            .unwrap()
+            .0;
+        let id = runestone::RuneId { block: 840000, tx: 7 };
+        let mint = runestone::Runestone { mint: Some(id), ..Default::default() };
+        let transfer = runestone::Runestone {
+            edicts: vec![runestone::Edict { id, amount: 250, output: 1 }],
+            ..Default::default()
+        };
+        
+        monitor.process_transaction(rune_tx(1, &etching(), None)).await.unwrap();
+        monitor.process_transaction(rune_tx(1, &etching(), Some((840000, 7)))).await.unwrap();
+        monitor.process_transaction(rune_tx(2, &mint, None)).await.unwrap();
+        monitor.process_transaction(rune_tx(3, &transfer, None)).await.unwrap();
+        
+        // 500 units at divisibility 2 are 5 runes, at 100 sats each
+        let minted = monitor.transaction(&rune_tx(2, &mint, None).txid).await.unwrap();
+        assert_eq!(minted.activities[0].value_usd, Some(price::sats_to_usd(500.0, 60_000.0)));
+        let moved = monitor.transaction(&rune_tx(3, &transfer, None).txid).await.unwrap();
+        assert_eq!(moved.activities[0].value_usd, Some(price::sats_to_usd(250.0, 60_000.0)));
+        assert_eq!(moved.total_value_usd, Some(price::sats_to_usd(546.0, 60_000.0)));
+    }
+}
@@ -0,0 +1,548 @@
+// Pre-broadcast safety checks for inscription and rune transfers
+use crate::runestone::{self, Artifact, RuneId};
+use crate::tokens::TokenRegistry;
+use crate::{AnalyzeRequest, Transaction};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Ordinals envelope opening: OP_FALSE OP_IF "ord"
+const ENVELOPE_MARKER: &str = "0063036f7264";
+
+/// An inscription the caller knows sits on an input, at `offset` sats into it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct InscriptionHint {
+    pub id: Option<String>,
+    #[serde(default)]
+    pub offset: u64,
+}
+
+/// A rune balance the caller knows an input holds. `rune` is an id
+/// (`block:tx`) or name; `amount` is in base units, as a number or string.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct RuneBalance {
+    pub rune: String,
+    pub amount: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct InputAssets {
+    pub input: usize,
+    #[serde(default)]
+    pub inscriptions: Vec<InscriptionHint>,
+    #[serde(default)]
+    pub runes: Vec<RuneBalance>,
+}
+
+/// Body of `POST /api/lint`: the transaction, as for `/api/analyze`, plus
+/// what the caller knows its inputs carry.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct LintRequest {
+    #[serde(flatten)]
+    pub transaction: AnalyzeRequest,
+    #[serde(default)]
+    pub assets: Vec<InputAssets>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct LintWarning {
+    pub severity: Severity,
+    pub code: String,
+    pub message: String,
+    pub input: Option<usize>,
+    pub output: Option<usize>,
+}
+
+/// Where a sat or rune balance ends up.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Destination {
+    Output { output: usize, offset: u64, address: Option<String> },
+    Fee,
+    Burned { output: Option<usize> },
+    Unknown,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct InscriptionFlow {
+    pub input: usize,
+    pub id: Option<String>,
+    /// "hint" for inscriptions the caller declared, "reveal" for ones this
+    /// transaction creates.
+    pub source: String,
+    pub destination: Destination,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct RuneFlow {
+    pub rune: String,
+    pub amount: String,
+    pub destination: Destination,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct LintReport {
+    pub txid: String,
+    /// False if any check produced an error-level warning.
+    pub ok: bool,
+    pub warnings: Vec<LintWarning>,
+    pub inscriptions: Vec<InscriptionFlow>,
+    pub runes: Vec<RuneFlow>,
+}
+
+impl LintReport {
+    fn warn(&mut self, severity: Severity, code: &str, message: String, input: Option<usize>, output: Option<usize>) {
+        self.warnings.push(LintWarning {
+            severity,
+            code: code.to_string(),
+            message,
+            input,
+            output,
+        });
+    }
+}
+
+fn is_op_return(tx: &Transaction, output: usize) -> bool {
+    tx.vout.get(output).is_some_and(|o| o.scriptpubkey.starts_with("6a"))
+}
+
+fn parse_amount(value: &serde_json::Value) -> Option<u128> {
+    match value {
+        serde_json::Value::Number(n) => n.as_u64().map(u128::from),
+        serde_json::Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Check where every known inscription and rune balance in `tx` will land.
+/// Runes declared by name are looked up in `tokens`.
+pub fn lint(tx: &Transaction, assets: &[InputAssets], tokens: &TokenRegistry) -> LintReport {
+    let mut report = LintReport {
+        txid: tx.txid.clone(),
+        ok: true,
+        warnings: Vec::new(),
+        inscriptions: Vec::new(),
+        runes: Vec::new(),
+    };
+
+    for asset in assets {
+        if asset.input >= tx.vin.len() {
+            report.warn(
+                Severity::Error,
+                "unknown_input",
+                format!("Assets declared for input {} but the transaction has {} inputs", asset.input, tx.vin.len()),
+                Some(asset.input),
+                None,
+            );
+        }
+    }
+
+    check_inscriptions(tx, assets, &mut report);
+    check_runes(tx, assets, tokens, &mut report);
+
+    report.ok = !report.warnings.iter().any(|w| w.severity == Severity::Error);
+    report
+}
+
+/// Follow the sats: an inscription at `offset` in input `i` moves to the
+/// output covering the same absolute position, or to the fee past the end.
+fn check_inscriptions(tx: &Transaction, assets: &[InputAssets], report: &mut LintReport) {
+    let mut tracked: Vec<(usize, u64, Option<String>, &str)> = Vec::new();
+
+    for asset in assets.iter().filter(|a| a.input < tx.vin.len()) {
+        for hint in &asset.inscriptions {
+            tracked.push((asset.input, hint.offset, hint.id.clone(), "hint"));
+        }
+    }
+
+    // New inscriptions are made on the first sat of the revealing input
+    for (idx, input) in tx.vin.iter().enumerate() {
+        let reveals = input.witness.as_ref().is_some_and(|w| w.iter().any(|item| item.contains(ENVELOPE_MARKER)));
+        if reveals {
+            tracked.push((idx, 0, None, "reveal"));
+        }
+    }
+
+    for (input, offset, id, source) in tracked {
+        let name = id.clone().unwrap_or_else(|| format!("on input {}", input));
+        let destination = match sat_position(tx, input, offset) {
+            Some(position) => locate_sat(tx, position),
+            None => Destination::Unknown,
+        };
+
+        match &destination {
+            Destination::Fee => report.warn(
+                Severity::Error,
+                "inscription_to_fee",
+                format!("Inscription {} falls past the last output and will be paid to the miner as fee", name),
+                Some(input),
+                None,
+            ),
+            Destination::Burned { output } => report.warn(
+                Severity::Error,
+                "inscription_burned",
+                format!("Inscription {} lands in an OP_RETURN output and will be burned", name),
+                Some(input),
+                *output,
+            ),
+            Destination::Unknown => report.warn(
+                Severity::Warning,
+                "inscription_untraceable",
+                format!("Cannot trace inscription {}: values of earlier inputs are unknown", name),
+                Some(input),
+                None,
+            ),
+            Destination::Output { .. } => {}
+        }
+
+        report.inscriptions.push(InscriptionFlow {
+            input,
+            id,
+            source: source.to_string(),
+            destination,
+        });
+    }
+}
+
+/// Absolute sat position of `offset` within `input`, counting from the
+/// first sat of the first input.
+fn sat_position(tx: &Transaction, input: usize, offset: u64) -> Option<u64> {
+    let mut position = offset;
+    for earlier in &tx.vin[..input] {
+        position += earlier.prevout.as_ref()?.value;
+    }
+    if let Some(prevout) = &tx.vin[input].prevout {
+        if offset >= prevout.value {
+            return None;
+        }
+    }
+    Some(position)
+}
+
+fn locate_sat(tx: &Transaction, position: u64) -> Destination {
+    let mut start = 0;
+    for (idx, output) in tx.vout.iter().enumerate() {
+        if position < start + output.value {
+            if is_op_return(tx, idx) {
+                return Destination::Burned { output: Some(idx) };
+            }
+            return Destination::Output {
+                output: idx,
+                offset: position - start,
+                address: output.scriptpubkey_address.clone(),
+            };
+        }
+        start += output.value;
+    }
+    Destination::Fee
+}
+
+/// The `block:tx` id of a declared rune, which may be given by name.
+fn rune_id(rune: &str, tokens: &TokenRegistry) -> Option<String> {
+    match rune.parse::<RuneId>() {
+        Ok(id) => Some(id.to_string()),
+        Err(_) => tokens.rune(&runestone::normalize_name(rune))?.rune_id.clone(),
+    }
+}
+
+/// Apply the runestone (or its absence) to the declared input balances,
+/// following ord's allocation rules.
+fn check_runes(tx: &Transaction, assets: &[InputAssets], tokens: &TokenRegistry, report: &mut LintReport) {
+    let mut unallocated: BTreeMap<String, u128> = BTreeMap::new();
+
+    for asset in assets {
+        if asset.input >= tx.vin.len() {
+            if !asset.runes.is_empty() {
+                report.warn(
+                    Severity::Warning,
+                    "runes_ignored",
+                    format!("Rune balances declared for missing input {} are left out of the simulation", asset.input),
+                    Some(asset.input),
+                    None,
+                );
+            }
+            continue;
+        }
+        for balance in &asset.runes {
+            let Some(id) = rune_id(&balance.rune, tokens) else {
+                report.warn(
+                    Severity::Error,
+                    "unknown_rune",
+                    format!("Rune {} on input {} is neither a rune id nor the name of a rune seen etched", balance.rune, asset.input),
+                    Some(asset.input),
+                    None,
+                );
+                continue;
+            };
+            match parse_amount(&balance.amount) {
+                Some(amount) => *unallocated.entry(id).or_default() += amount,
+                None => report.warn(
+                    Severity::Error,
+                    "invalid_amount",
+                    format!("Rune balance for {} on input {} is not a whole number", balance.rune, asset.input),
+                    Some(asset.input),
+                    None,
+                ),
+            }
+        }
+    }
+
+    let artifact = runestone::decipher(tx);
+    let mut allocations: Vec<(String, u128, usize)> = Vec::new();
+
+    if let Some(Artifact::Cenotaph(cenotaph)) = &artifact {
+        report.warn(
+            Severity::Error,
+            "cenotaph",
+            format!("Runestone is a cenotaph ({}); every rune in the inputs will be burned", cenotaph.flaws.join(", ")),
+            None,
+            runestone::runestone_output(tx),
+        );
+        for (rune, amount) in unallocated {
+            report.runes.push(RuneFlow {
+                rune,
+                amount: amount.to_string(),
+                destination: Destination::Burned { output: None },
+            });
+        }
+        return;
+    }
+
+    let runestone = match artifact {
+        Some(Artifact::Runestone(runestone)) => Some(runestone),
+        _ => None,
+    };
+
+    if let Some(runestone) = &runestone {
+        // A premine is allocated like an input balance under id 0:0
+        if let Some(etching) = &runestone.etching {
+            if let Some(premine) = etching.premine.filter(|p| *p > 0) {
+                unallocated.insert(RuneId { block: 0, tx: 0 }.to_string(), premine);
+            }
+        }
+
+        let spendable: Vec<usize> = (0..tx.vout.len()).filter(|o| !is_op_return(tx, *o)).collect();
+
+        for edict in &runestone.edicts {
+            let key = edict.id.to_string();
+            let Some(balance) = unallocated.get_mut(&key) else {
+                report.warn(
+                    Severity::Warning,
+                    "edict_without_balance",
+                    format!("Edict moves rune {} but no input is declared to hold it", key),
+                    None,
+                    Some(edict.output as usize),
+                );
+                continue;
+            };
+
+            if edict.output as usize == tx.vout.len() {
+                // Split across every non-OP_RETURN output
+                if spendable.is_empty() {
+                    continue;
+                }
+                if edict.amount == 0 {
+                    let share = *balance / spendable.len() as u128;
+                    let remainder = *balance % spendable.len() as u128;
+                    for (i, output) in spendable.iter().enumerate() {
+                        let amount = share + if (i as u128) < remainder { 1 } else { 0 };
+                        allocations.push((key.clone(), amount, *output));
+                    }
+                    *balance = 0;
+                } else {
+                    for output in &spendable {
+                        let amount = edict.amount.min(*balance);
+                        if amount == 0 {
+                            break;
+                        }
+                        allocations.push((key.clone(), amount, *output));
+                        *balance -= amount;
+                    }
+                }
+            } else {
+                let amount = if edict.amount == 0 { *balance } else { edict.amount.min(*balance) };
+                allocations.push((key.clone(), amount, edict.output as usize));
+                *balance -= amount;
+            }
+        }
+    }
+
+    // Whatever is left goes to the pointer, or else the first non-OP_RETURN output
+    let default_output = runestone
+        .as_ref()
+        .and_then(|r| r.pointer)
+        .map(|p| p as usize)
+        .or_else(|| (0..tx.vout.len()).find(|o| !is_op_return(tx, *o)));
+
+    for (rune, amount) in unallocated.into_iter().filter(|(_, amount)| *amount > 0) {
+        match default_output {
+            Some(output) => allocations.push((rune, amount, output)),
+            None => {
+                report.warn(
+                    Severity::Error,
+                    "runes_burned",
+                    format!("No non-OP_RETURN output to receive {} of rune {}; it will be burned", amount, rune),
+                    None,
+                    None,
+                );
+                report.runes.push(RuneFlow {
+                    rune,
+                    amount: amount.to_string(),
+                    destination: Destination::Burned { output: None },
+                });
+            }
+        }
+    }
+
+    for (rune, amount, output) in allocations {
+        let destination = if is_op_return(tx, output) {
+            report.warn(
+                Severity::Error,
+                "runes_burned",
+                format!("{} of rune {} is sent to OP_RETURN output {} and will be burned", amount, rune, output),
+                None,
+                Some(output),
+            );
+            Destination::Burned { output: Some(output) }
+        } else {
+            Destination::Output {
+                output,
+                offset: 0,
+                address: tx.vout.get(output).and_then(|o| o.scriptpubkey_address.clone()),
+            }
+        };
+        report.runes.push(RuneFlow {
+            rune,
+            amount: amount.to_string(),
+            destination,
+        });
+    }
+
+    // Rune-bearing outputs that also pick up an inscription are easy to spend by mistake
+    for flow in &report.inscriptions {
+        if let Destination::Output { output, .. } = flow.destination {
+            let shares_output = report.runes.iter().any(|r| matches!(r.destination, Destination::Output { output: o, .. } if o == output));
+            if shares_output {
+                report.warnings.push(LintWarning {
+                    severity: Severity::Info,
+                    code: "mixed_assets".to_string(),
+                    message: format!("Output {} receives both an inscription and runes", output),
+                    input: Some(flow.input),
+                    output: Some(output),
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> TokenRegistry {
+        let mut tokens = TokenRegistry::new();
+        let etch = serde_json::json!({
+            "seq": 1,
+            "txid": "11".repeat(32),
+            "timestamp": 1_700_000_000,
+            "protocols": ["runes"],
+            "total_value": 546,
+            "activities": [{
+                "protocol": "runes",
+                "operation": "etch",
+                "output": 0,
+                "data": { "rune": "UNCOMMON•GOODS", "rune_id": "840000:7" },
+                "changes": [],
+                "description": "",
+                "value_usd": null,
+                "importance": 5,
+            }],
+            "fee_rate": 1.0,
+            "size": 200,
+            "block_height": 840000,
+            "addresses": [],
+            "watched": [],
+        });
+        tokens.apply(&serde_json::from_value(etch).unwrap());
+        tokens
+    }
+
+    /// Spends one 10,000 sat input into a runestone moving 100 of 840000:7
+    /// to output 2, and two 546 sat outputs.
+    fn transfer() -> Transaction {
+        let runestone = runestone::Runestone {
+            edicts: vec![runestone::Edict { id: RuneId { block: 840000, tx: 7 }, amount: 100, output: 2 }],
+            ..Default::default()
+        };
+        let output = |seed: &str| serde_json::json!({ "scriptpubkey": format!("0014{}", seed.repeat(20)), "scriptpubkey_address": null, "value": 546 });
+        serde_json::from_value(serde_json::json!({
+            "txid": "22".repeat(32),
+            "size": 200,
+            "fee": null,
+            "status": { "confirmed": false },
+            "vin": [{ "txid": "33".repeat(32), "vout": 0, "witness": null, "prevout": output("44") }],
+            "vout": [
+                { "scriptpubkey": runestone::encipher(&runestone), "scriptpubkey_address": null, "value": 0 },
+                output("55"),
+                output("66"),
+            ],
+        }))
+        .unwrap()
+    }
+
+    fn runes(input: usize, rune: &str) -> InputAssets {
+        InputAssets {
+            input,
+            runes: vec![RuneBalance { rune: rune.to_string(), amount: serde_json::json!("500") }],
+            ..Default::default()
+        }
+    }
+
+    fn flows(report: &LintReport) -> Vec<(String, String, Option<usize>)> {
+        report
+            .runes
+            .iter()
+            .map(|flow| {
+                let output = match flow.destination {
+                    Destination::Output { output, .. } => Some(output),
+                    _ => None,
+                };
+                (flow.rune.clone(), flow.amount.clone(), output)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rune_balances_declared_by_name_follow_the_edicts() {
+        let tokens = registry();
+        let expected = [("840000:7".to_string(), "100".to_string(), Some(2)), ("840000:7".to_string(), "400".to_string(), Some(1))];
+
+        for rune in ["840000:7", "UNCOMMON•GOODS", "uncommongoods"] {
+            let report = lint(&transfer(), &[runes(0, rune)], &tokens);
+            assert!(report.ok, "{}: {:?}", rune, report.warnings);
+            assert!(report.warnings.is_empty(), "{}: {:?}", rune, report.warnings);
+            assert_eq!(flows(&report), expected);
+        }
+    }
+
+    #[test]
+    fn unknown_runes_and_inputs_are_reported() {
+        let report = lint(&transfer(), &[runes(0, "NOT•ETCHED")], &registry());
+        assert!(!report.ok);
+        let codes: Vec<&str> = report.warnings.iter().map(|w| w.code.as_str()).collect();
+        assert_eq!(codes, ["unknown_rune", "edict_without_balance"]);
+
+        let report = lint(&transfer(), &[runes(3, "840000:7")], &registry());
+        let codes: Vec<&str> = report.warnings.iter().map(|w| w.code.as_str()).collect();
+        assert_eq!(codes, ["unknown_input", "runes_ignored", "edict_without_balance"]);
+        assert!(report.runes.is_empty());
+    }
+}
@@ -1,10 +1,160 @@
 use bitcoin_monitor::*;
+use async_graphql::http::{GraphiQLSource, WebSocketProtocols, WsMessage};
 use clap::{Arg, Command};
 use std::sync::Arc;
+use warp::http::StatusCode;
 use warp::Filter;
 use warp::ws::{Message, WebSocket};
 use futures_util::{StreamExt, SinkExt};
-use tokio::sync::broadcast;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, watch};
+
+/// Default number of events queued per live feed connection.
+const DEFAULT_CLIENT_BUFFER: usize = 256;
+const MAX_CLIENT_BUFFER: usize = 4096;
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const PONG_TIMEOUT: Duration = Duration::from_secs(90);
+/// WebSocket close code sent to clients when the server shuts down.
+const CLOSE_GOING_AWAY: u16 = 1001;
+
+/// Query parameters accepted by the live feed (WebSocket and SSE): replay
+/// position, an optional comma-separated protocol filter, and the number of
+/// events to queue for this connection before dropping.
+#[derive(Debug, Clone, Default, Deserialize)]
+struct FeedQuery {
+    since_seq: Option<u64>,
+    since: Option<u64>,
+    protocols: Option<String>,
+    buffer: Option<usize>,
+}
+
+impl FeedQuery {
+    fn replay(&self) -> ReplayRequest {
+        ReplayRequest {
+            since_seq: self.since_seq,
+            since: self.since,
+        }
+    }
+    
+    fn buffer_size(&self) -> usize {
+        self.buffer.unwrap_or(DEFAULT_CLIENT_BUFFER).clamp(1, MAX_CLIENT_BUFFER)
+    }
+    
+    fn matches(&self, tx: &LiveTransaction) -> bool {
+        match &self.protocols {
+            Some(protocols) => protocols
+                .split(',')
+                .map(str::trim)
+                .any(|p| tx.protocols.iter().any(|tp| tp.eq_ignore_ascii_case(p))),
+            None => true,
+        }
+    }
+}
+
+/// Error returned by every API route as
+/// `{"error": {"code": "...", "message": "..."}}` with a matching status.
+#[derive(Debug, Clone)]
+struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl warp::reject::Reject for ApiError {}
+
+impl ApiError {
+    fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self { status, code, message: message.into() }
+    }
+    
+    fn bad_request(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, code, message)
+    }
+    
+    fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+    
+    /// Map upstream failures to their status; anything else is treated as
+    /// a problem with the request and reported under `fallback_code`.
+    fn from_error(err: &anyhow::Error, fallback_code: &'static str) -> Self {
+        let Some(source) = SourceError::classify(err) else {
+            return Self::bad_request(fallback_code, err.to_string());
+        };
+        let status = match source {
+            SourceError::InvalidTxid => StatusCode::BAD_REQUEST,
+            SourceError::NotFound => StatusCode::NOT_FOUND,
+            SourceError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            SourceError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        Self::new(status, source.code(), err.to_string())
+    }
+    
+    fn into_reply(self) -> warp::reply::WithStatus<warp::reply::Json> {
+        let body = warp::reply::json(&serde_json::json!({
+            "error": {
+                "code": self.code,
+                "message": self.message,
+            }
+        }));
+        warp::reply::with_status(body, self.status)
+    }
+}
+
+async fn handle_rejection(err: warp::Rejection) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let error = if let Some(error) = err.find::<ApiError>() {
+        error.clone()
+    } else if err.is_not_found() {
+        ApiError::new(StatusCode::NOT_FOUND, "route_not_found", "No such route")
+    } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        ApiError::bad_request("invalid_body", e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+        ApiError::bad_request("invalid_query", e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::InvalidHeader>() {
+        ApiError::bad_request("invalid_header", e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::MissingHeader>() {
+        ApiError::bad_request("missing_header", e.to_string())
+    } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+        ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", "Request body is too large")
+    } else if err.find::<warp::reject::UnsupportedMediaType>().is_some() {
+        ApiError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type", "Expected a JSON body")
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        ApiError::new(StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", "Method not allowed")
+    } else {
+        tracing::error!(rejection = ?err, "Unhandled rejection");
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", "Internal server error")
+    };
+    
+    Ok(error.into_reply())
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchRequest {
+    txids: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WatchRequest {
+    address: String,
+    label: Option<String>,
+}
+
+/// Keeps the connected client count accurate however a connection ends.
+struct ClientGuard(Arc<FeedMetrics>);
+
+impl ClientGuard {
+    fn new(metrics: Arc<FeedMetrics>) -> Self {
+        metrics.client_connected();
+        Self(metrics)
+    }
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.0.client_disconnected();
+    }
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -15,55 +165,170 @@ async fn main() -> anyhow::Result<()> {
             .short('p')
             .long("port")
             .value_name("PORT")
-            .help("Server port")
-            .default_value("8000"))
+            .help("Server port (default 8000)"))
+        .arg(Arg::new("config")
+            .short('c')
+            .long("config")
+            .value_name("FILE")
+            .help("TOML config file; defaults to BTC_MONITOR_CONFIG. BTC_MONITOR_* variables override it"))
         .arg(Arg::new("demo")
             .short('d')
             .long("demo")
             .help("Enable demo mode with simulated transactions")
             .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("log-format")
+            .long("log-format")
+            .value_name("FORMAT")
+            .help("Log output: text or json")
+            .default_value("text"))
+        .arg(Arg::new("log-level")
+            .long("log-level")
+            .value_name("FILTER")
+            .help("Log filter, e.g. info,bitcoin_monitor::parsers=debug (RUST_LOG overrides)")
+            .default_value("info"))
+        .arg(Arg::new("otlp-endpoint")
+            .long("otlp-endpoint")
+            .value_name("URL")
+            .help("Export traces to an OTLP/HTTP collector (needs the otlp feature); defaults to OTEL_EXPORTER_OTLP_ENDPOINT"))
+        .subcommand(Command::new("analyze-batch")
+            .about("Analyze a list of txids (one per line) and print the results as JSON")
+            .arg(Arg::new("file")
+                .value_name("FILE")
+                .help("File to read txids from; omit or use - for stdin"))
+            .arg(Arg::new("concurrency")
+                .short('c')
+                .long("concurrency")
+                .value_name("N")
+                .help("Transactions fetched at once")
+                .default_value("4")))
         .get_matches();
-
-    let port: u16 = matches.get_one::<String>("port").unwrap().parse()?;
-    let demo_mode = matches.get_flag("demo");
     
-    println!("Bitcoin Metaprotocol Monitor v2.0");
-    if demo_mode {
-        println!("Starting in demo mode");
+    let log_format: telemetry::LogFormat = matches.get_one::<String>("log-format").unwrap().parse()?;
+    let otlp_endpoint = matches.get_one::<String>("otlp-endpoint").cloned()
+        .or_else(|| std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok());
+    let _telemetry = telemetry::init(
+        log_format,
+        matches.get_one::<String>("log-level").unwrap(),
+        otlp_endpoint.as_deref(),
+    )?;
+    
+    let config_path = matches.get_one::<String>("config").cloned()
+        .or_else(|| std::env::var("BTC_MONITOR_CONFIG").ok());
+    let mut config = config::Config::load(config_path.as_deref().map(std::path::Path::new))?;
+    if let Some(port) = matches.get_one::<String>("port") {
+        config.server.port = port.parse()?;
+    }
+    if matches.get_flag("demo") {
+        config.server.demo = true;
     }
-    println!("Starting real-time monitoring...");
+    config.validate()?;
+    
+    if let Some(("analyze-batch", sub)) = matches.subcommand() {
+        let concurrency: usize = sub.get_one::<String>("concurrency").unwrap().parse()?;
+        let input = match sub.get_one::<String>("file").map(String::as_str) {
+            None | Some("-") => std::io::read_to_string(std::io::stdin())?,
+            Some(path) => std::fs::read_to_string(path)?,
+        };
+        let txids: Vec<String> = input
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(String::from)
+            .collect();
+        
+        let client = source::from_config(&config.backend)?;
+        let results = analyze_batch(client.as_ref(), &txids, concurrency).await;
+        println!("{}", serde_json::to_string_pretty(&results)?);
+        return Ok(());
+    }
+
+    let port = config.server.port;
+    let demo_mode = config.server.demo;
+    
+    tracing::info!(version = "2.0", demo_mode, backend = config.backend.kind.name(), "Bitcoin Metaprotocol Monitor starting");
     
     // Initialize monitor
-    let (monitor, tx_receiver) = MetaprotocolMonitor::new();
-    let monitor = Arc::new(monitor);
+    let mut handle = builder::MonitorBuilder::from_config(&config).build()?;
+    let monitor = handle.monitor();
     
     // Start monitoring
-    monitor.clone().start_monitoring(demo_mode).await;
+    handle.start();
     
     // Setup routes
-    let routes = setup_routes(monitor.clone(), tx_receiver);
+    let routes = setup_routes(monitor.clone());
     
-    println!("\nServer ready on http://localhost:{}", port);
-    println!("Dashboard: http://localhost:{}/", port);
-    println!("WebSocket: ws://localhost:{}/ws", port);
-    println!("API: http://localhost:{}/api/analyze/{{txid}}", port);
-    println!("\nMonitoring protocols: BRC-20, Runes, Stamps");
+    tracing::info!(
+        port,
+        dashboard = %format!("http://localhost:{}/", port),
+        websocket = %format!("ws://localhost:{}/ws", port),
+        graphql = %format!("http://localhost:{}/graphql", port),
+        protocols = "brc20,runes,stamps",
+        "Server ready",
+    );
     
-    warp::serve(routes)
-        .run(([0, 0, 0, 0], port))
-        .await;
+    // Stop accepting connections once the live feed has been closed
+    let mut closing = monitor.closing();
+    let (_, server) = warp::serve(routes).try_bind_with_graceful_shutdown(([0, 0, 0, 0], port), async move {
+        let _ = closing.wait_for(|closing| *closing).await;
+    })?;
+    let server = tokio::spawn(server);
+    
+    let signal = shutdown_signal().await;
+    let deadline = config.server.shutdown_timeout();
+    tracing::info!(signal, deadline_secs = deadline.as_secs(), "Shutting down");
+    
+    let shutdown = async {
+        let saved = handle.shutdown().await;
+        let _ = server.await;
+        saved
+    };
+    tokio::select! {
+        result = tokio::time::timeout(deadline, shutdown) => match result {
+            Ok(saved) => {
+                saved?;
+                tracing::info!("Shutdown complete");
+            }
+            Err(_) => tracing::warn!(deadline_secs = deadline.as_secs(), "Shutdown deadline passed, exiting"),
+        },
+        signal = shutdown_signal() => tracing::warn!(signal, "Second signal received, exiting"),
+    }
     
     Ok(())
 }
 
+/// Wait for SIGINT (Ctrl-C) or, on Unix, SIGTERM and return its name.
+async fn shutdown_signal() -> &'static str {
+    let interrupt = async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    };
+    
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    
+    tokio::select! {
+        _ = interrupt => "SIGINT",
+        _ = terminate => "SIGTERM",
+    }
+}
+
 fn setup_routes(
     monitor: Arc<MetaprotocolMonitor>,
-    tx_receiver: broadcast::Receiver<LiveTransaction>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     let cors = warp::cors()
         .allow_any_origin()
         .allow_headers(vec!["content-type"])
-        .allow_methods(vec!["GET", "POST", "OPTIONS"]);
+        .allow_methods(vec!["GET", "POST", "DELETE", "OPTIONS"]);
     
     // Dashboard HTML
     let dashboard = warp::path::end()
@@ -74,92 +339,592 @@ fn setup_routes(
         });
     
     // WebSocket endpoint
-    let tx_receiver = Arc::new(tokio::sync::Mutex::new(tx_receiver));
     let ws = warp::path("ws")
         .and(warp::ws())
-        .and(with_monitor(tx_receiver))
-        .map(|ws: warp::ws::Ws, tx_rx| {
-            ws.on_upgrade(move |socket| websocket_handler(socket, tx_rx))
+        .and(warp::query::<FeedQuery>())
+        .and(with_monitor(monitor.clone()))
+        .map(|ws: warp::ws::Ws, query: FeedQuery, monitor| {
+            ws.on_upgrade(move |socket| websocket_handler(socket, monitor, query))
         });
     
+    // Server-Sent Events mirror of the WebSocket feed
+    let api_stream = warp::path!("api" / "stream")
+        .and(warp::get())
+        .and(warp::query::<FeedQuery>())
+        .and(warp::header::optional::<u64>("last-event-id"))
+        .and(with_monitor(monitor.clone()))
+        .and_then(handle_stream);
+    
     // API endpoints
     let api_analyze = warp::path!("api" / "analyze" / String)
         .and(warp::post())
+        .and(with_monitor(monitor.clone()))
         .and_then(handle_analyze);
     
+    let api_analyze_body = warp::path!("api" / "analyze")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024 * 1024))
+        .and(warp::body::json())
+        .and(with_monitor(monitor.clone()))
+        .and_then(handle_analyze_request);
+    
+    let api_analyze_batch = warp::path!("api" / "analyze" / "batch")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024 * 1024))
+        .and(warp::body::json())
+        .and(with_monitor(monitor.clone()))
+        .and_then(handle_analyze_batch);
+    
+    let api_lint = warp::path!("api" / "lint")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024 * 1024))
+        .and(warp::body::json())
+        .and(with_monitor(monitor.clone()))
+        .and_then(handle_lint);
+    
     let monitor_stats = monitor.clone();
     let api_stats = warp::path!("api" / "stats")
         .and(warp::get())
-        .and(with_monitor_stats(monitor_stats))
+        .and(with_monitor(monitor_stats))
         .and_then(handle_stats);
     
+    let api_transactions = warp::path!("api" / "transactions")
+        .and(warp::get())
+        .and(warp::query::<history::HistoryQuery>())
+        .and(with_monitor(monitor.clone()))
+        .and_then(handle_transactions);
+    
+    let api_activities = warp::path!("api" / "activities")
+        .and(warp::get())
+        .and(warp::query::<history::HistoryQuery>())
+        .and(with_monitor(monitor.clone()))
+        .and_then(handle_activities);
+    
+    let api_brc20 = warp::path!("api" / "brc20" / String)
+        .and(warp::get())
+        .and(with_monitor(monitor.clone()))
+        .and_then(handle_brc20);
+    
+    let api_rune = warp::path!("api" / "runes" / String)
+        .and(warp::get())
+        .and(with_monitor(monitor.clone()))
+        .and_then(handle_rune);
+    
+    let api_address = warp::path!("api" / "address" / String)
+        .and(warp::get())
+        .and(warp::query::<history::HistoryQuery>())
+        .and(with_monitor(monitor.clone()))
+        .and_then(handle_address);
+    
+    let api_watchlist = warp::path!("api" / "watchlist")
+        .and(with_monitor(monitor.clone()));
+    
+    let api_watchlist_list = api_watchlist.clone()
+        .and(warp::get())
+        .and_then(handle_watchlist_list);
+    
+    let api_watchlist_add = api_watchlist.clone()
+        .and(warp::post())
+        .and(warp::body::json())
+        .and_then(handle_watchlist_add);
+    
+    let api_watchlist_remove = api_watchlist
+        .and(warp::delete())
+        .and(warp::body::json())
+        .and_then(handle_watchlist_remove);
+    
     let api_health = warp::path!("api" / "health")
         .and(warp::get())
-        .map(|| warp::reply::json(&serde_json::json!({
-            "status": "healthy",
-            "version": "2.0",
-            "protocols": ["brc20", "runes", "stamps"]
-        })));
+        .and(with_monitor(monitor.clone()))
+        .and_then(handle_health);
+    
+    let api_ready = warp::path!("api" / "ready")
+        .and(warp::get())
+        .and(with_monitor(monitor.clone()))
+        .and_then(handle_ready);
+    
+    // GraphQL queries, the GraphiQL explorer and subscriptions
+    let schema = graphql::build_schema(monitor.clone());
+    
+    let graphql_query = warp::path!("graphql")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024 * 1024))
+        .and(warp::body::json())
+        .and(with_schema(schema.clone()))
+        .and_then(handle_graphql);
+    
+    let graphiql = warp::path!("graphql")
+        .and(warp::get())
+        .map(|| {
+            let html = GraphiQLSource::build()
+                .endpoint("/graphql")
+                .subscription_endpoint("/graphql/ws")
+                .finish();
+            warp::reply::html(html)
+        });
+    
+    let graphql_ws = warp::path!("graphql" / "ws")
+        .and(warp::ws())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and(with_schema(schema))
+        .and(with_monitor(monitor.clone()))
+        .map(|ws: warp::ws::Ws, protocols: Option<String>, schema, monitor: Arc<MetaprotocolMonitor>| {
+            let protocol = protocols
+                .as_deref()
+                .and_then(|p| p.split(',').find_map(|p| p.trim().parse::<WebSocketProtocols>().ok()))
+                .unwrap_or(WebSocketProtocols::GraphQLWS);
+            let closing = monitor.closing();
+            let reply = ws.on_upgrade(move |socket| graphql_subscription(socket, schema, protocol, closing));
+            warp::reply::with_header(reply, "sec-websocket-protocol", protocol.sec_websocket_protocol())
+        });
+    
+    let metrics_route = warp::path!("metrics")
+        .and(warp::get())
+        .and(with_monitor(monitor.clone()))
+        .map(|monitor: Arc<MetaprotocolMonitor>| {
+            let metrics = metrics::metrics();
+            metrics.broadcast_backlog.set(monitor.broadcast_backlog() as i64);
+            warp::reply::with_header(metrics.render(), "content-type", "text/plain; version=0.0.4")
+        });
+    
+    let api_openapi = warp::path!("api" / "openapi.json")
+        .and(warp::get())
+        .map(|| warp::reply::json(&openapi::openapi_document()));
     
     dashboard
         .or(ws)
+        .or(api_stream)
+        .or(api_analyze_batch)
         .or(api_analyze)
+        .or(api_analyze_body)
+        .or(api_lint)
         .or(api_stats)
+        .or(api_transactions)
+        .or(api_activities)
+        .or(api_brc20)
+        .or(api_rune)
+        .or(api_address)
+        .or(api_watchlist_list)
+        .or(api_watchlist_add)
+        .or(api_watchlist_remove)
         .or(api_health)
+        .or(api_ready)
+        .or(api_openapi)
+        .or(metrics_route)
+        .or(graphql_ws)
+        .or(graphql_query)
+        .or(graphiql)
+        .recover(handle_rejection)
         .with(cors)
+        .with(warp::trace::request())
 }
 
 fn with_monitor(
-    tx_rx: Arc<tokio::sync::Mutex<broadcast::Receiver<LiveTransaction>>>
-) -> impl Filter<Extract = (Arc<tokio::sync::Mutex<broadcast::Receiver<LiveTransaction>>>,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || tx_rx.clone())
-}
-
-fn with_monitor_stats(
     monitor: Arc<MetaprotocolMonitor>
 ) -> impl Filter<Extract = (Arc<MetaprotocolMonitor>,), Error = std::convert::Infallible> + Clone {
     warp::any().map(move || monitor.clone())
 }
 
+fn with_schema(
+    schema: graphql::MonitorSchema
+) -> impl Filter<Extract = (graphql::MonitorSchema,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || schema.clone())
+}
+
+async fn handle_graphql(
+    request: async_graphql::Request,
+    schema: graphql::MonitorSchema,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&schema.execute(request).await))
+}
+
+/// Serve GraphQL subscriptions over either the graphql-transport-ws or the
+/// older graphql-ws protocol, whichever the client asked for.
+#[tracing::instrument(name = "graphql_ws_client", skip(socket, schema, closing))]
+async fn graphql_subscription(
+    socket: WebSocket,
+    schema: graphql::MonitorSchema,
+    protocol: WebSocketProtocols,
+    mut closing: watch::Receiver<bool>,
+) {
+    let (mut sink, stream) = socket.split();
+    let incoming = stream
+        .take_while(|msg| std::future::ready(msg.is_ok()))
+        .filter_map(|msg| std::future::ready(match msg {
+            Ok(msg) if msg.is_text() || msg.is_binary() => Some(msg.into_bytes()),
+            _ => None,
+        }));
+    
+    let mut outgoing = async_graphql::http::WebSocket::new(schema, incoming, protocol);
+    loop {
+        let message = tokio::select! {
+            message = outgoing.next() => message,
+            _ = closing.wait_for(|closing| *closing) => {
+                Some(WsMessage::Close(CLOSE_GOING_AWAY, "Server shutting down".to_string()))
+            }
+        };
+        let Some(message) = message else { break };
+        let message = match message {
+            WsMessage::Text(text) => Message::text(text),
+            WsMessage::Close(code, reason) => Message::close_with(code, reason),
+        };
+        let close = message.is_close();
+        if sink.send(message).await.is_err() || close {
+            break;
+        }
+    }
+}
+
+#[tracing::instrument(name = "ws_client", skip_all, fields(protocols = query.protocols.as_deref(), since_seq = query.since_seq))]
 async fn websocket_handler(
     ws: WebSocket,
-    tx_rx: Arc<tokio::sync::Mutex<broadcast::Receiver<LiveTransaction>>>,
+    monitor: Arc<MetaprotocolMonitor>,
+    query: FeedQuery,
 ) {
     let (mut ws_tx, mut ws_rx) = ws.split();
+    let metrics = monitor.feed_metrics();
+    let _guard = ClientGuard::new(metrics.clone());
+    tracing::debug!("WebSocket client connected");
     
-    // Clone receiver for this connection
-    let mut rx = {
-        let mut locked = tx_rx.lock().await;
-        locked.resubscribe()
-    };
+    // Subscribe for this connection, replaying any requested backlog first
+    let (backlog, rx) = monitor.subscribe(&query.replay()).await;
+    let (queue_tx, mut queue_rx) = mpsc::channel(query.buffer_size());
+    let forward_task = tokio::spawn(forward_feed(backlog, rx, monitor.subscribe_blocks(), queue_tx, query, metrics, monitor.closing()));
+    
+    let mut ping = tokio::time::interval(PING_INTERVAL);
+    let mut last_pong = Instant::now();
     
-    // Send transactions to websocket
-    let send_task = tokio::spawn(async move {
-        while let Ok(tx) = rx.recv().await {
-            let msg = serde_json::to_string(&tx).unwrap();
-            if ws_tx.send(Message::text(msg)).await.is_err() {
-                break;
+    loop {
+        tokio::select! {
+            event = queue_rx.recv() => {
+                let Some(event) = event else { break };
+                let msg = serde_json::to_string(&event).unwrap();
+                if ws_tx.send(Message::text(msg)).await.is_err() {
+                    break;
+                }
+            }
+            _ = ping.tick() => {
+                if last_pong.elapsed() > PONG_TIMEOUT {
+                    tracing::info!("WebSocket client missed keepalive, closing");
+                    break;
+                }
+                if ws_tx.send(Message::ping(Vec::new())).await.is_err() {
+                    break;
+                }
+            }
+            msg = ws_rx.next() => {
+                match msg {
+                    Some(Ok(msg)) if msg.is_close() => break,
+                    Some(Ok(msg)) => {
+                        if msg.is_pong() {
+                            last_pong = Instant::now();
+                        }
+                    }
+                    _ => break,
+                }
             }
         }
-    });
+    }
     
-    // Handle incoming messages (if any)
-    while let Some(Ok(_msg)) = ws_rx.next().await {
+    forward_task.abort();
+    if monitor.is_closing() {
+        let _ = ws_tx.send(Message::close_with(CLOSE_GOING_AWAY, "Server shutting down")).await;
+    }
+    let _ = ws_tx.close().await;
+    tracing::debug!("WebSocket client disconnected");
+}
+
+async fn handle_stream(
+    mut query: FeedQuery,
+    last_event_id: Option<u64>,
+    monitor: Arc<MetaprotocolMonitor>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // A reconnecting EventSource resumes from the last id it saw
+    if last_event_id.is_some() {
+        query.since_seq = last_event_id;
     }
     
-    send_task.abort();
+    let metrics = monitor.feed_metrics();
+    let guard = ClientGuard::new(metrics.clone());
+    
+    let (backlog, rx) = monitor.subscribe(&query.replay()).await;
+    let (queue_tx, queue_rx) = mpsc::channel(query.buffer_size());
+    let forward_task = tokio::spawn(forward_feed(backlog, rx, monitor.subscribe_blocks(), queue_tx, query, metrics, monitor.closing()));
+    
+    // The guard and forwarding task live as long as the response stream
+    let stream = futures_util::stream::unfold(
+        (queue_rx, guard, AbortOnDrop(forward_task)),
+        |(mut queue_rx, guard, task)| async move {
+            let event = queue_rx.recv().await?;
+            Some((sse_event(&event), (queue_rx, guard, task)))
+        },
+    );
+    
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(stream)))
 }
 
-async fn handle_analyze(txid: String) -> Result<impl warp::Reply, warp::Rejection> {
-    match analyze_transaction(&txid).await {
+fn sse_event(event: &FeedEvent) -> Result<warp::sse::Event, std::convert::Infallible> {
+    let sse = warp::sse::Event::default()
+        .json_data(event)
+        .unwrap_or_else(|_| warp::sse::Event::default());
+    
+    Ok(match event {
+        FeedEvent::Transaction(tx) => sse.event("transaction").id(tx.seq.to_string()),
+        FeedEvent::Gap { .. } => sse.event("gap"),
+        FeedEvent::Alert { .. } => sse.event("alert"),
+        FeedEvent::Block(_) => sse.event("block"),
+    })
+}
+
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Move events from the shared broadcast channel into one client's queue.
+/// Messages lost to broadcast lag or a full queue are counted and reported
+/// to the client as a single gap event once it has room again. Block
+/// summaries go to every client and are dropped if its queue is full. Once
+/// the monitor is closing, whatever is left in the channel is forwarded and
+/// the queue is closed.
+async fn forward_feed(
+    backlog: Vec<LiveTransaction>,
+    mut rx: broadcast::Receiver<LiveTransaction>,
+    mut blocks: broadcast::Receiver<BlockSummary>,
+    queue: mpsc::Sender<FeedEvent>,
+    filter: FeedQuery,
+    metrics: Arc<FeedMetrics>,
+    mut closing: watch::Receiver<bool>,
+) {
+    let mut last_seq = 0;
+    let mut skipped = 0u64;
+    
+    for tx in backlog.into_iter().filter(|tx| filter.matches(tx)) {
+        last_seq = tx.seq;
+        if let Some(alert) = FeedEvent::alert_for(&tx) {
+            if queue.send(alert).await.is_err() {
+                return;
+            }
+        }
+        if queue.send(FeedEvent::Transaction(tx)).await.is_err() {
+            return;
+        }
+    }
+    
+    loop {
+        let result = tokio::select! {
+            biased;
+            result = rx.recv() => result,
+            block = blocks.recv() => {
+                match block {
+                    Ok(summary) => match queue.try_send(FeedEvent::Block(summary)) {
+                        Ok(()) => {}
+                        Err(mpsc::error::TrySendError::Full(_)) => metrics.record_dropped(),
+                        Err(mpsc::error::TrySendError::Closed(_)) => return,
+                    },
+                    Err(broadcast::error::RecvError::Lagged(n)) => metrics.record_lag(n),
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+                continue;
+            }
+            _ = closing.wait_for(|closing| *closing) => return,
+        };
+        let tx = match result {
+            Ok(tx) => tx,
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                metrics.record_lag(n);
+                skipped += n;
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        
+        if !filter.matches(&tx) {
+            continue;
+        }
+        
+        if skipped > 0 {
+            match queue.try_send(FeedEvent::Gap { skipped, last_seq }) {
+                Ok(()) => {
+                    metrics.record_gap();
+                    skipped = 0;
+                }
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    metrics.record_dropped();
+                    skipped += 1;
+                    continue;
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => return,
+            }
+        }
+        
+        if let Some(alert) = FeedEvent::alert_for(&tx) {
+            match queue.try_send(alert) {
+                Ok(()) => {}
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    metrics.record_dropped();
+                    skipped += 1;
+                    continue;
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => return,
+            }
+        }
+        
+        let seq = tx.seq;
+        match queue.try_send(FeedEvent::Transaction(tx)) {
+            Ok(()) => last_seq = seq,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                metrics.record_dropped();
+                skipped += 1;
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => return,
+        }
+    }
+}
+
+async fn handle_analyze(txid: String, monitor: Arc<MetaprotocolMonitor>) -> Result<impl warp::Reply, warp::Rejection> {
+    match analyze_transaction(monitor.source(), &txid).await {
+        Ok(result) => Ok(warp::reply::json(&result)),
+        Err(e) => Err(ApiError::from_error(&e, "invalid_request").into()),
+    }
+}
+
+async fn handle_analyze_request(request: AnalyzeRequest, monitor: Arc<MetaprotocolMonitor>) -> Result<impl warp::Reply, warp::Rejection> {
+    match analyze_request(monitor.source(), &request).await {
         Ok(result) => Ok(warp::reply::json(&result)),
-        Err(e) => Ok(warp::reply::json(&serde_json::json!({
-            "error": e.to_string()
-        }))),
+        Err(e) => Err(ApiError::from_error(&e, "invalid_transaction").into()),
+    }
+}
+
+async fn handle_analyze_batch(request: BatchRequest, monitor: Arc<MetaprotocolMonitor>) -> Result<impl warp::Reply, warp::Rejection> {
+    if request.txids.len() > MAX_BATCH_SIZE {
+        return Err(ApiError::bad_request(
+            "batch_too_large",
+            format!("Batch is limited to {} txids", MAX_BATCH_SIZE),
+        ).into());
+    }
+    
+    Ok(warp::reply::json(&analyze_batch(monitor.source(), &request.txids, BATCH_CONCURRENCY).await))
+}
+
+async fn handle_lint(request: lint::LintRequest, monitor: Arc<MetaprotocolMonitor>) -> Result<impl warp::Reply, warp::Rejection> {
+    match monitor.lint(&request).await {
+        Ok(report) => Ok(warp::reply::json(&report)),
+        Err(e) => Err(ApiError::from_error(&e, "invalid_transaction").into()),
     }
 }
 
+async fn handle_health(monitor: Arc<MetaprotocolMonitor>) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&monitor.health().await))
+}
+
+/// 200 once the initial scans have completed, 503 until then.
+async fn handle_ready(monitor: Arc<MetaprotocolMonitor>) -> Result<impl warp::Reply, warp::Rejection> {
+    let ready = monitor.is_ready().await;
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    Ok(warp::reply::with_status(warp::reply::json(&serde_json::json!({ "ready": ready })), status))
+}
+
 async fn handle_stats(monitor: Arc<MetaprotocolMonitor>) -> Result<impl warp::Reply, warp::Rejection> {
     let stats = monitor.get_stats().await;
     Ok(warp::reply::json(&stats))
+}
+
+async fn handle_transactions(
+    query: history::HistoryQuery,
+    monitor: Arc<MetaprotocolMonitor>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match monitor.query_transactions(&query).await {
+        Ok(page) => Ok(warp::reply::json(&page)),
+        Err(e) => Err(ApiError::from_error(&e, "invalid_query").into()),
+    }
+}
+
+async fn handle_activities(
+    query: history::HistoryQuery,
+    monitor: Arc<MetaprotocolMonitor>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match monitor.query_activities(&query).await {
+        Ok(page) => Ok(warp::reply::json(&page)),
+        Err(e) => Err(ApiError::from_error(&e, "invalid_query").into()),
+    }
+}
+
+async fn handle_brc20(tick: String, monitor: Arc<MetaprotocolMonitor>) -> Result<impl warp::Reply, warp::Rejection> {
+    match monitor.brc20_detail(&tick).await {
+        Some(detail) => Ok(warp::reply::json(&detail)),
+        None => Err(ApiError::not_found(format!("No activity seen for BRC-20 token {}", tick)).into()),
+    }
+}
+
+async fn handle_rune(name_or_id: String, monitor: Arc<MetaprotocolMonitor>) -> Result<impl warp::Reply, warp::Rejection> {
+    // Names may arrive percent-encoded, e.g. with a spacer
+    let name_or_id = decode_path(&name_or_id);
+    match monitor.rune_detail(&name_or_id).await {
+        Some(detail) => Ok(warp::reply::json(&detail)),
+        None => Err(ApiError::not_found(format!("No activity seen for rune {}", name_or_id)).into()),
+    }
+}
+
+fn decode_path(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if let Some(byte) = segment.get(i + 1..i + 3).and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+async fn handle_address(
+    address: String,
+    mut query: history::HistoryQuery,
+    monitor: Arc<MetaprotocolMonitor>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    query.address = Some(address);
+    match monitor.query_activities(&query).await {
+        Ok(page) => Ok(warp::reply::json(&page)),
+        Err(e) => Err(ApiError::from_error(&e, "invalid_query").into()),
+    }
+}
+
+async fn handle_watchlist_list(monitor: Arc<MetaprotocolMonitor>) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut entries: Vec<WatchEntry> = monitor.watchlist().await.into_values().collect();
+    entries.sort_by_key(|e| e.added_at);
+    Ok(warp::reply::json(&entries))
+}
+
+async fn handle_watchlist_add(
+    monitor: Arc<MetaprotocolMonitor>,
+    request: WatchRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if request.address.trim().is_empty() {
+        return Err(ApiError::bad_request("invalid_address", "Address must not be empty").into());
+    }
+    let entry = monitor.watch(request.address.trim(), request.label).await;
+    Ok(warp::reply::json(&entry))
+}
+
+async fn handle_watchlist_remove(
+    monitor: Arc<MetaprotocolMonitor>,
+    request: WatchRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !monitor.unwatch(request.address.trim()).await {
+        return Err(ApiError::not_found(format!("{} is not on the watchlist", request.address)).into());
+    }
+    Ok(warp::reply::json(&serde_json::json!({
+        "address": request.address,
+        "removed": true,
+    })))
 }
\ No newline at end of file
@@ -0,0 +1,103 @@
+// Prometheus metrics, registered once and shared by the whole process
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+pub struct Metrics {
+    registry: Registry,
+    /// Transactions fetched for parsing, by `source` (mempool or block).
+    pub txs_scanned: IntCounterVec,
+    /// Transactions published with activity, by `protocol`.
+    pub txs_matched: IntCounterVec,
+    pub parser_duration: HistogramVec,
+    /// Upstream requests by `backend` and `endpoint`.
+    pub upstream_requests: IntCounterVec,
+    /// Failed upstream requests by `backend`, `endpoint` and `kind`.
+    pub upstream_errors: IntCounterVec,
+    pub upstream_duration: HistogramVec,
+    /// Duration of a full scan, by `scan` (mempool or blocks).
+    pub scan_duration: HistogramVec,
+    /// Transactions in the backend's mempool, in full sync mode.
+    pub mempool_size: IntGauge,
+    /// Transactions that left the mempool, in full sync mode.
+    pub mempool_removed: IntCounter,
+    pub ws_clients: IntGauge,
+    pub feed_lagged_events: IntCounter,
+    pub feed_dropped_messages: IntCounter,
+    pub feed_gaps: IntCounter,
+    /// Events waiting in the broadcast channel for the slowest subscriber.
+    pub broadcast_backlog: IntGauge,
+}
+
+fn counter_vec(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    let counter = IntCounterVec::new(Opts::new(name, help), labels).expect("valid metric");
+    registry.register(Box::new(counter.clone())).expect("unique metric");
+    counter
+}
+
+fn counter(registry: &Registry, name: &str, help: &str) -> IntCounter {
+    let counter = IntCounter::new(name, help).expect("valid metric");
+    registry.register(Box::new(counter.clone())).expect("unique metric");
+    counter
+}
+
+fn gauge(registry: &Registry, name: &str, help: &str) -> IntGauge {
+    let gauge = IntGauge::new(name, help).expect("valid metric");
+    registry.register(Box::new(gauge.clone())).expect("unique metric");
+    gauge
+}
+
+fn histogram_vec(registry: &Registry, name: &str, help: &str, labels: &[&str], buckets: Vec<f64>) -> HistogramVec {
+    let histogram = HistogramVec::new(HistogramOpts::new(name, help).buckets(buckets), labels).expect("valid metric");
+    registry.register(Box::new(histogram.clone())).expect("unique metric");
+    histogram
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("bitcoin_monitor".to_string()), None).expect("valid prefix");
+        let parser_buckets = prometheus::exponential_buckets(0.000_01, 4.0, 10).expect("valid buckets");
+        let request_buckets = prometheus::exponential_buckets(0.01, 2.0, 12).expect("valid buckets");
+        let scan_buckets = prometheus::exponential_buckets(0.5, 2.0, 10).expect("valid buckets");
+
+        Self {
+            txs_scanned: counter_vec(&registry, "transactions_scanned_total", "Transactions fetched and parsed", &["source"]),
+            txs_matched: counter_vec(&registry, "transactions_matched_total", "Transactions with metaprotocol activity", &["protocol"]),
+            parser_duration: histogram_vec(&registry, "parser_duration_seconds", "Time spent in each protocol parser", &["parser"], parser_buckets),
+            upstream_requests: counter_vec(&registry, "upstream_requests_total", "Requests to the upstream API", &["backend", "endpoint"]),
+            upstream_errors: counter_vec(&registry, "upstream_errors_total", "Failed requests to the upstream API", &["backend", "endpoint", "kind"]),
+            upstream_duration: histogram_vec(&registry, "upstream_request_duration_seconds", "Upstream request latency", &["backend", "endpoint"], request_buckets),
+            scan_duration: histogram_vec(&registry, "scan_duration_seconds", "Duration of mempool and block scans", &["scan"], scan_buckets),
+            mempool_size: gauge(&registry, "mempool_transactions", "Transactions in the backend mempool (full sync mode)"),
+            mempool_removed: counter(&registry, "mempool_removed_total", "Transactions that left the mempool: confirmed, replaced or evicted"),
+            ws_clients: gauge(&registry, "feed_connected_clients", "Connected live feed clients (WebSocket and SSE)"),
+            feed_lagged_events: counter(&registry, "feed_lagged_events_total", "Events skipped because a client fell behind the broadcast channel"),
+            feed_dropped_messages: counter(&registry, "feed_dropped_messages_total", "Events dropped because a client queue was full"),
+            feed_gaps: counter(&registry, "feed_gaps_total", "Gap notices sent to clients"),
+            broadcast_backlog: gauge(&registry, "broadcast_backlog", "Events queued in the broadcast channel"),
+            registry,
+        }
+    }
+
+    /// Run a parser and record how long it took.
+    pub fn time_parser<T>(&self, parser: &str, parse: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = parse();
+        self.parser_duration.with_label_values(&[parser]).observe(start.elapsed().as_secs_f64());
+        result
+    }
+
+    /// Everything registered, in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        let _ = TextEncoder::new().encode(&self.registry.gather(), &mut buffer);
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
@@ -0,0 +1,372 @@
+// OpenAPI description of the HTTP API, generated from the serialized types
+use crate::health::HealthReport;
+use crate::history::{ActivityRecord, HistoryQuery, Page};
+use crate::lint::{LintReport, LintRequest};
+use crate::{
+    Activity, AnalyzeRequest, FeedEvent, FeedMetricsSnapshot, LiveTransaction, ProtocolStats, StateChange, Transaction,
+    WatchEntry,
+};
+use schemars::generate::{SchemaGenerator, SchemaSettings};
+use schemars::JsonSchema;
+use serde_json::{json, Map, Value};
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+fn json_content(schema: Value) -> Value {
+    json!({ "application/json": { "schema": schema } })
+}
+
+fn ok(description: &str, schema: Value) -> Value {
+    json!({ "description": description, "content": json_content(schema) })
+}
+
+fn error(description: &str) -> Value {
+    ok(description, schema_ref("ErrorResponse"))
+}
+
+fn register<T: JsonSchema>(generator: &mut SchemaGenerator) -> Value {
+    generator.subschema_for::<T>().to_value()
+}
+
+/// Query parameters for every property of `schema`.
+fn query_parameters(schema: &Value) -> Vec<Value> {
+    let properties = schema["properties"].as_object().cloned().unwrap_or_default();
+    properties
+        .into_iter()
+        .map(|(name, mut property)| {
+            let description = property.as_object_mut().and_then(|p| p.remove("description"));
+            let mut parameter = json!({ "name": name, "in": "query", "required": false, "schema": property });
+            if let Some(description) = description {
+                parameter["description"] = description;
+            }
+            parameter
+        })
+        .collect()
+}
+
+fn path_parameter(name: &str, description: &str) -> Value {
+    json!({ "name": name, "in": "path", "required": true, "description": description, "schema": { "type": "string" } })
+}
+
+fn feed_parameters() -> Vec<Value> {
+    let parameter = |name: &str, kind: &str, description: &str| {
+        json!({ "name": name, "in": "query", "required": false, "description": description, "schema": { "type": kind } })
+    };
+    vec![
+        parameter("since_seq", "integer", "Replay retained transactions with a higher seq before going live"),
+        parameter("since", "integer", "Replay retained transactions seen at or after this unix timestamp"),
+        parameter("protocols", "string", "Comma-separated protocols to include, e.g. brc20,runes"),
+        parameter("buffer", "integer", "Events queued for this connection before a gap is reported"),
+    ]
+}
+
+/// Schemas for the JSON bodies built by hand rather than from a Rust type.
+fn handwritten_schemas() -> Map<String, Value> {
+    let mut schemas = Map::new();
+    schemas.insert("ErrorResponse".into(), json!({
+        "type": "object",
+        "required": ["error"],
+        "properties": {
+            "error": {
+                "type": "object",
+                "required": ["code", "message"],
+                "properties": {
+                    "code": { "type": "string" },
+                    "message": { "type": "string" },
+                },
+            },
+        },
+    }));
+    schemas.insert("Analysis".into(), json!({
+        "type": "object",
+        "properties": {
+            "txid": { "type": "string" },
+            "size": { "type": "integer" },
+            "vsize": { "type": "integer" },
+            "fee": { "type": "integer", "nullable": true },
+            "fee_rate_sat_vb": { "type": "number" },
+            "total_value_sats": { "type": "integer" },
+            "total_value_btc": { "type": "number" },
+            "protocols_detected": { "type": "array", "items": { "type": "string" } },
+            "activities": { "type": "array", "items": schema_ref("Activity") },
+            "activity_count": { "type": "integer" },
+            "is_metaprotocol": { "type": "boolean" },
+            "importance_score": { "type": "integer" },
+            "warnings": { "type": "array", "items": { "type": "string" } },
+            "timestamp": { "type": "integer", "nullable": true },
+            "confirmed": { "type": "boolean" },
+            "block_height": { "type": "integer", "nullable": true },
+            "broadcast": {
+                "type": "boolean",
+                "description": "Present and false for raw or PSBT input, which may not be on the network yet",
+            },
+        },
+    }));
+    schemas.insert("BatchRequest".into(), json!({
+        "type": "object",
+        "required": ["txids"],
+        "properties": {
+            "txids": { "type": "array", "items": { "type": "string" }, "maxItems": crate::MAX_BATCH_SIZE },
+        },
+    }));
+    schemas.insert("BatchResponse".into(), json!({
+        "type": "object",
+        "properties": {
+            "succeeded": { "type": "integer" },
+            "failed": { "type": "integer" },
+            "results": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "required": ["txid", "ok"],
+                    "properties": {
+                        "txid": { "type": "string" },
+                        "ok": { "type": "boolean" },
+                        "result": schema_ref("Analysis"),
+                        "error": { "$ref": "#/components/schemas/ErrorResponse/properties/error" },
+                    },
+                },
+            },
+        },
+    }));
+    schemas.insert("WatchRequest".into(), json!({
+        "type": "object",
+        "required": ["address"],
+        "properties": {
+            "address": { "type": "string" },
+            "label": { "type": "string", "nullable": true },
+        },
+    }));
+    schemas.insert("TokenDetail".into(), json!({
+        "type": "object",
+        "description": "Deploy or etching parameters, supply progress, holder count and recent activity",
+        "properties": {
+            "recent_activity": { "type": "array", "items": schema_ref("ActivityRecord") },
+        },
+        "additionalProperties": true,
+    }));
+    schemas
+}
+
+/// The OpenAPI 3.0 document served at `/api/openapi.json`. Live feed
+/// messages on `/ws` and `/api/stream` are described by `FeedEvent`.
+pub fn openapi_document() -> Value {
+    let mut generator = SchemaSettings::openapi3().into_generator();
+    register::<Transaction>(&mut generator);
+    register::<Activity>(&mut generator);
+    register::<StateChange>(&mut generator);
+    register::<ProtocolStats>(&mut generator);
+    register::<LiveTransaction>(&mut generator);
+    register::<FeedEvent>(&mut generator);
+    register::<FeedMetricsSnapshot>(&mut generator);
+    register::<WatchEntry>(&mut generator);
+    register::<AnalyzeRequest>(&mut generator);
+    register::<LintRequest>(&mut generator);
+    register::<LintReport>(&mut generator);
+    register::<ActivityRecord>(&mut generator);
+    let transactions_page = register::<Page<LiveTransaction>>(&mut generator);
+    let activities_page = register::<Page<ActivityRecord>>(&mut generator);
+    register::<HistoryQuery>(&mut generator);
+    register::<HealthReport>(&mut generator);
+
+    let mut schemas = generator.take_definitions(true);
+    schemas.extend(handwritten_schemas());
+    let history_parameters = query_parameters(&schemas["HistoryQuery"]);
+    let mut address_parameters = vec![path_parameter("address", "Bitcoin address")];
+    address_parameters.extend(history_parameters.iter().filter(|p| p["name"] != "address").cloned());
+    let mut stream_parameters = feed_parameters();
+    stream_parameters.push(json!({
+        "name": "Last-Event-ID", "in": "header", "required": false, "schema": { "type": "integer" },
+    }));
+
+    let stats = json!({ "type": "object", "additionalProperties": schema_ref("ProtocolStats") });
+    let upstream_errors = [
+        ("400", error("Invalid txid or transaction")),
+        ("404", error("Transaction not found upstream")),
+        ("429", error("Upstream rate limit reached")),
+        ("503", error("Upstream unavailable")),
+    ];
+    let with_errors = |success: Value| {
+        let mut responses = Map::new();
+        responses.insert("200".into(), success);
+        for (status, response) in &upstream_errors {
+            responses.insert((*status).into(), response.clone());
+        }
+        Value::Object(responses)
+    };
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Bitcoin Metaprotocol Monitor",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Live and historical BRC-20, Runes and Stamps activity. Every error uses the ErrorResponse body.",
+        },
+        "paths": {
+            "/ws": {
+                "get": {
+                    "summary": "Live feed over WebSocket",
+                    "description": "Each text message is a JSON FeedEvent: a transaction, a gap report, a watchlist alert or a block summary.",
+                    "parameters": feed_parameters(),
+                    "responses": {
+                        "101": {
+                            "description": "Switching to the WebSocket protocol",
+                            "x-websocket-message": schema_ref("FeedEvent"),
+                        },
+                    },
+                },
+            },
+            "/api/stream": {
+                "get": {
+                    "summary": "Live feed as Server-Sent Events",
+                    "description": "Event names are transaction, gap, alert and block; the data of each is a FeedEvent and the id its seq. Resumes from Last-Event-ID.",
+                    "parameters": stream_parameters,
+                    "responses": {
+                        "200": {
+                            "description": "Event stream",
+                            "content": { "text/event-stream": { "schema": schema_ref("FeedEvent") } },
+                        },
+                    },
+                },
+            },
+            "/api/analyze": {
+                "post": {
+                    "summary": "Analyze a transaction by txid, raw hex or PSBT",
+                    "requestBody": { "required": true, "content": json_content(schema_ref("AnalyzeRequest")) },
+                    "responses": with_errors(ok("Analysis", schema_ref("Analysis"))),
+                },
+            },
+            "/api/analyze/{txid}": {
+                "post": {
+                    "summary": "Analyze a broadcast transaction",
+                    "parameters": [path_parameter("txid", "Transaction id")],
+                    "responses": with_errors(ok("Analysis", schema_ref("Analysis"))),
+                },
+            },
+            "/api/analyze/batch": {
+                "post": {
+                    "summary": "Analyze up to MAX_BATCH_SIZE transactions",
+                    "requestBody": { "required": true, "content": json_content(schema_ref("BatchRequest")) },
+                    "responses": {
+                        "200": ok("Per-txid results; failures do not fail the batch", schema_ref("BatchResponse")),
+                        "400": error("Too many txids"),
+                    },
+                },
+            },
+            "/api/lint": {
+                "post": {
+                    "summary": "Check where inscriptions and runes end up before broadcasting",
+                    "requestBody": { "required": true, "content": json_content(schema_ref("LintRequest")) },
+                    "responses": with_errors(ok("Lint report", schema_ref("LintReport"))),
+                },
+            },
+            "/api/stats": {
+                "get": {
+                    "summary": "Per-protocol statistics",
+                    "responses": { "200": ok("Statistics keyed by protocol", stats) },
+                },
+            },
+            "/api/transactions": {
+                "get": {
+                    "summary": "Query retained transactions",
+                    "parameters": history_parameters,
+                    "responses": {
+                        "200": ok("A page of transactions", transactions_page.clone()),
+                        "400": error("Invalid query or cursor"),
+                    },
+                },
+            },
+            "/api/activities": {
+                "get": {
+                    "summary": "Query retained activities",
+                    "parameters": history_parameters,
+                    "responses": {
+                        "200": ok("A page of activities", activities_page.clone()),
+                        "400": error("Invalid query or cursor"),
+                    },
+                },
+            },
+            "/api/address/{address}": {
+                "get": {
+                    "summary": "Activities involving an address",
+                    "parameters": address_parameters,
+                    "responses": {
+                        "200": ok("A page of activities", activities_page),
+                        "400": error("Invalid query or cursor"),
+                    },
+                },
+            },
+            "/api/brc20/{tick}": {
+                "get": {
+                    "summary": "BRC-20 token detail",
+                    "parameters": [path_parameter("tick", "Token ticker, case-insensitive")],
+                    "responses": {
+                        "200": ok("Token detail", schema_ref("TokenDetail")),
+                        "404": error("Token not seen"),
+                    },
+                },
+            },
+            "/api/runes/{rune}": {
+                "get": {
+                    "summary": "Rune detail",
+                    "parameters": [path_parameter("rune", "Rune id (block:tx) or name, spacers optional")],
+                    "responses": {
+                        "200": ok("Rune detail", schema_ref("TokenDetail")),
+                        "404": error("Rune not seen"),
+                    },
+                },
+            },
+            "/api/watchlist": {
+                "get": {
+                    "summary": "List watched addresses",
+                    "responses": {
+                        "200": ok("Watched addresses, oldest first", json!({ "type": "array", "items": schema_ref("WatchEntry") })),
+                    },
+                },
+                "post": {
+                    "summary": "Watch an address",
+                    "requestBody": { "required": true, "content": json_content(schema_ref("WatchRequest")) },
+                    "responses": {
+                        "200": ok("The watch entry", schema_ref("WatchEntry")),
+                        "400": error("Empty address"),
+                    },
+                },
+                "delete": {
+                    "summary": "Stop watching an address",
+                    "requestBody": { "required": true, "content": json_content(schema_ref("WatchRequest")) },
+                    "responses": {
+                        "200": ok("Removed", json!({
+                            "type": "object",
+                            "properties": { "address": { "type": "string" }, "removed": { "type": "boolean" } },
+                        })),
+                        "404": error("Address not watched"),
+                    },
+                },
+            },
+            "/api/health": {
+                "get": {
+                    "summary": "Backend connectivity, scanner progress, sync height and feed state",
+                    "responses": { "200": ok("Health report", schema_ref("HealthReport")) },
+                },
+            },
+            "/api/ready": {
+                "get": {
+                    "summary": "Readiness: succeeds once the initial mempool and block scans complete",
+                    "responses": {
+                        "200": ok("Ready", json!({ "type": "object", "properties": { "ready": { "type": "boolean" } } })),
+                        "503": ok("Still syncing", json!({ "type": "object", "properties": { "ready": { "type": "boolean" } } })),
+                    },
+                },
+            },
+            "/api/openapi.json": {
+                "get": {
+                    "summary": "This document",
+                    "responses": { "200": { "description": "OpenAPI document" } },
+                },
+            },
+        },
+        "components": { "schemas": schemas },
+    })
+}
@@ -0,0 +1,396 @@
+// BTC/USD and token floor prices, for expressing values in USD
+use crate::config::{PriceConfig, PriceKind};
+use crate::runestone::normalize_name;
+use crate::tokens::{self, RuneToken, TokenRegistry};
+use crate::{metrics, Activity};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const SATS_PER_BTC: f64 = 100_000_000.0;
+/// Asset name of the BTC/USD rows in a price file.
+const BTC: &str = "btc";
+/// Timestamps this close to now use the HTTP source's latest price.
+const LATEST_WINDOW_SECS: u64 = 600;
+/// How long the HTTP source reuses its latest price.
+const LATEST_TTL: Duration = Duration::from_secs(60);
+/// Historical HTTP prices are cached per bucket of this many seconds.
+const HISTORY_BUCKET_SECS: u64 = 3600;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+/// After a failed HTTP lookup, lookups fail fast for this long, doubling
+/// with each failure up to `MAX_FAILURE_BACKOFF`.
+const MIN_FAILURE_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_FAILURE_BACKOFF: Duration = Duration::from_secs(600);
+
+/// Where USD prices come from.
+#[async_trait]
+pub trait PriceSource: Send + Sync {
+    /// Short name used in logs.
+    fn kind(&self) -> &'static str;
+
+    /// USD per BTC at unix time `timestamp`.
+    async fn btc_usd(&self, timestamp: u64) -> anyhow::Result<f64>;
+
+    /// Floor price of `token` in sats per whole token at `timestamp`. Keys
+    /// are `brc20:TICK` or `runes:<rune id or name>`, as made by `floor_key`.
+    fn floor_sats(&self, _token: &str, _timestamp: u64) -> Option<f64> {
+        None
+    }
+}
+
+/// The price source `config` describes, if any.
+pub fn from_config(config: &PriceConfig) -> anyhow::Result<Option<Arc<dyn PriceSource>>> {
+    let floors = config.floors.clone();
+    Ok(Some(match config.kind {
+        PriceKind::None => return Ok(None),
+        PriceKind::Static => {
+            let btc_usd = config.btc_usd.ok_or_else(|| anyhow::anyhow!("price.btc_usd is required for the static price source"))?;
+            Arc::new(StaticPrice::new(btc_usd).with_floors(floors))
+        }
+        PriceKind::File => {
+            let path = config.path.as_ref().ok_or_else(|| anyhow::anyhow!("price.path is required for the file price source"))?;
+            Arc::new(FilePrice::from_file(path)?.with_floors(floors))
+        }
+        PriceKind::Http => Arc::new(HttpPrice::new(&config.url).with_floors(floors)),
+    }))
+}
+
+/// Canonical floor key: BRC-20 ticks upper-cased, rune names without
+/// spacers. Rune ids (`block:tx`) are kept as they are.
+pub fn floor_key(key: &str) -> String {
+    match key.split_once(':') {
+        Some(("brc20", tick)) => format!("brc20:{}", tick.to_uppercase()),
+        Some(("runes", rune)) if rune.contains(':') => key.to_string(),
+        Some(("runes", rune)) => format!("runes:{}", normalize_name(rune)),
+        _ => key.to_string(),
+    }
+}
+
+fn normalize_floors(floors: &HashMap<String, f64>) -> HashMap<String, f64> {
+    floors.iter().map(|(key, floor)| (floor_key(key), *floor)).collect()
+}
+
+pub fn sats_to_usd(sats: f64, btc_usd: f64) -> f64 {
+    sats / SATS_PER_BTC * btc_usd
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Worth in sats of the tokens an activity mints or moves, at floor
+/// prices; `None` when the amount or a floor is unknown. Rune amounts are
+/// scaled by the divisibility `tokens` learned from the etching.
+pub fn activity_sats(activity: &Activity, tokens: &TokenRegistry, floor: impl Fn(&str) -> Option<f64>) -> Option<f64> {
+    let data = &activity.data;
+    match (activity.protocol.as_str(), activity.operation.as_str()) {
+        ("brc20", "mint" | "transfer") => {
+            let tick = data.get("tick")?.as_str()?;
+            let amount = tokens::number(data.get("amount"))?;
+            Some(amount * floor(&floor_key(&format!("brc20:{}", tick)))?)
+        }
+        ("runes", "mint") => {
+            let id = data.get("rune_id")?.as_str()?;
+            let rune = tokens.rune(id)?;
+            Some(whole_runes(rune.mint_limit?, Some(rune)) * rune_floor(id, Some(rune), &floor)?)
+        }
+        ("runes", "transfer") => {
+            let values: Vec<f64> = data
+                .get("edicts")?
+                .as_array()?
+                .iter()
+                .filter_map(|edict| {
+                    let id = edict.get("rune_id")?.as_str()?;
+                    let amount: u128 = edict.get("amount")?.as_str()?.parse().ok()?;
+                    let rune = tokens.rune(id);
+                    Some(whole_runes(amount, rune) * rune_floor(id, rune, &floor)?)
+                })
+                .collect();
+            (!values.is_empty()).then(|| values.iter().sum())
+        }
+        _ => None,
+    }
+}
+
+fn whole_runes(amount: u128, rune: Option<&RuneToken>) -> f64 {
+    let divisibility = rune.and_then(|r| r.divisibility).unwrap_or(0);
+    amount as f64 / 10f64.powi(divisibility as i32)
+}
+
+/// A rune's floor by id, or else by name.
+fn rune_floor(id: &str, rune: Option<&RuneToken>, floor: &impl Fn(&str) -> Option<f64>) -> Option<f64> {
+    floor(&format!("runes:{}", id)).or_else(|| floor(&floor_key(&format!("runes:{}", rune?.name.as_ref()?))))
+}
+
+/// One fixed BTC/USD price.
+pub struct StaticPrice {
+    btc_usd: f64,
+    floors: HashMap<String, f64>,
+}
+
+impl StaticPrice {
+    pub fn new(btc_usd: f64) -> Self {
+        Self {
+            btc_usd,
+            floors: HashMap::new(),
+        }
+    }
+
+    /// Token floor prices in sats, keyed as in `PriceSource::floor_sats`.
+    pub fn with_floors(mut self, floors: HashMap<String, f64>) -> Self {
+        self.floors = normalize_floors(&floors);
+        self
+    }
+}
+
+#[async_trait]
+impl PriceSource for StaticPrice {
+    fn kind(&self) -> &'static str {
+        "static"
+    }
+
+    async fn btc_usd(&self, _timestamp: u64) -> anyhow::Result<f64> {
+        Ok(self.btc_usd)
+    }
+
+    fn floor_sats(&self, token: &str, _timestamp: u64) -> Option<f64> {
+        self.floors.get(token).copied()
+    }
+}
+
+/// One row of a price file: USD per BTC when `asset` is `btc`, otherwise
+/// the floor of a token in sats.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PricePoint {
+    pub timestamp: u64,
+    pub asset: String,
+    pub price: f64,
+}
+
+/// Prices from a historical file, each valid from its timestamp until the
+/// next one for the same asset.
+pub struct FilePrice {
+    name: String,
+    series: HashMap<String, Vec<(u64, f64)>>,
+    floors: HashMap<String, f64>,
+}
+
+impl FilePrice {
+    pub fn new(points: impl IntoIterator<Item = PricePoint>) -> Self {
+        let mut series: HashMap<String, Vec<(u64, f64)>> = HashMap::new();
+        for point in points {
+            let asset = match point.asset.eq_ignore_ascii_case(BTC) {
+                true => BTC.to_string(),
+                false => floor_key(&point.asset),
+            };
+            series.entry(asset).or_default().push((point.timestamp, point.price));
+        }
+        for prices in series.values_mut() {
+            prices.sort_by_key(|(timestamp, _)| *timestamp);
+        }
+        Self {
+            name: "prices".to_string(),
+            series,
+            floors: HashMap::new(),
+        }
+    }
+
+    /// Load `timestamp,asset,price` rows from a `.csv` file, or an array
+    /// of such objects from JSON.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let invalid = |e: &dyn std::fmt::Display| anyhow::anyhow!("Invalid price file {}: {}", path.display(), e);
+        let points: Vec<PricePoint> = if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("csv")) {
+            csv::Reader::from_path(path)
+                .map_err(|e| invalid(&e))?
+                .deserialize()
+                .collect::<Result<_, _>>()
+                .map_err(|e| invalid(&e))?
+        } else {
+            let bytes = std::fs::read(path).map_err(|e| anyhow::anyhow!("Cannot read price file {}: {}", path.display(), e))?;
+            serde_json::from_slice(&bytes).map_err(|e| invalid(&e))?
+        };
+
+        let source = Self::new(points);
+        if !source.series.contains_key(BTC) {
+            return Err(invalid(&"no btc prices"));
+        }
+        Ok(Self {
+            name: path.display().to_string(),
+            ..source
+        })
+    }
+
+    /// Fallback floors for tokens the file has no prices for.
+    pub fn with_floors(mut self, floors: HashMap<String, f64>) -> Self {
+        self.floors = normalize_floors(&floors);
+        self
+    }
+
+    fn price_at(&self, asset: &str, timestamp: u64) -> Option<f64> {
+        let prices = self.series.get(asset)?;
+        let index = prices.partition_point(|(t, _)| *t <= timestamp);
+        Some(prices.get(index.checked_sub(1)?)?.1)
+    }
+}
+
+#[async_trait]
+impl PriceSource for FilePrice {
+    fn kind(&self) -> &'static str {
+        "file"
+    }
+
+    async fn btc_usd(&self, timestamp: u64) -> anyhow::Result<f64> {
+        self.price_at(BTC, timestamp)
+            .ok_or_else(|| anyhow::anyhow!("No BTC/USD price at or before {} in {}", timestamp, self.name))
+    }
+
+    fn floor_sats(&self, token: &str, timestamp: u64) -> Option<f64> {
+        self.price_at(token, timestamp).or_else(|| self.floors.get(token).copied())
+    }
+}
+
+/// Prices from a mempool.space-style API: `/prices` for the latest and
+/// `/historical-price?currency=USD&timestamp=` for the past. One request
+/// is made at a time, and none for a while after one fails, so an outage
+/// doesn't hold up every transaction for the request timeout.
+pub struct HttpPrice {
+    client: reqwest::Client,
+    url: String,
+    floors: HashMap<String, f64>,
+    latest: Mutex<Option<(Instant, f64)>>,
+    history: Mutex<HashMap<u64, f64>>,
+    fetching: tokio::sync::Mutex<()>,
+    /// When to try again after a failure, and the backoff that was used.
+    failure: Mutex<Option<(Instant, Duration)>>,
+}
+
+impl HttpPrice {
+    pub fn new(url: &str) -> Self {
+        Self {
+            client: reqwest::Client::builder().timeout(HTTP_TIMEOUT).build().unwrap_or_default(),
+            url: url.trim_end_matches('/').to_string(),
+            floors: HashMap::new(),
+            latest: Mutex::new(None),
+            history: Mutex::new(HashMap::new()),
+            fetching: tokio::sync::Mutex::new(()),
+            failure: Mutex::new(None),
+        }
+    }
+
+    /// Token floor prices in sats; the API only serves BTC/USD.
+    pub fn with_floors(mut self, floors: HashMap<String, f64>) -> Self {
+        self.floors = normalize_floors(&floors);
+        self
+    }
+
+    async fn get(&self, endpoint: &'static str, query: &[(&str, String)]) -> anyhow::Result<serde_json::Value> {
+        let m = metrics::metrics();
+        m.upstream_requests.with_label_values(&["price", endpoint]).inc();
+        let result = self.client.get(format!("{}/{}", self.url, endpoint)).query(query).send().await;
+        let resp = match result.and_then(|r| r.error_for_status()) {
+            Ok(resp) => resp,
+            Err(e) => {
+                m.upstream_errors.with_label_values(&["price", endpoint, "network"]).inc();
+                anyhow::bail!("Price request to {} failed: {}", endpoint, e);
+            }
+        };
+        Ok(resp.json().await?)
+    }
+
+    fn is_latest(timestamp: u64) -> bool {
+        timestamp + LATEST_WINDOW_SECS >= unix_now()
+    }
+
+    fn cached(&self, timestamp: u64) -> Option<f64> {
+        if Self::is_latest(timestamp) {
+            let (fetched, price) = (*self.latest.lock().unwrap())?;
+            return (fetched.elapsed() < LATEST_TTL).then_some(price);
+        }
+        self.history.lock().unwrap().get(&(timestamp / HISTORY_BUCKET_SECS)).copied()
+    }
+
+    async fn fetch(&self, timestamp: u64) -> anyhow::Result<f64> {
+        if Self::is_latest(timestamp) {
+            let body = self.get("prices", &[]).await?;
+            let price = body["USD"].as_f64().ok_or_else(|| anyhow::anyhow!("Price API returned no USD price"))?;
+            *self.latest.lock().unwrap() = Some((Instant::now(), price));
+            return Ok(price);
+        }
+
+        let query = [("currency", "USD".to_string()), ("timestamp", timestamp.to_string())];
+        let body = self.get("historical-price", &query).await?;
+        let price = body["prices"][0]["USD"]
+            .as_f64()
+            .ok_or_else(|| anyhow::anyhow!("Price API returned no USD price for {}", timestamp))?;
+        self.history.lock().unwrap().insert(timestamp / HISTORY_BUCKET_SECS, price);
+        Ok(price)
+    }
+}
+
+#[async_trait]
+impl PriceSource for HttpPrice {
+    fn kind(&self) -> &'static str {
+        "http"
+    }
+
+    async fn btc_usd(&self, timestamp: u64) -> anyhow::Result<f64> {
+        if let Some(price) = self.cached(timestamp) {
+            return Ok(price);
+        }
+
+        // Another lookup may have fetched the price, or failed, meanwhile
+        let _fetching = self.fetching.lock().await;
+        if let Some(price) = self.cached(timestamp) {
+            return Ok(price);
+        }
+        if let Some((retry_at, _)) = *self.failure.lock().unwrap() {
+            let wait = retry_at.saturating_duration_since(Instant::now());
+            if !wait.is_zero() {
+                anyhow::bail!("Price API unavailable, retrying in {}s", wait.as_secs() + 1);
+            }
+        }
+
+        let result = self.fetch(timestamp).await;
+        let mut failure = self.failure.lock().unwrap();
+        *failure = match &result {
+            Ok(_) => None,
+            Err(_) => {
+                let backoff = failure.map_or(MIN_FAILURE_BACKOFF, |(_, backoff)| (backoff * 2).min(MAX_FAILURE_BACKOFF));
+                Some((Instant::now() + backoff, backoff))
+            }
+        };
+        result
+    }
+
+    fn floor_sats(&self, token: &str, _timestamp: u64) -> Option<f64> {
+        self.floors.get(token).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn failed_http_lookups_back_off() {
+        // Nothing listens on port 9 (discard), so the connection is refused
+        let prices = HttpPrice::new("http://127.0.0.1:9/api/v1");
+        let requests = || metrics::metrics().upstream_requests.with_label_values(&["price", "prices"]).get();
+
+        let before = requests();
+        assert!(prices.btc_usd(unix_now()).await.is_err());
+        let error = prices.btc_usd(unix_now()).await.unwrap_err();
+        assert!(error.to_string().contains("retrying in"), "{}", error);
+        assert_eq!(requests(), before + 1);
+    }
+
+    #[test]
+    fn floor_keys_are_canonical() {
+        assert_eq!(floor_key("brc20:ordi"), "brc20:ORDI");
+        assert_eq!(floor_key("runes:uncommon.goods"), "runes:UNCOMMONGOODS");
+        assert_eq!(floor_key("runes:840000:7"), "runes:840000:7");
+    }
+}
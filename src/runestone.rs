@@ -0,0 +1,588 @@
+// Runestone decoding, following the ord reference implementation
+use crate::Transaction;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+const OP_RETURN: u8 = 0x6a;
+const OP_13: u8 = 0x5d;
+
+// Field tags. Even tags that aren't understood make the runestone a cenotaph.
+const TAG_BODY: u128 = 0;
+const TAG_DIVISIBILITY: u128 = 1;
+const TAG_FLAGS: u128 = 2;
+const TAG_SPACERS: u128 = 3;
+const TAG_RUNE: u128 = 4;
+const TAG_SYMBOL: u128 = 5;
+const TAG_PREMINE: u128 = 6;
+const TAG_CAP: u128 = 8;
+const TAG_AMOUNT: u128 = 10;
+const TAG_HEIGHT_START: u128 = 12;
+const TAG_HEIGHT_END: u128 = 14;
+const TAG_OFFSET_START: u128 = 16;
+const TAG_OFFSET_END: u128 = 18;
+const TAG_MINT: u128 = 20;
+const TAG_POINTER: u128 = 22;
+
+const FLAG_ETCHING: u128 = 1 << 0;
+const FLAG_TERMS: u128 = 1 << 1;
+const FLAG_TURBO: u128 = 1 << 2;
+
+const MAX_DIVISIBILITY: u128 = 38;
+const MAX_SPACERS: u128 = 0b0000_0111_1111_1111_1111_1111_1111_1111;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct RuneId {
+    pub block: u64,
+    pub tx: u32,
+}
+
+impl fmt::Display for RuneId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.block, self.tx)
+    }
+}
+
+impl std::str::FromStr for RuneId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (block, tx) = s.split_once(':').ok_or_else(|| anyhow::anyhow!("Invalid rune id"))?;
+        Ok(Self {
+            block: block.parse()?,
+            tx: tx.parse()?,
+        })
+    }
+}
+
+impl RuneId {
+    /// An id, unless it points at a transaction in block 0, which ord
+    /// rejects (`0:0` stands for the rune etched by the same runestone).
+    fn new(block: u64, tx: u32) -> Option<Self> {
+        (block > 0 || tx == 0).then_some(Self { block, tx })
+    }
+
+    /// Apply an edict's delta-encoded id to the previous one.
+    fn next(self, block: u128, tx: u128) -> Option<Self> {
+        let block = self.block.checked_add(u64::try_from(block).ok()?)?;
+        let tx = if block == self.block {
+            self.tx.checked_add(u32::try_from(tx).ok()?)?
+        } else {
+            u32::try_from(tx).ok()?
+        };
+        Self::new(block, tx)
+    }
+}
+
+/// Render a rune name from its integer encoding, inserting `•` wherever the
+/// spacers bitfield has a bit set.
+pub fn rune_name(rune: u128, spacers: u32) -> String {
+    let mut n = rune.saturating_add(1);
+    let mut letters = Vec::new();
+    while n > 0 {
+        letters.push(b'A' + ((n - 1) % 26) as u8);
+        n = (n - 1) / 26;
+    }
+    letters.reverse();
+
+    let mut name = String::new();
+    for (i, letter) in letters.iter().enumerate() {
+        name.push(*letter as char);
+        if i + 1 < letters.len() && spacers & (1 << i) != 0 {
+            name.push('•');
+        }
+    }
+    name
+}
+
+/// Strip spacers so `UNCOMMON•GOODS`, `UNCOMMON.GOODS` and `uncommongoods`
+/// all compare equal.
+pub fn normalize_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_ascii_alphabetic())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Edict {
+    pub id: RuneId,
+    pub amount: u128,
+    pub output: u32,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Terms {
+    pub cap: Option<u128>,
+    pub amount: Option<u128>,
+    pub height: (Option<u64>, Option<u64>),
+    pub offset: (Option<u64>, Option<u64>),
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Etching {
+    /// Spaced rune name, or `None` when the etcher left it to be assigned.
+    pub rune: Option<String>,
+    pub divisibility: Option<u8>,
+    pub premine: Option<u128>,
+    pub symbol: Option<char>,
+    pub terms: Option<Terms>,
+    pub turbo: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Runestone {
+    pub edicts: Vec<Edict>,
+    pub etching: Option<Etching>,
+    pub mint: Option<RuneId>,
+    pub pointer: Option<u32>,
+}
+
+/// A malformed runestone. All runes input to a transaction carrying a
+/// cenotaph are burned, though any etching or mint it names still counts.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Cenotaph {
+    pub flaws: Vec<String>,
+    pub etching: Option<String>,
+    pub mint: Option<RuneId>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Artifact {
+    Runestone(Runestone),
+    Cenotaph(Cenotaph),
+}
+
+impl Artifact {
+    pub fn mint(&self) -> Option<RuneId> {
+        match self {
+            Artifact::Runestone(r) => r.mint,
+            Artifact::Cenotaph(c) => c.mint,
+        }
+    }
+
+    pub fn is_cenotaph(&self) -> bool {
+        matches!(self, Artifact::Cenotaph(_))
+    }
+}
+
+/// Index of the output carrying the transaction's runestone. Only the first
+/// `OP_RETURN OP_13` output counts.
+pub fn runestone_output(tx: &Transaction) -> Option<usize> {
+    tx.vout.iter().position(|out| out.scriptpubkey.starts_with("6a5d"))
+}
+
+/// Decode the transaction's runestone, if it has one.
+pub fn decipher(tx: &Transaction) -> Option<Artifact> {
+    let idx = runestone_output(tx)?;
+    let script = hex::decode(&tx.vout[idx].scriptpubkey).ok()?;
+
+    let mut flaws = Vec::new();
+    let payload = match payload(&script) {
+        Ok(payload) => payload,
+        Err(flaw) => {
+            return Some(Artifact::Cenotaph(Cenotaph {
+                flaws: vec![flaw.to_string()],
+                ..Default::default()
+            }))
+        }
+    };
+
+    let integers = match integers(&payload) {
+        Ok(integers) => integers,
+        Err(flaw) => {
+            return Some(Artifact::Cenotaph(Cenotaph {
+                flaws: vec![flaw.to_string()],
+                ..Default::default()
+            }))
+        }
+    };
+
+    let mut fields: HashMap<u128, VecDeque<u128>> = HashMap::new();
+    let mut edicts = Vec::new();
+    let mut i = 0;
+
+    while i < integers.len() {
+        let tag = integers[i];
+
+        if tag == TAG_BODY {
+            let mut id = RuneId { block: 0, tx: 0 };
+            let body = &integers[i + 1..];
+            for chunk in body.chunks(4) {
+                if chunk.len() != 4 {
+                    flaws.push("trailing integers".to_string());
+                    break;
+                }
+                let Some(next) = id.next(chunk[0], chunk[1]) else {
+                    flaws.push("edict rune id".to_string());
+                    break;
+                };
+                let output = match u32::try_from(chunk[3]) {
+                    Ok(output) if (output as usize) <= tx.vout.len() => output,
+                    _ => {
+                        flaws.push("edict output".to_string());
+                        break;
+                    }
+                };
+                id = next;
+                edicts.push(Edict {
+                    id,
+                    amount: chunk[2],
+                    output,
+                });
+            }
+            break;
+        }
+
+        match integers.get(i + 1) {
+            Some(value) => fields.entry(tag).or_default().push_back(*value),
+            None => {
+                flaws.push("truncated field".to_string());
+                break;
+            }
+        }
+        i += 2;
+    }
+
+    let flags = take(&mut fields, TAG_FLAGS, Some).unwrap_or(0);
+    let mut remaining_flags = flags;
+    let mut has_flag = |flag: u128| {
+        let set = remaining_flags & flag != 0;
+        remaining_flags &= !flag;
+        set
+    };
+
+    let etching = if has_flag(FLAG_ETCHING) {
+        let spacers = take(&mut fields, TAG_SPACERS, |v| (v <= MAX_SPACERS).then_some(v as u32));
+        let rune = take(&mut fields, TAG_RUNE, Some);
+        Some(Etching {
+            rune: rune.map(|r| rune_name(r, spacers.unwrap_or(0))),
+            divisibility: take(&mut fields, TAG_DIVISIBILITY, |v| (v <= MAX_DIVISIBILITY).then_some(v as u8)),
+            premine: take(&mut fields, TAG_PREMINE, Some),
+            symbol: take(&mut fields, TAG_SYMBOL, |v| char::from_u32(u32::try_from(v).ok()?)),
+            terms: has_flag(FLAG_TERMS).then(|| Terms {
+                cap: take(&mut fields, TAG_CAP, Some),
+                amount: take(&mut fields, TAG_AMOUNT, Some),
+                height: (
+                    take(&mut fields, TAG_HEIGHT_START, |v| u64::try_from(v).ok()),
+                    take(&mut fields, TAG_HEIGHT_END, |v| u64::try_from(v).ok()),
+                ),
+                offset: (
+                    take(&mut fields, TAG_OFFSET_START, |v| u64::try_from(v).ok()),
+                    take(&mut fields, TAG_OFFSET_END, |v| u64::try_from(v).ok()),
+                ),
+            }),
+            turbo: has_flag(FLAG_TURBO),
+        })
+    } else {
+        None
+    };
+
+    let mint = take2(&mut fields, TAG_MINT, |block, tx| {
+        RuneId::new(u64::try_from(block).ok()?, u32::try_from(tx).ok()?)
+    });
+
+    let pointer = take(&mut fields, TAG_POINTER, |v| {
+        let pointer = u32::try_from(v).ok()?;
+        ((pointer as usize) < tx.vout.len()).then_some(pointer)
+    });
+
+    if let Some(etching) = &etching {
+        let cap = etching.terms.as_ref().and_then(|t| t.cap).unwrap_or(0);
+        let amount = etching.terms.as_ref().and_then(|t| t.amount).unwrap_or(0);
+        let supply = cap
+            .checked_mul(amount)
+            .and_then(|minted| minted.checked_add(etching.premine.unwrap_or(0)));
+        if supply.is_none() {
+            flaws.push("supply overflow".to_string());
+        }
+    }
+
+    if remaining_flags != 0 {
+        flaws.push("unrecognized flag".to_string());
+    }
+
+    if fields.keys().any(|tag| tag % 2 == 0) {
+        flaws.push("unrecognized even tag".to_string());
+    }
+
+    if flaws.is_empty() {
+        Some(Artifact::Runestone(Runestone {
+            edicts,
+            etching,
+            mint,
+            pointer,
+        }))
+    } else {
+        Some(Artifact::Cenotaph(Cenotaph {
+            flaws,
+            etching: etching.and_then(|e| e.rune),
+            mint,
+        }))
+    }
+}
+
+/// Remove a single-valued field, leaving it in place when the value is invalid
+/// so that it is later reported as an unrecognized even tag.
+fn take<T>(fields: &mut HashMap<u128, VecDeque<u128>>, tag: u128, with: impl Fn(u128) -> Option<T>) -> Option<T> {
+    let values = fields.get_mut(&tag)?;
+    let value = with(*values.front()?)?;
+    values.pop_front();
+    if values.is_empty() {
+        fields.remove(&tag);
+    }
+    Some(value)
+}
+
+fn take2<T>(fields: &mut HashMap<u128, VecDeque<u128>>, tag: u128, with: impl Fn(u128, u128) -> Option<T>) -> Option<T> {
+    let values = fields.get_mut(&tag)?;
+    if values.len() < 2 {
+        return None;
+    }
+    let value = with(values[0], values[1])?;
+    values.drain(..2);
+    if values.is_empty() {
+        fields.remove(&tag);
+    }
+    Some(value)
+}
+
+/// Concatenate the data pushes following `OP_RETURN OP_13`.
+fn payload(script: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let mut payload = Vec::new();
+    let mut i = 2;
+
+    if script.len() < 2 || script[0] != OP_RETURN || script[1] != OP_13 {
+        return Err("invalid script");
+    }
+
+    while i < script.len() {
+        let opcode = script[i];
+        i += 1;
+
+        let len = match opcode {
+            0x00 => 0,
+            0x01..=0x4b => opcode as usize,
+            0x4c => {
+                let len = *script.get(i).ok_or("invalid script")? as usize;
+                i += 1;
+                len
+            }
+            0x4d => {
+                let bytes = script.get(i..i + 2).ok_or("invalid script")?;
+                i += 2;
+                u16::from_le_bytes([bytes[0], bytes[1]]) as usize
+            }
+            0x4e => {
+                let bytes = script.get(i..i + 4).ok_or("invalid script")?;
+                i += 4;
+                u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize
+            }
+            _ => return Err("opcode"),
+        };
+
+        let data = script.get(i..i + len).ok_or("invalid script")?;
+        payload.extend_from_slice(data);
+        i += len;
+    }
+
+    Ok(payload)
+}
+
+/// Split the payload into LEB128-encoded integers.
+fn integers(payload: &[u8]) -> Result<Vec<u128>, &'static str> {
+    let mut integers = Vec::new();
+    let mut i = 0;
+
+    while i < payload.len() {
+        let (value, len) = decode_varint(&payload[i..])?;
+        integers.push(value);
+        i += len;
+    }
+
+    Ok(integers)
+}
+
+pub fn decode_varint(bytes: &[u8]) -> Result<(u128, usize), &'static str> {
+    let mut n = 0u128;
+
+    for (i, &byte) in bytes.iter().enumerate() {
+        if i > 18 {
+            return Err("varint");
+        }
+
+        let value = u128::from(byte & 0x7f);
+        if i == 18 && value & 0x7c != 0 {
+            return Err("varint");
+        }
+
+        n |= value << (7 * i);
+
+        if byte & 0x80 == 0 {
+            return Ok((n, i + 1));
+        }
+    }
+
+    Err("varint")
+}
+
+pub fn encode_varint(mut n: u128, out: &mut Vec<u8>) {
+    while n >> 7 > 0 {
+        out.push((n as u8 & 0x7f) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+/// Integer encoding and spacers bitfield of a rune name; the inverse of
+/// `rune_name`. Either `•` or `.` may be used as a spacer.
+pub fn rune_number(name: &str) -> Option<(u128, u32)> {
+    let mut rune = 0u128;
+    let mut spacers = 0u32;
+    let mut letters = 0;
+
+    for c in name.chars() {
+        match c {
+            'A'..='Z' => {
+                if letters > 0 {
+                    rune = rune.checked_add(1)?;
+                }
+                rune = rune.checked_mul(26)?.checked_add(u128::from(c as u8 - b'A'))?;
+                letters += 1;
+            }
+            '•' | '.' if letters > 0 => spacers |= 1 << (letters - 1),
+            _ => return None,
+        }
+    }
+
+    (letters > 0).then_some((rune, spacers))
+}
+
+/// The `OP_RETURN OP_13` output script, in hex, carrying `runestone`.
+pub fn encipher(runestone: &Runestone) -> String {
+    let mut fields: Vec<(u128, u128)> = Vec::new();
+
+    if let Some(etching) = &runestone.etching {
+        let mut flags = FLAG_ETCHING;
+        if etching.terms.is_some() {
+            flags |= FLAG_TERMS;
+        }
+        if etching.turbo {
+            flags |= FLAG_TURBO;
+        }
+        fields.push((TAG_FLAGS, flags));
+
+        if let Some((rune, spacers)) = etching.rune.as_deref().and_then(rune_number) {
+            fields.push((TAG_RUNE, rune));
+            if spacers != 0 {
+                fields.push((TAG_SPACERS, spacers.into()));
+            }
+        }
+        if let Some(divisibility) = etching.divisibility {
+            fields.push((TAG_DIVISIBILITY, divisibility.into()));
+        }
+        if let Some(symbol) = etching.symbol {
+            fields.push((TAG_SYMBOL, u32::from(symbol).into()));
+        }
+        if let Some(premine) = etching.premine {
+            fields.push((TAG_PREMINE, premine));
+        }
+        if let Some(terms) = &etching.terms {
+            let optional = [
+                (TAG_CAP, terms.cap),
+                (TAG_AMOUNT, terms.amount),
+                (TAG_HEIGHT_START, terms.height.0.map(u128::from)),
+                (TAG_HEIGHT_END, terms.height.1.map(u128::from)),
+                (TAG_OFFSET_START, terms.offset.0.map(u128::from)),
+                (TAG_OFFSET_END, terms.offset.1.map(u128::from)),
+            ];
+            fields.extend(optional.into_iter().filter_map(|(tag, value)| Some((tag, value?))));
+        }
+    }
+    if let Some(mint) = runestone.mint {
+        fields.push((TAG_MINT, mint.block.into()));
+        fields.push((TAG_MINT, mint.tx.into()));
+    }
+    if let Some(pointer) = runestone.pointer {
+        fields.push((TAG_POINTER, pointer.into()));
+    }
+
+    let mut payload = Vec::new();
+    for (tag, value) in fields {
+        encode_varint(tag, &mut payload);
+        encode_varint(value, &mut payload);
+    }
+
+    if !runestone.edicts.is_empty() {
+        encode_varint(TAG_BODY, &mut payload);
+        let mut edicts = runestone.edicts.clone();
+        edicts.sort_by_key(|e| e.id);
+        let mut previous = RuneId { block: 0, tx: 0 };
+        for edict in edicts {
+            let block = edict.id.block - previous.block;
+            let tx = if block == 0 { edict.id.tx - previous.tx } else { edict.id.tx };
+            for value in [block.into(), tx.into(), edict.amount, edict.output.into()] {
+                encode_varint(value, &mut payload);
+            }
+            previous = edict.id;
+        }
+    }
+
+    let mut script = vec![OP_RETURN, OP_13];
+    for chunk in payload.chunks(0x4b) {
+        script.push(chunk.len() as u8);
+        script.extend_from_slice(chunk);
+    }
+    hex::encode(script)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transaction(runestone: &Runestone) -> Transaction {
+        serde_json::from_value(serde_json::json!({
+            "txid": "00".repeat(32),
+            "size": 200,
+            "fee": null,
+            "status": { "confirmed": false },
+            "vin": [],
+            "vout": [
+                { "scriptpubkey": encipher(runestone), "scriptpubkey_address": null, "value": 0 },
+                { "scriptpubkey": "0014".to_string() + &"11".repeat(20), "scriptpubkey_address": null, "value": 546 },
+            ],
+        }))
+        .unwrap()
+    }
+
+    fn edict(block: u64, tx: u32) -> Runestone {
+        Runestone {
+            edicts: vec![Edict { id: RuneId { block, tx }, amount: 100, output: 1 }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn edict_ids_are_delta_decoded() {
+        let Some(Artifact::Runestone(runestone)) = decipher(&transaction(&edict(840000, 3))) else {
+            panic!("expected a runestone");
+        };
+        assert_eq!(runestone.edicts[0].id, RuneId { block: 840000, tx: 3 });
+    }
+
+    #[test]
+    fn edict_in_block_zero_is_a_cenotaph() {
+        assert!(decipher(&transaction(&edict(0, 0))).is_some_and(|a| !a.is_cenotaph()));
+        let Some(Artifact::Cenotaph(cenotaph)) = decipher(&transaction(&edict(0, 1))) else {
+            panic!("expected a cenotaph");
+        };
+        assert_eq!(cenotaph.flaws, ["edict rune id"]);
+    }
+
+    #[test]
+    fn mint_in_block_zero_is_a_cenotaph() {
+        let runestone = Runestone { mint: Some(RuneId { block: 0, tx: 1 }), ..Default::default() };
+        assert!(decipher(&transaction(&runestone)).is_some_and(|a| a.is_cenotaph()));
+    }
+}
@@ -0,0 +1,523 @@
+// Where the monitor gets its transactions from
+use crate::config::{BackendConfig, BackendKind, MempoolSync, Network, ScanConfig};
+use crate::{metrics, SourceError, Transaction};
+use async_trait::async_trait;
+use futures_util::future;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+pub mod blockfile;
+pub mod demo;
+pub mod electrum;
+pub mod esplora;
+pub mod p2p;
+pub mod replay;
+pub mod rpc;
+
+pub use blockfile::BlockFileSource;
+pub use demo::DemoSource;
+pub use electrum::ElectrumSource;
+pub use p2p::P2pSource;
+pub use replay::ReplaySource;
+pub use rpc::CoreRpcSource;
+
+/// First and longest pause after a source reports a rate limit.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Connected blocks remembered for spotting reorgs.
+const REORG_DEPTH: usize = 12;
+
+/// A connection a streaming backend talks over: TCP, TLS or an in-memory pipe.
+pub(crate) trait ByteStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ByteStream for T {}
+
+/// A stream of source events; an error reports a failed pass and the
+/// stream carries on.
+pub type EventStream<T> = BoxStream<'static, anyhow::Result<T>>;
+
+#[derive(Debug, Clone)]
+pub enum MempoolEvent {
+    /// A transaction not seen in the mempool before.
+    Added(Transaction),
+    /// A transaction that left the mempool since the previous pass:
+    /// confirmed, replaced or evicted. Only sources that see the whole
+    /// mempool report these.
+    Removed(String),
+    /// A pass over the mempool completed.
+    Synced,
+}
+
+/// A block and the transactions taken from it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Block {
+    pub height: u32,
+    pub hash: String,
+    /// In block order, unless each sets its `status.block_index`.
+    pub transactions: Vec<Transaction>,
+}
+
+#[derive(Debug, Clone)]
+pub enum BlockEvent {
+    Connected(Block),
+    /// A block that was reorganized out of the best chain.
+    Disconnected { height: u32, hash: String },
+    /// The source has caught up with the chain tip.
+    Synced,
+}
+
+/// A backend the monitor can fetch transactions and chain state from.
+#[async_trait]
+pub trait TransactionSource: Send + Sync {
+    /// Short name used in metrics and health reports.
+    fn kind(&self) -> &'static str;
+
+    /// Where the source reads from, for display.
+    fn url(&self) -> &str;
+
+    /// Chain the source is on, which sets the address encoding of the
+    /// transactions it decodes.
+    fn network(&self) -> Network {
+        Network::Mainnet
+    }
+
+    async fn get_transaction(&self, txid: &str) -> anyhow::Result<Transaction>;
+
+    async fn tip_height(&self) -> anyhow::Result<u32>;
+
+    /// Transactions as they enter the mempool.
+    fn mempool_additions(self: Arc<Self>, policy: ScanConfig) -> EventStream<MempoolEvent>;
+
+    /// Blocks as they are connected to and disconnected from the best chain,
+    /// starting with the block after `after` (the last one processed) or
+    /// at the tip if that is unknown.
+    fn block_events(self: Arc<Self>, policy: ScanConfig, after: Option<u32>) -> EventStream<BlockEvent>;
+
+    /// Fill in missing prevouts by fetching the parent transactions, then
+    /// compute the fee if every input is known. Failures leave gaps.
+    async fn resolve_prevouts(&self, tx: &mut Transaction) {
+        let mut parents: HashMap<String, Option<Transaction>> = HashMap::new();
+
+        for input in tx.vin.iter_mut().filter(|i| i.prevout.is_none()) {
+            if input.txid.bytes().all(|b| b == b'0') {
+                continue; // coinbase
+            }
+            if !parents.contains_key(&input.txid) {
+                let parent = self.get_transaction(&input.txid).await.ok();
+                parents.insert(input.txid.clone(), parent);
+            }
+            input.prevout = parents[&input.txid]
+                .as_ref()
+                .and_then(|p| p.vout.get(input.vout as usize).cloned());
+        }
+
+        if tx.fee.is_none() && tx.vin.iter().all(|i| i.prevout.is_some()) {
+            let inputs: u64 = tx.vin.iter().filter_map(|i| i.prevout.as_ref()).map(|p| p.value).sum();
+            let outputs: u64 = tx.vout.iter().map(|o| o.value).sum();
+            tx.fee = inputs.checked_sub(outputs);
+        }
+    }
+}
+
+/// The source `backend` describes.
+pub fn from_config(backend: &BackendConfig) -> anyhow::Result<Arc<dyn TransactionSource>> {
+    Ok(match backend.kind {
+        BackendKind::Esplora => Arc::new(crate::BitcoinClient::from_config(backend)),
+        BackendKind::Rpc => Arc::new(CoreRpcSource::from_config(backend)?),
+        BackendKind::Replay => {
+            let path = backend.path.as_ref().ok_or_else(|| anyhow::anyhow!("backend.path is required for replay"))?;
+            Arc::new(ReplaySource::from_file(path)?)
+        }
+        BackendKind::Blockfile => Arc::new(BlockFileSource::from_config(backend)?),
+        BackendKind::P2p => Arc::new(P2pSource::from_config(backend)),
+        BackendKind::Electrum => Arc::new(ElectrumSource::from_config(backend)?),
+    })
+}
+
+/// A request/response backend, turned into event streams by polling.
+#[async_trait]
+pub trait Poller: Send + Sync + 'static {
+    async fn get_transaction(&self, txid: &str) -> anyhow::Result<Transaction>;
+
+    /// Up to `limit` of the most recent mempool txids.
+    async fn recent_mempool_txids(&self, limit: usize) -> anyhow::Result<Vec<String>>;
+
+    /// Every txid in the mempool.
+    async fn mempool_txids(&self) -> anyhow::Result<Vec<String>>;
+
+    /// Height and hash of the best block.
+    async fn tip(&self) -> anyhow::Result<(u32, String)>;
+
+    /// Hash of the best-chain block at `height`.
+    async fn block_hash(&self, height: u32) -> anyhow::Result<String>;
+
+    /// Every transaction of a block, in block order.
+    async fn block_transactions(&self, hash: &str, height: u32, policy: &ScanConfig) -> anyhow::Result<Vec<Transaction>>;
+}
+
+/// Fetch up to `policy.fetch_concurrency` items from the front of `queue`
+/// at once. Rate limited items go back on the queue after `backoff`, which
+/// doubles each time; otherwise it resets and the batch is followed by
+/// `policy.request_delay()`. Returns the other results, in queue order.
+pub(crate) async fn fetch_batch<T, R, F, Fut>(
+    queue: &mut VecDeque<T>,
+    backoff: &mut Duration,
+    policy: &ScanConfig,
+    fetch: F,
+) -> Vec<(T, anyhow::Result<R>)>
+where
+    F: Fn(&T) -> Fut,
+    Fut: Future<Output = anyhow::Result<R>>,
+{
+    let size = policy.fetch_concurrency.min(queue.len());
+    let batch: Vec<T> = queue.drain(..size).collect();
+    let results = future::join_all(batch.iter().map(&fetch)).await;
+
+    let mut done = Vec::new();
+    let mut limited = Vec::new();
+    for (item, result) in batch.into_iter().zip(results) {
+        match result {
+            Err(e) if SourceError::classify(&e) == Some(&SourceError::RateLimited) => limited.push(item),
+            result => done.push((item, result)),
+        }
+    }
+
+    if limited.is_empty() {
+        *backoff = Duration::ZERO;
+        tokio::time::sleep(policy.request_delay()).await;
+    } else {
+        *backoff = (*backoff * 2).clamp(MIN_BACKOFF, MAX_BACKOFF);
+        tracing::warn!(retrying = limited.len(), backoff_secs = backoff.as_secs(), "Rate limited, backing off");
+        for item in limited.into_iter().rev() {
+            queue.push_front(item);
+        }
+        tokio::time::sleep(*backoff).await;
+    }
+    done
+}
+
+/// Poll the mempool every `policy.mempool_interval()`, emitting the
+/// transactions that were not there on the previous pass and, in full sync
+/// mode, the ones that have gone. New transactions are fetched
+/// `policy.fetch_concurrency` at a time; rate limited fetches are retried
+/// after a growing backoff, and other failed fetches on the next pass.
+pub fn poll_mempool<P: Poller + TransactionSource>(poller: Arc<P>, policy: ScanConfig) -> EventStream<MempoolEvent> {
+    struct State<P> {
+        poller: Arc<P>,
+        policy: ScanConfig,
+        seen: HashSet<String>,
+        queue: VecDeque<String>,
+        pending: VecDeque<MempoolEvent>,
+        backoff: Duration,
+        pass: Option<Instant>,
+        first: bool,
+    }
+
+    impl<P: Poller + TransactionSource> State<P> {
+        async fn list(&self) -> anyhow::Result<Vec<String>> {
+            match self.policy.mempool_sync {
+                MempoolSync::Recent => self.poller.recent_mempool_txids(self.policy.mempool_tx_limit).await,
+                MempoolSync::Full => self.poller.mempool_txids().await,
+            }
+        }
+
+        async fn fetch_batch(&mut self) {
+            let poller = self.poller.clone();
+            let results = fetch_batch(&mut self.queue, &mut self.backoff, &self.policy, |txid: &String| {
+                let poller = poller.clone();
+                let txid = txid.clone();
+                async move { Poller::get_transaction(&*poller, &txid).await }
+            })
+            .await;
+
+            for (txid, result) in results {
+                match result {
+                    Ok(tx) => self.pending.push_back(MempoolEvent::Added(tx)),
+                    Err(e) => match SourceError::classify(&e) {
+                        // Usually a transaction that left the mempool in the meantime
+                        Some(SourceError::NotFound) => tracing::debug!(%txid, "Transaction left the mempool before it was fetched"),
+                        source_error => {
+                            let kind = source_error.map(SourceError::code).unwrap_or("invalid_response");
+                            let labels = [TransactionSource::kind(&*self.poller), "mempool_transaction", kind];
+                            metrics::metrics().upstream_errors.with_label_values(&labels).inc();
+                            tracing::warn!(%txid, error = %e, "Failed to fetch transaction, retrying on the next pass");
+                            // Forgetting it makes the next listing queue it again
+                            self.seen.remove(&txid);
+                        }
+                    },
+                }
+            }
+        }
+    }
+
+    let state = State {
+        poller,
+        policy,
+        seen: HashSet::new(),
+        queue: VecDeque::new(),
+        pending: VecDeque::new(),
+        backoff: Duration::ZERO,
+        pass: None,
+        first: true,
+    };
+
+    stream::unfold(state, |mut s| async move {
+        loop {
+            if let Some(event) = s.pending.pop_front() {
+                return Some((Ok(event), s));
+            }
+            if !s.queue.is_empty() {
+                s.fetch_batch().await;
+                continue;
+            }
+            if let Some(started) = s.pass.take() {
+                metrics::metrics().scan_duration.with_label_values(&["mempool"]).observe(started.elapsed().as_secs_f64());
+                return Some((Ok(MempoolEvent::Synced), s));
+            }
+
+            if !s.first {
+                tokio::time::sleep(s.policy.mempool_interval()).await;
+            }
+            s.first = false;
+
+            let started = Instant::now();
+            match s.list().await {
+                Ok(txids) => {
+                    let current: HashSet<String> = txids.iter().cloned().collect();
+                    // A window of recent txids says nothing about what left the mempool
+                    if s.policy.mempool_sync == MempoolSync::Full {
+                        metrics::metrics().mempool_size.set(current.len() as i64);
+                        s.pending.extend(s.seen.difference(&current).cloned().map(MempoolEvent::Removed));
+                    }
+                    s.queue = txids.into_iter().filter(|t| !s.seen.contains(t)).collect();
+                    s.seen = current;
+                    s.pass = Some(started);
+                    tracing::info!(count = s.queue.len(), "Scanning mempool transactions");
+                }
+                Err(e) => return Some((Err(e), s)),
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Poll the chain tip every `policy.block_interval()` and connect every
+/// block from the one after `after` up to the tip, at most
+/// `policy.max_catchup_blocks` of them. Blocks that dropped out of the best
+/// chain are disconnected first, newest first.
+pub fn poll_blocks<P: Poller>(poller: Arc<P>, policy: ScanConfig, after: Option<u32>) -> EventStream<BlockEvent> {
+    struct State<P> {
+        poller: Arc<P>,
+        policy: ScanConfig,
+        after: Option<u32>,
+        /// Recently connected blocks, newest last.
+        chain: VecDeque<(u32, String)>,
+        /// Height of the next block to connect, once known.
+        next: Option<u32>,
+        /// The tip the current pass is catching up to.
+        target: Option<(u32, String)>,
+        pending: VecDeque<BlockEvent>,
+        pass: Option<Instant>,
+        first: bool,
+    }
+
+    impl<P: Poller> State<P> {
+        /// Find the tip, queue disconnects for blocks no longer in the best
+        /// chain and decide where to resume.
+        async fn start_pass(&mut self) -> anyhow::Result<()> {
+            let (tip_height, tip_hash) = self.poller.tip().await?;
+
+            if self.chain.back() != Some(&(tip_height, tip_hash.clone())) {
+                while let Some((height, hash)) = self.chain.back().cloned() {
+                    if height <= tip_height && self.poller.block_hash(height).await? == hash {
+                        break;
+                    }
+                    self.chain.pop_back();
+                    self.pending.push_back(BlockEvent::Disconnected { height, hash });
+                    self.next = Some(height);
+                }
+            }
+
+            let mut next = self.next.unwrap_or_else(|| self.after.map_or(tip_height, |h| h + 1));
+            let behind = (tip_height + 1).saturating_sub(next);
+            if behind > self.policy.max_catchup_blocks {
+                next = tip_height + 1 - self.policy.max_catchup_blocks;
+                tracing::warn!(skipped = behind - self.policy.max_catchup_blocks, from = next, "Too far behind, skipping older blocks");
+            }
+            if behind > 1 {
+                tracing::info!(from = next, to = tip_height, "Catching up on missed blocks");
+            }
+
+            self.next = Some(next);
+            self.target = Some((tip_height, tip_hash));
+            Ok(())
+        }
+
+        async fn fetch_block(&self, height: u32) -> anyhow::Result<Block> {
+            let hash = match &self.target {
+                Some((tip_height, tip_hash)) if *tip_height == height => tip_hash.clone(),
+                _ => self.poller.block_hash(height).await?,
+            };
+            let transactions = self.poller.block_transactions(&hash, height, &self.policy).await?;
+            tracing::info!(count = transactions.len(), height, "Fetched block transactions");
+            Ok(Block { height, hash, transactions })
+        }
+    }
+
+    let state = State {
+        poller,
+        policy,
+        after,
+        chain: VecDeque::new(),
+        next: None,
+        target: None,
+        pending: VecDeque::new(),
+        pass: None,
+        first: true,
+    };
+
+    stream::unfold(state, |mut s| async move {
+        loop {
+            if let Some(event) = s.pending.pop_front() {
+                return Some((Ok(event), s));
+            }
+
+            if let Some((tip_height, _)) = s.target {
+                let height = s.next.unwrap_or(tip_height + 1);
+                if height <= tip_height {
+                    match s.fetch_block(height).await {
+                        Ok(block) => {
+                            if s.chain.len() == REORG_DEPTH {
+                                s.chain.pop_front();
+                            }
+                            s.chain.push_back((block.height, block.hash.clone()));
+                            s.next = Some(height + 1);
+                            return Some((Ok(BlockEvent::Connected(block)), s));
+                        }
+                        Err(e) => {
+                            s.target = None;
+                            s.pass = None;
+                            return Some((Err(e), s));
+                        }
+                    }
+                }
+                s.target = None;
+                if let Some(started) = s.pass.take() {
+                    metrics::metrics().scan_duration.with_label_values(&["blocks"]).observe(started.elapsed().as_secs_f64());
+                }
+                return Some((Ok(BlockEvent::Synced), s));
+            }
+
+            if !s.first {
+                tokio::time::sleep(s.policy.block_interval()).await;
+            }
+            s.first = false;
+
+            s.pass = Some(Instant::now());
+            if let Err(e) = s.start_pass().await {
+                s.pass = None;
+                return Some((Err(e), s));
+            }
+        }
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// A mempool of one transaction whose first fetch fails.
+    struct FlakyPoller {
+        fetches: AtomicU32,
+    }
+
+    #[async_trait]
+    impl Poller for FlakyPoller {
+        async fn get_transaction(&self, txid: &str) -> anyhow::Result<Transaction> {
+            if self.fetches.fetch_add(1, Ordering::SeqCst) == 0 {
+                return Err(SourceError::Unavailable("HTTP 502".to_string()).into());
+            }
+            Ok(serde_json::from_value(serde_json::json!({
+                "txid": txid,
+                "size": 200,
+                "fee": null,
+                "status": { "confirmed": false },
+                "vin": [],
+                "vout": [],
+            }))?)
+        }
+
+        async fn recent_mempool_txids(&self, _limit: usize) -> anyhow::Result<Vec<String>> {
+            self.mempool_txids().await
+        }
+
+        async fn mempool_txids(&self) -> anyhow::Result<Vec<String>> {
+            Ok(vec!["ab".repeat(32)])
+        }
+
+        async fn tip(&self) -> anyhow::Result<(u32, String)> {
+            Ok((1, "00".repeat(32)))
+        }
+
+        async fn block_hash(&self, _height: u32) -> anyhow::Result<String> {
+            Ok("00".repeat(32))
+        }
+
+        async fn block_transactions(&self, _hash: &str, _height: u32, _policy: &ScanConfig) -> anyhow::Result<Vec<Transaction>> {
+            Ok(Vec::new())
+        }
+    }
+
+    #[async_trait]
+    impl TransactionSource for FlakyPoller {
+        fn kind(&self) -> &'static str {
+            "flaky"
+        }
+
+        fn url(&self) -> &str {
+            "flaky"
+        }
+
+        async fn get_transaction(&self, txid: &str) -> anyhow::Result<Transaction> {
+            Poller::get_transaction(self, txid).await
+        }
+
+        async fn tip_height(&self) -> anyhow::Result<u32> {
+            Ok(1)
+        }
+
+        fn mempool_additions(self: Arc<Self>, policy: ScanConfig) -> EventStream<MempoolEvent> {
+            poll_mempool(self, policy)
+        }
+
+        fn block_events(self: Arc<Self>, policy: ScanConfig, after: Option<u32>) -> EventStream<BlockEvent> {
+            poll_blocks(self, policy, after)
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_mempool_fetches_are_retried_on_the_next_pass() {
+        let policy = ScanConfig {
+            mempool_sync: MempoolSync::Full,
+            mempool_interval_secs: 0,
+            request_delay_ms: 0,
+            ..Default::default()
+        };
+        let poller = Arc::new(FlakyPoller { fetches: AtomicU32::new(0) });
+        let mut events = poller.clone().mempool_additions(policy);
+
+        assert!(matches!(events.next().await, Some(Ok(MempoolEvent::Synced))));
+        match events.next().await {
+            Some(Ok(MempoolEvent::Added(tx))) => assert_eq!(tx.txid, "ab".repeat(32)),
+            other => panic!("expected the transaction on the second pass, got {:?}", other.map(|e| e.is_ok())),
+        }
+        assert!(matches!(events.next().await, Some(Ok(MempoolEvent::Synced))));
+        assert_eq!(poller.fetches.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,589 @@
+// Blocks read straight from bitcoind's blk*.dat and rev*.dat files
+use super::{Block, BlockEvent, EventStream, MempoolEvent, TransactionSource};
+use crate::config::{BackendConfig, Network, ScanConfig};
+use crate::decode::{self, Reader};
+use crate::{leveldb, Output, SourceError, Transaction};
+use async_trait::async_trait;
+use futures_util::stream::{self, StreamExt};
+use num_bigint::BigUint;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Block index status flags.
+const BLOCK_VALID_MASK: u64 = 7;
+const BLOCK_VALID_SCRIPTS: u64 = 5;
+const BLOCK_HAVE_DATA: u64 = 8;
+const BLOCK_HAVE_UNDO: u64 = 16;
+const BLOCK_FAILED: u64 = 32 | 64;
+
+/// Where a best-chain block and its undo data are stored.
+#[derive(Debug, Clone)]
+struct BlockPos {
+    file: u32,
+    data_pos: u32,
+    undo_pos: Option<u32>,
+    time: u32,
+}
+
+/// One block index record.
+struct IndexEntry {
+    height: u32,
+    status: u64,
+    pos: Option<BlockPos>,
+    prev: [u8; 32],
+}
+
+pub struct BlockFileSource {
+    blocks_dir: PathBuf,
+    name: String,
+    xor_key: Option<[u8; 8]>,
+    /// Best-chain blocks by height; `None` where the data was pruned.
+    chain: Vec<Option<BlockPos>>,
+    start_height: Option<u32>,
+    network: Network,
+}
+
+impl BlockFileSource {
+    /// Open a datadir, or its `blocks` directory, loading the block index to
+    /// order the best chain by height. The node must not be running.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let blocks_dir = match path.join("blocks") {
+            dir if dir.is_dir() => dir,
+            _ => path.to_path_buf(),
+        };
+
+        let xor_key = match std::fs::read(blocks_dir.join("xor.dat")) {
+            Ok(key) => {
+                let key: [u8; 8] = key.try_into().map_err(|_| anyhow::anyhow!("xor.dat must hold 8 bytes"))?;
+                Some(key).filter(|k| k.iter().any(|b| *b != 0))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        let records = leveldb::read(&blocks_dir.join("index"), b"b")?;
+        let mut index = std::collections::HashMap::with_capacity(records.len());
+        for (key, value) in &records {
+            let hash: [u8; 32] = key[1..].try_into().map_err(|_| anyhow::anyhow!("Bad block index key"))?;
+            index.insert(hash, read_index_entry(value)?);
+        }
+
+        // Headers can run far ahead of the blocks downloaded, so the tip is
+        // the highest block the node has stored and connected
+        let mut tip = index
+            .iter()
+            .filter(|(_, e)| e.is_connected())
+            .max_by_key(|(_, e)| e.height)
+            .map(|(hash, _)| *hash)
+            .ok_or_else(|| anyhow::anyhow!("No block index in {}", blocks_dir.display()))?;
+
+        let mut chain = vec![None; index[&tip].height as usize + 1];
+        while let Some(entry) = index.get(&tip) {
+            chain[entry.height as usize] = entry.pos.clone();
+            if entry.height == 0 {
+                break;
+            }
+            tip = entry.prev;
+        }
+
+        tracing::info!(blocks_dir = %blocks_dir.display(), tip = chain.len() - 1, obfuscated = xor_key.is_some(), "Loaded block index");
+        Ok(Self {
+            name: path.display().to_string(),
+            blocks_dir,
+            xor_key,
+            chain,
+            start_height: None,
+            network: Network::Mainnet,
+        })
+    }
+
+    pub fn from_config(backend: &BackendConfig) -> anyhow::Result<Self> {
+        let path = backend.path.as_ref().ok_or_else(|| anyhow::anyhow!("backend.path is required for blockfile"))?;
+        Ok(Self {
+            start_height: backend.start_height,
+            network: backend.network,
+            ..Self::open(path)?
+        })
+    }
+
+    /// Height to start from when nothing has been processed yet; defaults
+    /// to the genesis block.
+    pub fn with_start_height(mut self, height: u32) -> Self {
+        self.start_height = Some(height);
+        self
+    }
+
+    fn tip(&self) -> u32 {
+        self.chain.len() as u32 - 1
+    }
+
+    /// Read a length-prefixed record from a blk or rev file, undoing the
+    /// XOR obfuscation.
+    fn read_record(&self, prefix: &str, file: u32, pos: u32) -> anyhow::Result<Vec<u8>> {
+        let path = self.blocks_dir.join(format!("{}{:05}.dat", prefix, file));
+        let mut f = File::open(&path).map_err(|e| anyhow::anyhow!("Cannot open {}: {}", path.display(), e))?;
+        let start = u64::from(pos).checked_sub(4).ok_or_else(|| anyhow::anyhow!("Bad position in {}", path.display()))?;
+
+        let mut size = [0u8; 4];
+        f.seek(SeekFrom::Start(start))?;
+        f.read_exact(&mut size)?;
+        self.unmask(&mut size, start);
+
+        let size = u32::from_le_bytes(size);
+        if u64::from(pos) + u64::from(size) > f.metadata()?.len() {
+            anyhow::bail!("Record at {} overruns {}", pos, path.display());
+        }
+        let mut data = vec![0u8; size as usize];
+        f.read_exact(&mut data)?;
+        self.unmask(&mut data, u64::from(pos));
+        Ok(data)
+    }
+
+    fn unmask(&self, data: &mut [u8], offset: u64) {
+        if let Some(key) = self.xor_key {
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte ^= key[((offset + i as u64) % 8) as usize];
+            }
+        }
+    }
+
+    /// The best-chain block at `height`, with prevouts and fees filled in
+    /// from its undo data when that is available.
+    pub fn read_block(&self, height: u32) -> anyhow::Result<Block> {
+        let pos = self
+            .chain
+            .get(height as usize)
+            .and_then(Option::as_ref)
+            .ok_or_else(|| anyhow::anyhow!("Block {} is not stored in {}", height, self.blocks_dir.display()))?;
+
+        let raw = self.read_record("blk", pos.file, pos.data_pos)?;
+        let (hash, mut transactions) = decode::decode_block(&raw, Some(height), self.network)?;
+        for tx in &mut transactions {
+            tx.status.block_time = Some(u64::from(pos.time));
+        }
+
+        if let Some(undo_pos) = pos.undo_pos {
+            let undo = read_undo(&self.read_record("rev", pos.file, undo_pos)?, self.network)?;
+            if undo.len() + 1 != transactions.len() {
+                anyhow::bail!("Undo data for block {} does not match its transactions", height);
+            }
+            for (tx, coins) in transactions.iter_mut().skip(1).zip(undo) {
+                if coins.len() != tx.vin.len() {
+                    anyhow::bail!("Undo data for {} does not match its inputs", tx.txid);
+                }
+                for (input, coin) in tx.vin.iter_mut().zip(coins) {
+                    input.prevout = Some(coin);
+                }
+                let inputs = checked_sum(tx.vin.iter().filter_map(|i| i.prevout.as_ref()).map(|p| p.value));
+                let outputs = checked_sum(tx.vout.iter().map(|o| o.value));
+                tx.fee = inputs.zip(outputs).and_then(|(inputs, outputs)| inputs.checked_sub(outputs));
+            }
+        }
+
+        Ok(Block { height, hash, transactions })
+    }
+}
+
+/// None if corrupt values overflow.
+fn checked_sum(mut values: impl Iterator<Item = u64>) -> Option<u64> {
+    values.try_fold(0u64, u64::checked_add)
+}
+
+/// Bitcoin Core's VARINT: base 128, most significant group first, with one
+/// added to every group but the last.
+fn core_varint(reader: &mut Reader) -> anyhow::Result<u64> {
+    let mut n = 0u64;
+    loop {
+        let byte = reader.u8()?;
+        n = n
+            .checked_mul(128)
+            .and_then(|n| n.checked_add(u64::from(byte & 0x7f)))
+            .ok_or_else(|| anyhow::anyhow!("VARINT overflow"))?;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+        n = n.checked_add(1).ok_or_else(|| anyhow::anyhow!("VARINT overflow"))?;
+    }
+}
+
+fn read_index_entry(value: &[u8]) -> anyhow::Result<IndexEntry> {
+    let mut reader = Reader::new(value);
+    core_varint(&mut reader)?; // client version
+    let height = core_varint(&mut reader)? as u32;
+    let status = core_varint(&mut reader)?;
+    core_varint(&mut reader)?; // transaction count
+
+    let file = match status & (BLOCK_HAVE_DATA | BLOCK_HAVE_UNDO) {
+        0 => None,
+        _ => Some(core_varint(&mut reader)? as u32),
+    };
+    let data_pos = match status & BLOCK_HAVE_DATA {
+        0 => None,
+        _ => Some(core_varint(&mut reader)? as u32),
+    };
+    let undo_pos = match status & BLOCK_HAVE_UNDO {
+        0 => None,
+        _ => Some(core_varint(&mut reader)? as u32),
+    };
+
+    let header = reader.bytes(80)?;
+    let pos = match (file, data_pos) {
+        (Some(file), Some(data_pos)) => Some(BlockPos {
+            file,
+            data_pos,
+            undo_pos,
+            time: u32::from_le_bytes(header[68..72].try_into()?),
+        }),
+        _ => None,
+    };
+
+    Ok(IndexEntry {
+        height,
+        status,
+        pos,
+        prev: header[4..36].try_into()?,
+    })
+}
+
+impl IndexEntry {
+    fn is_connected(&self) -> bool {
+        self.status & BLOCK_FAILED == 0 && self.status & BLOCK_HAVE_DATA != 0 && self.status & BLOCK_VALID_MASK >= BLOCK_VALID_SCRIPTS
+    }
+}
+
+/// The coins spent by each non-coinbase transaction of a block.
+fn read_undo(data: &[u8], network: Network) -> anyhow::Result<Vec<Vec<Output>>> {
+    let mut reader = Reader::new(data);
+    let mut undo = Vec::new();
+    for _ in 0..reader.compact_size()? {
+        let mut coins = Vec::new();
+        for _ in 0..reader.compact_size()? {
+            coins.push(read_coin(&mut reader, network)?);
+        }
+        undo.push(coins);
+    }
+    Ok(undo)
+}
+
+fn read_coin(reader: &mut Reader, network: Network) -> anyhow::Result<Output> {
+    let code = core_varint(reader)?;
+    if code >> 1 > 0 {
+        core_varint(reader)?; // version, kept by old nodes
+    }
+    let value = decompress_amount(core_varint(reader)?)?;
+    let script = decompress_script(reader)?;
+
+    Ok(Output {
+        scriptpubkey: hex::encode(&script),
+        scriptpubkey_address: decode::script_address(&script, network),
+        value,
+    })
+}
+
+fn decompress_amount(x: u64) -> anyhow::Result<u64> {
+    if x == 0 {
+        return Ok(0);
+    }
+    let mut x = x - 1;
+    let exponent = x % 10;
+    x /= 10;
+    let mut n = if exponent < 9 {
+        let digit = x % 9 + 1;
+        x /= 9;
+        x * 10 + digit
+    } else {
+        x + 1
+    };
+    for _ in 0..exponent {
+        n = n.checked_mul(10).ok_or_else(|| anyhow::anyhow!("Compressed amount overflow"))?;
+    }
+    Ok(n)
+}
+
+/// Expand Core's compressed scripts: the common templates are stored as
+/// their hash or key alone.
+fn decompress_script(reader: &mut Reader) -> anyhow::Result<Vec<u8>> {
+    let kind = core_varint(reader)?;
+    Ok(match kind {
+        0 => [&[0x76, 0xa9, 0x14][..], reader.bytes(20)?, &[0x88, 0xac]].concat(),
+        1 => [&[0xa9, 0x14][..], reader.bytes(20)?, &[0x87]].concat(),
+        2 | 3 => [&[0x21, kind as u8][..], reader.bytes(32)?, &[0xac]].concat(),
+        4 | 5 => {
+            let key = decompress_pubkey(reader.bytes(32)?, kind == 5)?;
+            [&[0x41][..], &key, &[0xac]].concat()
+        }
+        n => reader.bytes(usize::try_from(n - 6)?)?.to_vec(),
+    })
+}
+
+/// The uncompressed form of a secp256k1 public key given its x coordinate
+/// and whether y is odd.
+fn decompress_pubkey(x: &[u8], odd: bool) -> anyhow::Result<[u8; 65]> {
+    let p = BigUint::parse_bytes(b"fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f", 16).expect("valid field prime");
+    let x_num = BigUint::from_bytes_be(x);
+    let y_squared = (x_num.modpow(&BigUint::from(3u8), &p) + 7u8) % &p;
+    let mut y = y_squared.modpow(&((&p + 1u8) >> 2), &p);
+    if (&y * &y) % &p != y_squared {
+        anyhow::bail!("Stored public key is not on the curve");
+    }
+    if y.bit(0) != odd {
+        y = &p - y;
+    }
+
+    let mut key = [0u8; 65];
+    key[0] = 0x04;
+    key[1..33].copy_from_slice(x);
+    let y = y.to_bytes_be();
+    key[65 - y.len()..].copy_from_slice(&y);
+    Ok(key)
+}
+
+#[async_trait]
+impl TransactionSource for BlockFileSource {
+    fn kind(&self) -> &'static str {
+        "blockfile"
+    }
+
+    fn url(&self) -> &str {
+        &self.name
+    }
+
+    fn network(&self) -> Network {
+        self.network
+    }
+
+    /// Block files have no txid index, so lookups always fail.
+    async fn get_transaction(&self, _txid: &str) -> anyhow::Result<Transaction> {
+        Err(SourceError::NotFound.into())
+    }
+
+    async fn tip_height(&self) -> anyhow::Result<u32> {
+        Ok(self.tip())
+    }
+
+    /// Block files hold no mempool, so this only reports synced once per
+    /// mempool interval.
+    fn mempool_additions(self: Arc<Self>, policy: ScanConfig) -> EventStream<MempoolEvent> {
+        stream::iter([Ok(MempoolEvent::Synced)])
+            .chain(every(policy.mempool_interval(), || Ok(MempoolEvent::Synced)))
+            .boxed()
+    }
+
+    /// Every stored block from the one after `after`, or the start height,
+    /// up to the tip of the index, then synced once per block interval.
+    fn block_events(self: Arc<Self>, policy: ScanConfig, after: Option<u32>) -> EventStream<BlockEvent> {
+        let start = after.map(|h| h + 1).or(self.start_height).unwrap_or(0);
+        // Pruned blocks are skipped
+        let heights: Vec<u32> = (start..=self.tip()).filter(|h| self.chain[*h as usize].is_some()).collect();
+
+        stream::iter(heights)
+            .then(move |height| {
+                let source = self.clone();
+                async move {
+                    let block = tokio::task::spawn_blocking(move || source.read_block(height)).await??;
+                    Ok(BlockEvent::Connected(block))
+                }
+            })
+            .chain(stream::iter([Ok(BlockEvent::Synced)]))
+            .chain(every(policy.block_interval(), || Ok(BlockEvent::Synced)))
+            .boxed()
+    }
+}
+
+/// An endless stream yielding `event()` after each `interval`.
+fn every<T: Send + 'static>(interval: Duration, event: fn() -> T) -> impl stream::Stream<Item = T> + Send {
+    stream::unfold((), move |()| async move {
+        tokio::time::sleep(interval).await;
+        Some((event(), ()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GENESIS_HEADER: &str = "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c";
+    const G_X: &str = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    const G_Y: &str = "483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8";
+
+    fn put_core_varint(out: &mut Vec<u8>, mut n: u64) {
+        let mut bytes = vec![(n & 0x7f) as u8];
+        while n > 0x7f {
+            n = (n >> 7) - 1;
+            bytes.push((n & 0x7f) as u8 | 0x80);
+        }
+        out.extend(bytes.iter().rev());
+    }
+
+    fn index_record(height: u64, status: u64, file_pos: Option<(u64, u64, u64)>, header: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for n in [259_900, height, status, 1] {
+            put_core_varint(&mut out, n);
+        }
+        for n in file_pos.map(|(file, data, undo)| vec![file, data, undo]).unwrap_or_default() {
+            put_core_varint(&mut out, n);
+        }
+        out.extend(header);
+        out
+    }
+
+    fn header(prev: [u8; 32], time: u32) -> Vec<u8> {
+        [&1u32.to_le_bytes()[..], &prev, &[0; 32], &time.to_le_bytes(), &0x1d00_ffffu32.to_le_bytes(), &[0; 4]].concat()
+    }
+
+    fn tx(input: [u8; 32], coinbase: bool, value: u64) -> Vec<u8> {
+        let mut tx = 1u32.to_le_bytes().to_vec();
+        tx.push(1);
+        tx.extend(input);
+        tx.extend(if coinbase { u32::MAX } else { 0 }.to_le_bytes());
+        tx.extend([1, 0x51]);
+        tx.extend(u32::MAX.to_le_bytes());
+        tx.push(1);
+        tx.extend(value.to_le_bytes());
+        tx.extend([22, 0x00, 0x14]);
+        tx.extend([9; 20]);
+        tx.extend(0u32.to_le_bytes());
+        tx
+    }
+
+    /// A block file record: network magic, size, then the data.
+    fn record(data: &[u8]) -> Vec<u8> {
+        [&[0xfa, 0xbf, 0xb5, 0xda][..], &(data.len() as u32).to_le_bytes(), data].concat()
+    }
+
+    /// A write-ahead log holding one batch of puts.
+    fn index_log(entries: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+        let mut batch = 1u64.to_le_bytes().to_vec();
+        batch.extend((entries.len() as u32).to_le_bytes());
+        for (key, value) in entries {
+            batch.push(1);
+            put_core_varint(&mut batch, key.len() as u64);
+            batch.extend(key);
+            put_core_varint(&mut batch, value.len() as u64);
+            batch.extend(value);
+        }
+        [&[0; 4][..], &(batch.len() as u16).to_le_bytes(), &[1], &batch].concat()
+    }
+
+    #[test]
+    fn core_varint_vectors() {
+        for (n, encoded) in [
+            (0, "00"),
+            (0x7f, "7f"),
+            (0x80, "8000"),
+            (0x1234, "a334"),
+            (0xffff, "82fe7f"),
+            (0x123456, "c7e756"),
+            (0x8012_3456, "86ffc7e756"),
+            (0xffff_ffff, "8efefefe7f"),
+        ] {
+            let bytes = hex::decode(encoded).unwrap();
+            assert_eq!(core_varint(&mut Reader::new(&bytes)).unwrap(), n);
+            let mut out = Vec::new();
+            put_core_varint(&mut out, n);
+            assert_eq!(out, bytes);
+        }
+        assert!(core_varint(&mut Reader::new(&[0xff; 12])).is_err());
+    }
+
+    #[test]
+    fn amount_compression_vectors() {
+        for (amount, compressed) in [
+            (0, 0),
+            (1, 1),
+            (1_000_000, 7),
+            (100_000_000, 9),
+            (5_000_000_000, 50),
+            (2_100_000_000_000_000, 21_000_000),
+        ] {
+            assert_eq!(decompress_amount(compressed).unwrap(), amount);
+        }
+        assert!(decompress_amount(u64::MAX).is_err());
+    }
+
+    #[test]
+    fn decompresses_scripts() {
+        let x = hex::decode(G_X).unwrap();
+        let script = |kind: u8| decompress_script(&mut Reader::new(&[&[kind][..], &x].concat())).unwrap();
+
+        assert_eq!(hex::encode(script(2)), format!("2102{}ac", G_X));
+        assert_eq!(hex::encode(script(4)), format!("4104{}{}ac", G_X, G_Y));
+        let odd = script(5);
+        let p = BigUint::parse_bytes(b"fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f", 16).unwrap();
+        assert_eq!(BigUint::from_bytes_be(&odd[34..66]), p - BigUint::parse_bytes(G_Y.as_bytes(), 16).unwrap());
+
+        let p2pkh = decompress_script(&mut Reader::new(&[[0].as_slice(), &[7; 20]].concat())).unwrap();
+        assert_eq!(hex::encode(p2pkh), format!("76a914{}88ac", "07".repeat(20)));
+        let raw = decompress_script(&mut Reader::new(&[8, 0x51, 0x52])).unwrap();
+        assert_eq!(raw, [0x51, 0x52]);
+        // x = 5 has no point on the curve
+        let mut off_curve = [0u8; 33];
+        off_curve[0] = 4;
+        off_curve[32] = 5;
+        assert!(decompress_script(&mut Reader::new(&off_curve)).is_err());
+    }
+
+    #[test]
+    fn reads_a_known_index_record() {
+        let header = hex::decode(GENESIS_HEADER).unwrap();
+        assert_eq!(decode::hash_to_hex(&decode::sha256d(&header)), "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f");
+
+        // Version 259900, height 0, scripts valid with data, one
+        // transaction, file 0 at offset 8
+        let value = [hex::decode("8eed3c000d010008").unwrap(), header].concat();
+        let entry = read_index_entry(&value).unwrap();
+        assert_eq!(entry.height, 0);
+        assert!(entry.is_connected());
+        assert_eq!(entry.prev, [0; 32]);
+        let pos = entry.pos.unwrap();
+        assert_eq!((pos.file, pos.data_pos, pos.undo_pos, pos.time), (0, 8, None, 1_231_006_505));
+
+        assert!(read_index_entry(&value[..40]).is_err());
+    }
+
+    #[test]
+    fn reads_blocks_with_undo_data() {
+        let dir = std::env::temp_dir().join(format!("blockfile-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("blocks/index")).unwrap();
+
+        // Genesis is pruned, block 1 is stored with undo data and block 2
+        // has only a header
+        let genesis = hex::decode(GENESIS_HEADER).unwrap();
+        let genesis_hash = decode::sha256d(&genesis);
+        let first = header(genesis_hash, 1_700_000_000);
+        let first_hash = decode::sha256d(&first);
+        let second = header(first_hash, 1_700_000_600);
+        let index = [
+            (&genesis, index_record(0, BLOCK_VALID_SCRIPTS, None, &genesis)),
+            (&first, index_record(1, BLOCK_VALID_SCRIPTS | BLOCK_HAVE_DATA | BLOCK_HAVE_UNDO, Some((0, 8, 8)), &first)),
+            (&second, index_record(2, 2, None, &second)),
+        ]
+        .map(|(header, value)| ([b"b".as_slice(), &decode::sha256d(header)].concat(), value));
+        std::fs::write(dir.join("blocks/index/000003.log"), index_log(&index)).unwrap();
+
+        let block = [first.clone(), vec![2], tx([0; 32], true, 5_000_000_000), tx([1; 32], false, 4_990_000_000)].concat();
+        std::fs::write(dir.join("blocks/blk00000.dat"), record(&block)).unwrap();
+        // One spending transaction with one coin: a height 1 coinbase
+        // output of 50 BTC to a P2PKH script
+        let undo = [&[1, 1, 3, 0, 50, 0][..], &[7; 20]].concat();
+        std::fs::write(dir.join("blocks/rev00000.dat"), [record(&undo), vec![0; 32]].concat()).unwrap();
+
+        let result = BlockFileSource::open(&dir).and_then(|source| {
+            assert_eq!(source.tip(), 1);
+            assert!(source.read_block(0).is_err());
+            source.read_block(1)
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let block = result.unwrap();
+        assert_eq!(block.hash, decode::hash_to_hex(&first_hash));
+        let spend = &block.transactions[1];
+        assert_eq!(spend.fee, Some(10_000_000));
+        let prevout = spend.vin[0].prevout.as_ref().unwrap();
+        assert_eq!(prevout.scriptpubkey, format!("76a914{}88ac", "07".repeat(20)));
+        assert_eq!(spend.status.block_time, Some(1_700_000_000));
+    }
+}
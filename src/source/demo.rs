@@ -0,0 +1,274 @@
+// Simulated transactions for demo mode, built so the real parsers find them
+use super::{Block, BlockEvent, EventStream, MempoolEvent, TransactionSource};
+use crate::config::{Network, ScanConfig};
+use crate::decode::{hash_to_hex, script_address, sha256d};
+use crate::runestone::{self, Edict, Etching, RuneId, Runestone, Terms};
+use crate::{Input, Output, SourceError, Transaction, TxStatus};
+use async_trait::async_trait;
+use futures_util::stream::{self, StreamExt};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Chain height the demo starts at.
+pub const DEMO_HEIGHT: u32 = 840_000;
+/// Time between simulated blocks.
+const DEMO_BLOCK_INTERVAL: Duration = Duration::from_secs(60);
+/// Generated transactions kept for lookups by txid.
+const RECENT_TRANSACTIONS: usize = 1000;
+
+const TICKERS: [&str; 6] = ["ORDI", "SATS", "MEME", "PEPE", "WZRD", "BITS"];
+const RUNES: [&str; 3] = ["SATOSHI•NAKAMOTO", "BITCOIN•PIZZA", "GENESIS•BLOCK"];
+
+pub struct DemoSource {
+    recent: Mutex<VecDeque<Transaction>>,
+    height: AtomicU32,
+}
+
+impl Default for DemoSource {
+    fn default() -> Self {
+        Self {
+            recent: Mutex::new(VecDeque::new()),
+            height: AtomicU32::new(DEMO_HEIGHT),
+        }
+    }
+}
+
+impl DemoSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn remember(&self, tx: &Transaction) {
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == RECENT_TRANSACTIONS {
+            recent.pop_front();
+        }
+        recent.push_back(tx.clone());
+    }
+}
+
+fn push(data: &[u8]) -> Vec<u8> {
+    let mut script = match data.len() {
+        len @ 0..=0x4b => vec![len as u8],
+        len => vec![0x4c, len as u8],
+    };
+    script.extend_from_slice(data);
+    script
+}
+
+fn taproot_output(key: &[u8], value: u64) -> Output {
+    let mut script = vec![0x51, 0x20];
+    script.extend_from_slice(&key[..32]);
+    Output {
+        scriptpubkey: hex::encode(&script),
+        scriptpubkey_address: script_address(&script, Network::Mainnet),
+        value,
+    }
+}
+
+/// A BRC-20 inscription reveal: the envelope sits in the tapscript.
+fn brc20_witness(hash: u64, key: &[u8]) -> Vec<String> {
+    let tick = TICKERS[(hash / 9 % 6) as usize];
+    let json = match hash / 3 % 3 {
+        0 => format!(r#"{{"p":"brc-20","op":"deploy","tick":"{}","max":"21000000","lim":"1000"}}"#, tick),
+        1 => format!(r#"{{"p":"brc-20","op":"mint","tick":"{}","amt":"{}"}}"#, tick, 1000 + hash % 9000),
+        _ => format!(r#"{{"p":"brc-20","op":"transfer","tick":"{}","amt":"{}"}}"#, tick, 100 + hash % 900),
+    };
+
+    let mut script = push(&key[..32]);
+    script.extend_from_slice(&[0xac, 0x00, 0x63]);
+    script.extend(push(b"ord"));
+    script.extend_from_slice(&[0x01, 0x01]);
+    script.extend(push(b"text/plain;charset=utf-8"));
+    script.push(0x00);
+    script.extend(push(json.as_bytes()));
+    script.push(0x68);
+
+    let mut control = vec![0xc0];
+    control.extend_from_slice(&key[..32]);
+
+    vec![hex::encode([0x01; 64]), hex::encode(script), hex::encode(control)]
+}
+
+fn runestone(hash: u64) -> Runestone {
+    let id = RuneId {
+        block: u64::from(DEMO_HEIGHT) - 1000 * (hash / 9 % 3),
+        tx: 1,
+    };
+    match hash / 3 % 3 {
+        0 => Runestone {
+            etching: Some(Etching {
+                rune: Some(RUNES[(hash / 9 % 3) as usize].to_string()),
+                divisibility: Some(2),
+                premine: Some(1_000_000),
+                symbol: Some('¤'),
+                terms: Some(Terms {
+                    cap: Some(10_000),
+                    amount: Some(1_000),
+                    ..Default::default()
+                }),
+                turbo: true,
+            }),
+            ..Default::default()
+        },
+        1 => Runestone {
+            mint: Some(id),
+            ..Default::default()
+        },
+        _ => Runestone {
+            edicts: vec![Edict {
+                id,
+                amount: u128::from(10_000 + hash % 90_000),
+                output: 1,
+            }],
+            ..Default::default()
+        },
+    }
+}
+
+/// A transaction carrying one BRC-20, rune or stamp operation, derived
+/// entirely from `seed`.
+pub fn demo_transaction(seed: u64) -> Transaction {
+    let digest = sha256d(&seed.to_le_bytes());
+    let hash = u64::from_le_bytes(digest[..8].try_into().unwrap());
+    let key = sha256d(&digest);
+
+    let fee = 1_000 + hash % 20_000;
+    let mut witness = vec![hex::encode([0x01; 64])];
+    let vout = match hash % 3 {
+        0 => {
+            witness = brc20_witness(hash, &key);
+            vec![taproot_output(&key, 546)]
+        }
+        1 => vec![
+            Output {
+                scriptpubkey: runestone::encipher(&runestone(hash)),
+                scriptpubkey_address: None,
+                value: 0,
+            },
+            taproot_output(&key, 546),
+        ],
+        _ => {
+            let mut data = b"SMPS\0".to_vec();
+            data.extend_from_slice(&(1000 + hash % 9000).to_be_bytes());
+            let mut script = vec![0x6a];
+            script.extend(push(&data));
+            vec![
+                taproot_output(&key, 546),
+                Output {
+                    scriptpubkey: hex::encode(script),
+                    scriptpubkey_address: None,
+                    value: 0,
+                },
+            ]
+        }
+    };
+
+    let spent: u64 = vout.iter().map(|o| o.value).sum::<u64>() + 10_000 + hash % 990_000;
+    let size = 200 + (hash % 800) as u32;
+
+    Transaction {
+        txid: hash_to_hex(&digest),
+        size,
+        weight: Some(size * 3),
+        fee: Some(fee),
+        status: TxStatus {
+            confirmed: false,
+            block_height: None,
+            block_time: None,
+            block_index: None,
+        },
+        vin: vec![Input {
+            txid: hash_to_hex(&key),
+            vout: 0,
+            witness: Some(witness),
+            prevout: Some(taproot_output(&digest, spent + fee)),
+        }],
+        vout,
+    }
+}
+
+#[async_trait]
+impl TransactionSource for DemoSource {
+    fn kind(&self) -> &'static str {
+        "demo"
+    }
+
+    fn url(&self) -> &str {
+        "demo"
+    }
+
+    async fn get_transaction(&self, txid: &str) -> anyhow::Result<Transaction> {
+        self.recent
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|tx| tx.txid == txid)
+            .cloned()
+            .ok_or_else(|| SourceError::NotFound.into())
+    }
+
+    async fn tip_height(&self) -> anyhow::Result<u32> {
+        Ok(self.height.load(Ordering::Relaxed))
+    }
+
+    /// A burst of one to three transactions every two to four seconds.
+    fn mempool_additions(self: Arc<Self>, _policy: ScanConfig) -> EventStream<MempoolEvent> {
+        let state = (self, 0u64, VecDeque::new(), false);
+
+        stream::unfold(state, |(source, mut rng, mut queue, started)| async move {
+            if !started {
+                return Some((Ok(MempoolEvent::Synced), (source, rng, queue, true)));
+            }
+            if queue.is_empty() {
+                tokio::time::sleep(Duration::from_secs(2 + (rng % 3))).await;
+                for _ in 0..1 + rng % 3 {
+                    let tx = demo_transaction(rng);
+                    source.remember(&tx);
+                    queue.push_back(tx);
+                    rng = rng.wrapping_mul(1664525).wrapping_add(1013904223);
+                }
+            }
+            let tx = queue.pop_front()?;
+            Some((Ok(MempoolEvent::Added(tx)), (source, rng, queue, started)))
+        })
+        .boxed()
+    }
+
+    /// A block of five to fifteen transactions every minute, continuing
+    /// from `after`.
+    fn block_events(self: Arc<Self>, _policy: ScanConfig, after: Option<u32>) -> EventStream<BlockEvent> {
+        self.height.fetch_max(after.unwrap_or(DEMO_HEIGHT), Ordering::Relaxed);
+
+        let blocks = stream::unfold(self, |source| async move {
+            tokio::time::sleep(DEMO_BLOCK_INTERVAL).await;
+            let height = source.height.fetch_add(1, Ordering::Relaxed) + 1;
+            let seed = u64::from(height) << 32;
+            let transactions = (0..5 + seed % 11)
+                .map(|i| {
+                    let mut tx = demo_transaction(seed + i);
+                    tx.status = TxStatus {
+                        confirmed: true,
+                        block_height: Some(height),
+                        block_time: None,
+                        block_index: None,
+                    };
+                    source.remember(&tx);
+                    tx
+                })
+                .collect();
+            let block = Block {
+                height,
+                hash: hash_to_hex(&sha256d(&seed.to_le_bytes())),
+                transactions,
+            };
+            Some(([Ok(BlockEvent::Connected(block)), Ok(BlockEvent::Synced)], source))
+        });
+
+        stream::iter([Ok(BlockEvent::Synced)])
+            .chain(blocks.flat_map(stream::iter))
+            .boxed()
+    }
+}
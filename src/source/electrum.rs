@@ -0,0 +1,638 @@
+// Electrum protocol (electrs, Fulcrum) over TCP or TLS
+use super::{Block, BlockEvent, ByteStream, EventStream, MempoolEvent, TransactionSource, MAX_BACKOFF, MIN_BACKOFF, REORG_DEPTH};
+use crate::config::{BackendConfig, Network, ScanConfig};
+use crate::decode::{self, hash_to_hex, sha256d};
+use crate::{is_valid_txid, metrics, SourceError, Transaction, TxStatus};
+use async_trait::async_trait;
+use futures_util::stream::{self, StreamExt};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+const CLIENT_NAME: &str = concat!("bitcoin-monitor ", env!("CARGO_PKG_VERSION"));
+const PROTOCOL_VERSION: &str = "1.4";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const NOTIFICATION_CAPACITY: usize = 1024;
+/// Headers per `blockchain.block.headers` request; servers cap it at 2016.
+const HEADER_BATCH: u32 = 2016;
+
+/// A server push, or the connection dropping.
+#[derive(Debug, Clone)]
+enum Notification {
+    Header(Value),
+    Scripthash(String),
+    Closed,
+}
+
+/// One connection, with requests matched to responses by id.
+struct Connection {
+    lines: mpsc::UnboundedSender<String>,
+    pending: Mutex<HashMap<u64, oneshot::Sender<Value>>>,
+    next_id: AtomicU64,
+    closed: AtomicBool,
+}
+
+impl Connection {
+    /// Start the reader and writer tasks for `stream`.
+    fn spawn(stream: Box<dyn ByteStream>, notifications: broadcast::Sender<Notification>) -> Arc<Self> {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let (lines, mut outgoing) = mpsc::unbounded_channel::<String>();
+        let connection = Arc::new(Self {
+            lines,
+            pending: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+        });
+
+        tokio::spawn(async move {
+            while let Some(line) = outgoing.recv().await {
+                if writer.write_all(line.as_bytes()).await.is_err() || writer.flush().await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let conn = connection.clone();
+        tokio::spawn(async move {
+            let mut reader = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = reader.next_line().await {
+                if let Ok(message) = serde_json::from_str::<Value>(&line) {
+                    conn.dispatch(message, &notifications);
+                }
+            }
+            conn.closed.store(true, Ordering::SeqCst);
+            conn.pending.lock().unwrap().clear();
+            let _ = notifications.send(Notification::Closed);
+        });
+
+        connection
+    }
+
+    fn dispatch(&self, mut message: Value, notifications: &broadcast::Sender<Notification>) {
+        if let Some(id) = message["id"].as_u64() {
+            if let Some(reply) = self.pending.lock().unwrap().remove(&id) {
+                let _ = reply.send(message);
+            }
+            return;
+        }
+        let notification = match message["method"].as_str() {
+            Some("blockchain.headers.subscribe") => Notification::Header(message["params"][0].take()),
+            Some("blockchain.scripthash.subscribe") => match message["params"][0].as_str() {
+                Some(scripthash) => Notification::Scripthash(scripthash.to_string()),
+                None => return,
+            },
+            _ => return,
+        };
+        let _ = notifications.send(notification);
+    }
+
+    /// Send a request and wait for the whole response; `None` if the
+    /// connection closed first.
+    async fn request(&self, method: &str, params: Value) -> Option<Value> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (reply, response) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, reply);
+
+        let line = json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params}).to_string() + "\n";
+        if self.lines.send(line).is_err() {
+            self.pending.lock().unwrap().remove(&id);
+            return None;
+        }
+        response.await.ok()
+    }
+}
+
+pub struct ElectrumSource {
+    url: String,
+    host: String,
+    port: u16,
+    tls: bool,
+    network: Network,
+    /// Electrum scripthashes of the watched addresses.
+    scripthashes: Vec<String>,
+    connection: tokio::sync::Mutex<Option<Arc<Connection>>>,
+    notifications: broadcast::Sender<Notification>,
+}
+
+/// Electrum's key for an output script: its SHA-256, byte-reversed.
+pub fn scripthash(script: &[u8]) -> String {
+    hash_to_hex(&Sha256::digest(script))
+}
+
+impl ElectrumSource {
+    /// Connect to `url` (`tcp://host:port` or `ssl://host:port`), following
+    /// the transactions of `addresses` on `network`.
+    pub fn new(url: &str, addresses: &[String], network: Network) -> anyhow::Result<Self> {
+        let (tls, address) = match url.split_once("://") {
+            Some(("tcp", address)) => (false, address),
+            Some(("ssl" | "tls", address)) => (true, address),
+            _ => anyhow::bail!("Electrum URL must start with tcp:// or ssl://, got {:?}", url),
+        };
+        let address = address.trim_end_matches('/');
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| anyhow::anyhow!("Invalid port in {:?}", url))?),
+            None => (address, if tls { 50002 } else { 50001 }),
+        };
+
+        let scripthashes = addresses
+            .iter()
+            .map(|address| {
+                decode::address_script(address, network)
+                    .map(|script| scripthash(&script))
+                    .ok_or_else(|| anyhow::anyhow!("Invalid {} address {:?}", network.name(), address))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            url: url.to_string(),
+            host: host.trim_start_matches('[').trim_end_matches(']').to_string(),
+            port,
+            tls,
+            network,
+            scripthashes,
+            connection: tokio::sync::Mutex::new(None),
+            notifications: broadcast::channel(NOTIFICATION_CAPACITY).0,
+        })
+    }
+
+    pub fn from_config(backend: &BackendConfig) -> anyhow::Result<Self> {
+        if backend.addresses.is_empty() {
+            tracing::warn!("No backend.addresses configured; the electrum backend will only follow the chain tip");
+        }
+        Self::new(&backend.url, &backend.addresses, backend.network)
+    }
+
+    async fn open(&self) -> anyhow::Result<Box<dyn ByteStream>> {
+        let tcp = tokio::net::TcpStream::connect((self.host.as_str(), self.port)).await?;
+        if !self.tls {
+            return Ok(Box::new(tcp));
+        }
+        let connector = tokio_native_tls::TlsConnector::from(tokio_native_tls::native_tls::TlsConnector::new()?);
+        Ok(Box::new(connector.connect(&self.host, tcp).await?))
+    }
+
+    /// The open connection, reconnecting and negotiating the protocol
+    /// version if the last one closed.
+    async fn connection(&self) -> anyhow::Result<Arc<Connection>> {
+        let mut current = self.connection.lock().await;
+        if let Some(connection) = current.as_ref().filter(|c| !c.closed.load(Ordering::SeqCst)) {
+            return Ok(connection.clone());
+        }
+
+        let stream = self.open().await.map_err(|e| SourceError::Unavailable(e.to_string()))?;
+        let connection = Connection::spawn(stream, self.notifications.clone());
+        let version = tokio::time::timeout(REQUEST_TIMEOUT, connection.request("server.version", json!([CLIENT_NAME, PROTOCOL_VERSION])))
+            .await
+            .ok()
+            .flatten()
+            .ok_or_else(|| SourceError::Unavailable("Electrum server did not answer server.version".to_string()))?;
+        if let Some(error) = version.get("error").filter(|e| !e.is_null()) {
+            anyhow::bail!("Electrum server rejected protocol {}: {}", PROTOCOL_VERSION, error["message"]);
+        }
+
+        tracing::info!(url = %self.url, server = %version["result"][0], "Connected to Electrum server");
+        *current = Some(connection.clone());
+        Ok(connection)
+    }
+
+    /// Call a method, recording request count, latency and errors.
+    #[tracing::instrument(level = "debug", skip(self, params), fields(backend = "electrum"))]
+    pub async fn call(&self, method: &'static str, params: Value) -> anyhow::Result<Value> {
+        let m = metrics::metrics();
+        let labels = ["electrum", method];
+        m.upstream_requests.with_label_values(&labels).inc();
+        let error = |kind: &str| m.upstream_errors.with_label_values(&["electrum", method, kind]).inc();
+
+        let timer = m.upstream_duration.with_label_values(&labels).start_timer();
+        let result = match self.connection().await {
+            Ok(connection) => tokio::time::timeout(REQUEST_TIMEOUT, connection.request(method, params)).await,
+            Err(e) => {
+                timer.observe_duration();
+                error("network");
+                return Err(e);
+            }
+        };
+        timer.observe_duration();
+
+        let mut response = match result {
+            Ok(Some(response)) => response,
+            Ok(None) => {
+                error("network");
+                return Err(SourceError::Unavailable("Electrum connection closed".to_string()).into());
+            }
+            Err(_) => {
+                error("timeout");
+                return Err(SourceError::Unavailable(format!("Electrum {} timed out", method)).into());
+            }
+        };
+        if let Some(rpc_error) = response.get("error").filter(|e| !e.is_null()) {
+            error("rpc_error");
+            let message = rpc_error["message"].as_str().unwrap_or("unknown error");
+            let lower = message.to_lowercase();
+            if method == "blockchain.transaction.get" && ["not found", "no such", "missing"].iter().any(|m| lower.contains(m)) {
+                return Err(SourceError::NotFound.into());
+            }
+            return Err(SourceError::Unavailable(format!("Electrum {} failed: {}", method, message)).into());
+        }
+
+        Ok(response["result"].take())
+    }
+
+    /// A transaction as stored, without status or prevouts.
+    async fn raw_transaction(&self, txid: &str) -> anyhow::Result<Transaction> {
+        let raw = self.call("blockchain.transaction.get", json!([txid])).await?;
+        let raw = raw.as_str().ok_or_else(|| anyhow::anyhow!("blockchain.transaction.get returned no hex"))?;
+        decode::decode_transaction_hex(raw, self.network)
+    }
+
+    /// Hash and timestamp of the best-chain header at `height`.
+    async fn header(&self, height: u32) -> anyhow::Result<(String, u32)> {
+        let header = self.call("blockchain.block.header", json!([height])).await?;
+        parse_header(header.as_str().and_then(|h| hex::decode(h).ok()).as_deref().unwrap_or_default())
+    }
+
+    /// Height of each transaction touching `scripthash`; zero or below for
+    /// unconfirmed ones.
+    async fn history(&self, scripthash: &str) -> anyhow::Result<Vec<(String, i64)>> {
+        let history = self.call("blockchain.scripthash.get_history", json!([scripthash])).await?;
+        Ok(history
+            .as_array()
+            .ok_or_else(|| anyhow::anyhow!("blockchain.scripthash.get_history returned no list"))?
+            .iter()
+            .filter_map(|entry| Some((entry["tx_hash"].as_str()?.to_string(), entry["height"].as_i64()?)))
+            .collect())
+    }
+
+    /// Confirmation status, from the history of the first output whose
+    /// history the server returns (OP_RETURN outputs are not indexed, and
+    /// busy addresses can have too long a history). Unconfirmed if none do.
+    async fn status(&self, tx: &Transaction) -> anyhow::Result<TxStatus> {
+        let scripts = tx
+            .vout
+            .iter()
+            .filter_map(|o| hex::decode(&o.scriptpubkey).ok())
+            .filter(|script| script.first() != Some(&0x6a));
+        let mut height = None;
+        for script in scripts {
+            match self.history(&scripthash(&script)).await {
+                Ok(history) => {
+                    height = history.into_iter().find(|(txid, _)| *txid == tx.txid).map(|(_, height)| height);
+                    break;
+                }
+                Err(e) => tracing::debug!(txid = %tx.txid, error = %e, "History lookup failed, trying the next output"),
+            }
+        }
+
+        match height {
+            Some(height) if height > 0 => {
+                let (_, time) = self.header(height as u32).await?;
+                Ok(TxStatus {
+                    confirmed: true,
+                    block_height: Some(height as u32),
+                    block_time: Some(u64::from(time)),
+                    block_index: Some(self.position(&tx.txid, height as u32).await?),
+                })
+            }
+            _ => Ok(TxStatus {
+                confirmed: false,
+                block_height: None,
+                block_time: None,
+                block_index: None,
+            }),
+        }
+    }
+
+    /// Position of `txid` in the block at `height`, as the block itself
+    /// cannot be listed.
+    async fn position(&self, txid: &str, height: u32) -> anyhow::Result<u32> {
+        let merkle = self.call("blockchain.transaction.get_merkle", json!([txid, height])).await?;
+        merkle["pos"]
+            .as_u64()
+            .and_then(|pos| u32::try_from(pos).ok())
+            .ok_or_else(|| anyhow::anyhow!("blockchain.transaction.get_merkle returned no position"))
+    }
+
+    /// Fetch each parent to fill in prevouts, then compute the fee.
+    async fn resolve_inputs(&self, tx: &mut Transaction) {
+        let mut parents: HashMap<String, Option<Transaction>> = HashMap::new();
+        for input in tx.vin.iter_mut().filter(|i| i.prevout.is_none()) {
+            if input.txid.bytes().all(|b| b == b'0') {
+                continue; // coinbase
+            }
+            if !parents.contains_key(&input.txid) {
+                let parent = self.raw_transaction(&input.txid).await.ok();
+                parents.insert(input.txid.clone(), parent);
+            }
+            input.prevout = parents[&input.txid]
+                .as_ref()
+                .and_then(|p| p.vout.get(input.vout as usize).cloned());
+        }
+
+        if tx.fee.is_none() && tx.vin.iter().all(|i| i.prevout.is_some()) {
+            let inputs: u64 = tx.vin.iter().filter_map(|i| i.prevout.as_ref()).map(|p| p.value).sum();
+            let outputs: u64 = tx.vout.iter().map(|o| o.value).sum();
+            tx.fee = inputs.checked_sub(outputs);
+        }
+    }
+
+    /// A transaction with prevouts resolved, its status looked up unless
+    /// already known.
+    async fn transaction(&self, txid: &str, status: Option<TxStatus>) -> anyhow::Result<Transaction> {
+        let mut tx = self.raw_transaction(txid).await?;
+        tx.status = match status {
+            Some(status) => status,
+            None => self.status(&tx).await?,
+        };
+        self.resolve_inputs(&mut tx).await;
+        Ok(tx)
+    }
+
+    /// The watched addresses' confirmed txids by height, from one history
+    /// request per address.
+    async fn confirmed_txids(&self) -> anyhow::Result<HashMap<u32, Vec<String>>> {
+        let mut by_height: HashMap<u32, Vec<String>> = HashMap::new();
+        for scripthash in &self.scripthashes {
+            for (txid, height) in self.history(scripthash).await? {
+                let Some(height) = u32::try_from(height).ok().filter(|h| *h > 0) else {
+                    continue;
+                };
+                let txids = by_height.entry(height).or_default();
+                if !txids.contains(&txid) {
+                    txids.push(txid);
+                }
+            }
+        }
+        Ok(by_height)
+    }
+
+    /// The transactions of `txids`, confirmed at `height`; the Electrum
+    /// protocol cannot list a whole block.
+    async fn block_transactions(&self, height: u32, time: u32, txids: &[String]) -> anyhow::Result<Vec<Transaction>> {
+        let mut transactions = Vec::with_capacity(txids.len());
+        for txid in txids {
+            let status = TxStatus {
+                confirmed: true,
+                block_height: Some(height),
+                block_time: Some(u64::from(time)),
+                block_index: Some(self.position(txid, height).await?),
+            };
+            transactions.push(self.transaction(txid, Some(status)).await?);
+        }
+        Ok(transactions)
+    }
+
+    /// Emit the unconfirmed transactions of `scripthash` not seen yet.
+    async fn scan_history(&self, scripthash: &str, seen: &mut HashSet<String>, events: &mpsc::Sender<anyhow::Result<MempoolEvent>>) -> anyhow::Result<()> {
+        for (txid, height) in self.history(scripthash).await? {
+            if height > 0 {
+                seen.remove(&txid);
+            } else if seen.insert(txid.clone()) {
+                let status = TxStatus {
+                    confirmed: false,
+                    block_height: None,
+                    block_time: None,
+                    block_index: None,
+                };
+                let tx = self.transaction(&txid, Some(status)).await?;
+                let _ = events.send(Ok(MempoolEvent::Added(tx))).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Subscribe to every watched scripthash and report new unconfirmed
+    /// transactions until the connection fails.
+    async fn watch_mempool(&self, events: &mpsc::Sender<anyhow::Result<MempoolEvent>>, seen: &mut HashSet<String>, backoff: &mut Duration) -> anyhow::Error {
+        let mut notifications = self.notifications.subscribe();
+        for scripthash in &self.scripthashes {
+            if let Err(e) = self.call("blockchain.scripthash.subscribe", json!([scripthash])).await {
+                return e;
+            }
+            if let Err(e) = self.scan_history(scripthash, seen, events).await {
+                return e;
+            }
+        }
+        let _ = events.send(Ok(MempoolEvent::Synced)).await;
+        *backoff = MIN_BACKOFF;
+
+        loop {
+            match notifications.recv().await {
+                Ok(Notification::Scripthash(scripthash)) => {
+                    if let Err(e) = self.scan_history(&scripthash, seen, events).await {
+                        return e;
+                    }
+                    let _ = events.send(Ok(MempoolEvent::Synced)).await;
+                }
+                Ok(Notification::Closed) => return anyhow::anyhow!("Electrum connection closed"),
+                Ok(Notification::Header(_)) => {}
+                Err(e) => return anyhow::anyhow!("Missed scripthash notifications: {}", e),
+            }
+            if events.is_closed() {
+                return anyhow::anyhow!("Mempool stream dropped");
+            }
+        }
+    }
+
+    /// Bring the blocks up to `tip`: disconnect those the server no longer
+    /// has, then connect from `next`, or the tip if nothing came before.
+    async fn sync_blocks(
+        &self,
+        policy: &ScanConfig,
+        events: &mpsc::Sender<anyhow::Result<BlockEvent>>,
+        chain: &mut VecDeque<(u32, String)>,
+        next: &mut Option<u32>,
+        tip: u32,
+    ) -> anyhow::Result<()> {
+        while let Some((height, hash)) = chain.back().cloned() {
+            if height <= tip && self.header(height).await?.0 == hash {
+                break;
+            }
+            chain.pop_back();
+            *next = Some(height);
+            let _ = events.send(Ok(BlockEvent::Disconnected { height, hash })).await;
+        }
+
+        let mut start = next.unwrap_or(tip);
+        if start <= tip && tip - start >= policy.max_catchup_blocks {
+            tracing::warn!(from = start, tip, max = policy.max_catchup_blocks, "Too many missed blocks, skipping ahead");
+            start = tip + 1 - policy.max_catchup_blocks;
+        }
+        if start < tip {
+            tracing::info!(from = start, tip, "Catching up on missed blocks");
+        }
+
+        // Histories cover every block up to the tip, so fetch them once
+        let confirmed = if start <= tip { self.confirmed_txids().await? } else { HashMap::new() };
+        while start <= tip {
+            let count = (tip - start + 1).min(HEADER_BATCH);
+            let headers = self.call("blockchain.block.headers", json!([start, count])).await?;
+            let headers = headers["hex"]
+                .as_str()
+                .and_then(|h| hex::decode(h).ok())
+                .ok_or_else(|| anyhow::anyhow!("blockchain.block.headers returned no hex"))?;
+            if headers.is_empty() {
+                break;
+            }
+
+            for header in headers.chunks(80) {
+                let (hash, time) = parse_header(header)?;
+                let block = Block {
+                    height: start,
+                    transactions: self.block_transactions(start, time, confirmed.get(&start).map(Vec::as_slice).unwrap_or_default()).await?,
+                    hash: hash.clone(),
+                };
+                chain.push_back((start, hash));
+                if chain.len() > REORG_DEPTH {
+                    chain.pop_front();
+                }
+                start += 1;
+                *next = Some(start);
+                if events.send(Ok(BlockEvent::Connected(block))).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Subscribe to headers and follow the tip until the connection fails.
+    async fn watch_blocks(
+        &self,
+        policy: &ScanConfig,
+        events: &mpsc::Sender<anyhow::Result<BlockEvent>>,
+        chain: &mut VecDeque<(u32, String)>,
+        next: &mut Option<u32>,
+        backoff: &mut Duration,
+    ) -> anyhow::Error {
+        let mut notifications = self.notifications.subscribe();
+        let mut tip = match self.call("blockchain.headers.subscribe", json!([])).await {
+            Ok(header) => header,
+            Err(e) => return e,
+        };
+
+        loop {
+            let Some(height) = tip["height"].as_u64() else {
+                return anyhow::anyhow!("Electrum header notification has no height");
+            };
+            let _timer = metrics::metrics().scan_duration.with_label_values(&["blocks"]).start_timer();
+            if let Err(e) = self.sync_blocks(policy, events, chain, next, height as u32).await {
+                return e;
+            }
+            let _ = events.send(Ok(BlockEvent::Synced)).await;
+            *backoff = MIN_BACKOFF;
+
+            tip = loop {
+                match notifications.recv().await {
+                    Ok(Notification::Header(header)) => break header,
+                    Ok(Notification::Closed) => return anyhow::anyhow!("Electrum connection closed"),
+                    Ok(Notification::Scripthash(_)) => {}
+                    // A later header notification still catches up
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(e) => return e.into(),
+                }
+                if events.is_closed() {
+                    return anyhow::anyhow!("Block stream dropped");
+                }
+            };
+        }
+    }
+
+    /// Report a lost subscription and wait out the backoff; false once
+    /// nobody is listening any more.
+    async fn retry_after<T>(&self, error: anyhow::Error, subscription: &str, events: &mpsc::Sender<anyhow::Result<T>>, backoff: &mut Duration) -> bool {
+        if events.is_closed() {
+            return false;
+        }
+        tracing::warn!(url = %self.url, subscription, error = %error, retry_in = ?backoff, "Electrum subscription lost");
+        let _ = events.send(Err(error)).await;
+        tokio::time::sleep(*backoff).await;
+        *backoff = (*backoff * 2).min(MAX_BACKOFF);
+        true
+    }
+}
+
+fn parse_header(header: &[u8]) -> anyhow::Result<(String, u32)> {
+    if header.len() != 80 {
+        anyhow::bail!("Electrum server returned a malformed block header");
+    }
+    Ok((hash_to_hex(&sha256d(header)), u32::from_le_bytes(header[68..72].try_into()?)))
+}
+
+/// Stream what `follow` sends from its own task, which should return once
+/// the stream is dropped.
+fn task_stream<T, Fut>(follow: impl FnOnce(mpsc::Sender<anyhow::Result<T>>) -> Fut) -> EventStream<T>
+where
+    T: Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(64);
+    tokio::spawn(follow(tx));
+    stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|event| (event, rx)) }).boxed()
+}
+
+#[async_trait]
+impl TransactionSource for ElectrumSource {
+    fn kind(&self) -> &'static str {
+        "electrum"
+    }
+
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn network(&self) -> Network {
+        self.network
+    }
+
+    async fn get_transaction(&self, txid: &str) -> anyhow::Result<Transaction> {
+        if !is_valid_txid(txid) {
+            return Err(SourceError::InvalidTxid.into());
+        }
+        self.transaction(txid, None).await
+    }
+
+    async fn tip_height(&self) -> anyhow::Result<u32> {
+        let header = self.call("blockchain.headers.subscribe", json!([])).await?;
+        header["height"]
+            .as_u64()
+            .map(|h| h as u32)
+            .ok_or_else(|| anyhow::anyhow!("blockchain.headers.subscribe returned no height"))
+    }
+
+    /// Unconfirmed transactions of the watched addresses, as their
+    /// scripthash subscriptions report changes.
+    fn mempool_additions(self: Arc<Self>, _policy: ScanConfig) -> EventStream<MempoolEvent> {
+        task_stream(move |events| async move {
+            let mut seen = HashSet::new();
+            let mut backoff = MIN_BACKOFF;
+            loop {
+                let error = self.watch_mempool(&events, &mut seen, &mut backoff).await;
+                if !self.retry_after(error, "scripthashes", &events, &mut backoff).await {
+                    return;
+                }
+            }
+        })
+    }
+
+    /// Blocks from header notifications, holding only the watched
+    /// addresses' transactions.
+    fn block_events(self: Arc<Self>, policy: ScanConfig, after: Option<u32>) -> EventStream<BlockEvent> {
+        task_stream(move |events| async move {
+            let mut chain = VecDeque::new();
+            let mut next = after.map(|h| h + 1);
+            let mut backoff = MIN_BACKOFF;
+            loop {
+                let error = self.watch_blocks(&policy, &events, &mut chain, &mut next, &mut backoff).await;
+                if !self.retry_after(error, "headers", &events, &mut backoff).await {
+                    return;
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,79 @@
+// Esplora REST API, polled through `BitcoinClient`
+use super::{fetch_batch, poll_blocks, poll_mempool, BlockEvent, EventStream, MempoolEvent, Poller, TransactionSource};
+use crate::config::{Network, ScanConfig};
+use crate::{BitcoinClient, Transaction, ESPLORA_BLOCK_PAGE};
+use async_trait::async_trait;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[async_trait]
+impl Poller for BitcoinClient {
+    async fn get_transaction(&self, txid: &str) -> anyhow::Result<Transaction> {
+        BitcoinClient::get_transaction(self, txid).await
+    }
+
+    async fn recent_mempool_txids(&self, limit: usize) -> anyhow::Result<Vec<String>> {
+        self.get_mempool_txs(limit).await
+    }
+
+    async fn mempool_txids(&self) -> anyhow::Result<Vec<String>> {
+        self.get_mempool_txids().await
+    }
+
+    async fn tip(&self) -> anyhow::Result<(u32, String)> {
+        self.get_tip().await
+    }
+
+    async fn block_hash(&self, height: u32) -> anyhow::Result<String> {
+        self.get_block_hash(height).await
+    }
+
+    /// Pages through the block's transactions, fetching
+    /// `policy.fetch_concurrency` pages at a time.
+    async fn block_transactions(&self, hash: &str, _height: u32, policy: &ScanConfig) -> anyhow::Result<Vec<Transaction>> {
+        let count = self.get_block_tx_count(hash).await?;
+        let mut pages: VecDeque<usize> = (0..count).step_by(ESPLORA_BLOCK_PAGE).collect();
+        let mut fetched = BTreeMap::new();
+        let mut backoff = Duration::ZERO;
+
+        while !pages.is_empty() {
+            let results = fetch_batch(&mut pages, &mut backoff, policy, |start: &usize| self.get_block_txs_page(hash, *start)).await;
+            for (start, result) in results {
+                fetched.insert(start, result?);
+            }
+        }
+        Ok(fetched.into_values().flatten().collect())
+    }
+}
+
+#[async_trait]
+impl TransactionSource for BitcoinClient {
+    fn kind(&self) -> &'static str {
+        "esplora"
+    }
+
+    fn url(&self) -> &str {
+        self.base_url()
+    }
+
+    fn network(&self) -> Network {
+        BitcoinClient::network(self)
+    }
+
+    async fn get_transaction(&self, txid: &str) -> anyhow::Result<Transaction> {
+        BitcoinClient::get_transaction(self, txid).await
+    }
+
+    async fn tip_height(&self) -> anyhow::Result<u32> {
+        self.get_tip_height().await
+    }
+
+    fn mempool_additions(self: Arc<Self>, policy: ScanConfig) -> EventStream<MempoolEvent> {
+        poll_mempool(self, policy)
+    }
+
+    fn block_events(self: Arc<Self>, policy: ScanConfig, after: Option<u32>) -> EventStream<BlockEvent> {
+        poll_blocks(self, policy, after)
+    }
+}
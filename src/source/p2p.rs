@@ -0,0 +1,759 @@
+// Transactions and blocks relayed by a peer on the Bitcoin P2P network
+use super::{Block, BlockEvent, ByteStream, EventStream, MempoolEvent, TransactionSource, MAX_BACKOFF, MIN_BACKOFF, REORG_DEPTH};
+use crate::config::{BackendConfig, Network, ScanConfig};
+use crate::decode::{self, Reader};
+use crate::{SourceError, Transaction};
+use async_trait::async_trait;
+use futures_util::future::BoxFuture;
+use futures_util::stream::{self, StreamExt};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast;
+
+const PROTOCOL_VERSION: u32 = 70016;
+const USER_AGENT: &str = concat!("/bitcoin-monitor:", env!("CARGO_PKG_VERSION"), "/");
+const HEADER_LEN: usize = 24;
+const MAX_PAYLOAD: usize = 32 * 1024 * 1024;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Peers ping every two minutes, so a longer silence means a dead connection.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// How often a live session reports the source as synced.
+const SYNCED_INTERVAL: Duration = Duration::from_secs(30);
+/// Most headers a peer sends in one message.
+const MAX_HEADERS: usize = 2000;
+/// Relayed transactions kept for lookups by txid.
+const TX_CACHE_SIZE: usize = 10_000;
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+/// Inventory types, and the flag asking for witness serialization.
+const INV_TX: u32 = 1;
+const INV_BLOCK: u32 = 2;
+const INV_WITNESS: u32 = 1 << 30;
+
+fn magic(network: Network) -> [u8; 4] {
+    match network {
+        Network::Mainnet => [0xf9, 0xbe, 0xb4, 0xd9],
+        Network::Testnet => [0x0b, 0x11, 0x09, 0x07],
+        Network::Testnet4 => [0x1c, 0x16, 0x3f, 0x28],
+        Network::Signet => [0x0a, 0x03, 0xcf, 0x40],
+        Network::Regtest => [0xfa, 0xbf, 0xb5, 0xda],
+    }
+}
+
+fn default_port(network: Network) -> u16 {
+    match network {
+        Network::Mainnet => 8333,
+        Network::Testnet => 18333,
+        Network::Testnet4 => 48333,
+        Network::Signet => 38333,
+        Network::Regtest => 18444,
+    }
+}
+
+fn write_compact_size(buf: &mut Vec<u8>, n: usize) {
+    match n {
+        0..=0xfc => buf.push(n as u8),
+        0xfd..=0xffff => {
+            buf.push(0xfd);
+            buf.extend((n as u16).to_le_bytes());
+        }
+        _ => {
+            buf.push(0xfe);
+            buf.extend((n as u32).to_le_bytes());
+        }
+    }
+}
+
+/// Message framing over any byte stream, so a scripted fake peer can sit
+/// on the other end of an in-memory pipe.
+pub struct Peer<S> {
+    stream: S,
+    magic: [u8; 4],
+    /// Bytes read but not yet returned as a message.
+    buffer: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Peer<S> {
+    pub fn new(stream: S, network: Network) -> Self {
+        Self {
+            stream,
+            magic: magic(network),
+            buffer: Vec::new(),
+        }
+    }
+
+    pub async fn send(&mut self, command: &str, payload: &[u8]) -> anyhow::Result<()> {
+        let mut name = [0u8; 12];
+        name[..command.len()].copy_from_slice(command.as_bytes());
+
+        let mut message = Vec::with_capacity(HEADER_LEN + payload.len());
+        message.extend(self.magic);
+        message.extend(name);
+        message.extend((payload.len() as u32).to_le_bytes());
+        message.extend(&decode::sha256d(payload)[..4]);
+        message.extend(payload);
+        self.stream.write_all(&message).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    /// The next message's command and payload. Cancel safe: a partly read
+    /// message is kept for the next call.
+    pub async fn receive(&mut self) -> anyhow::Result<(String, Vec<u8>)> {
+        loop {
+            if let Some(message) = self.next_message()? {
+                return Ok(message);
+            }
+            self.buffer.reserve(64 * 1024);
+            if self.stream.read_buf(&mut self.buffer).await? == 0 {
+                anyhow::bail!("Peer closed the connection");
+            }
+        }
+    }
+
+    /// Take a complete message off the buffer.
+    fn next_message(&mut self) -> anyhow::Result<Option<(String, Vec<u8>)>> {
+        let Some(header) = self.buffer.get(..HEADER_LEN) else { return Ok(None) };
+        if header[..4] != self.magic {
+            anyhow::bail!("Peer is on another network");
+        }
+        let command = String::from_utf8_lossy(&header[4..16]).trim_end_matches('\0').to_string();
+        let len = u32::from_le_bytes(header[16..20].try_into()?) as usize;
+        if len > MAX_PAYLOAD {
+            anyhow::bail!("Oversized {} message from peer", command);
+        }
+
+        let Some(payload) = self.buffer.get(HEADER_LEN..HEADER_LEN + len) else { return Ok(None) };
+        if decode::sha256d(payload)[..4] != header[20..24] {
+            anyhow::bail!("Bad checksum on {} message from peer", command);
+        }
+        let payload = payload.to_vec();
+        self.buffer.drain(..HEADER_LEN + len);
+        Ok(Some((command, payload)))
+    }
+
+    /// Exchange version and verack messages, returning the peer's height.
+    pub async fn handshake(&mut self) -> anyhow::Result<u32> {
+        self.send("version", &version_payload()).await?;
+
+        let (mut height, mut verack) = (None, false);
+        while height.is_none() || !verack {
+            let (command, payload) = self.receive().await?;
+            match command.as_str() {
+                "version" => {
+                    height = Some(peer_height(&payload)?);
+                    self.send("verack", &[]).await?;
+                }
+                "verack" => verack = true,
+                _ => {}
+            }
+        }
+        Ok(height.unwrap_or_default())
+    }
+}
+
+/// Our version message: no services offered, relay requested.
+fn version_payload() -> Vec<u8> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut payload = Vec::new();
+    payload.extend(PROTOCOL_VERSION.to_le_bytes());
+    payload.extend(0u64.to_le_bytes()); // services
+    payload.extend(now.as_secs().to_le_bytes());
+    payload.extend([0u8; 26 * 2]); // receiver and sender addresses
+    payload.extend((now.as_nanos() as u64).to_le_bytes()); // nonce
+    write_compact_size(&mut payload, USER_AGENT.len());
+    payload.extend(USER_AGENT.as_bytes());
+    payload.extend(0u32.to_le_bytes()); // start height
+    payload.push(1); // relay
+    payload
+}
+
+/// A getheaders request for the blocks after the newest `locator` hash the
+/// peer knows.
+fn getheaders_payload(locator: &[[u8; 32]]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(4 + 9 + 32 * (locator.len() + 1));
+    payload.extend(PROTOCOL_VERSION.to_le_bytes());
+    write_compact_size(&mut payload, locator.len());
+    for hash in locator {
+        payload.extend(hash);
+    }
+    payload.extend([0u8; 32]); // no stop hash
+    payload
+}
+
+/// A getdata request for inventory items, with witnesses.
+fn getdata_payload(items: &[(u32, &[u8])]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(9 + 36 * items.len());
+    write_compact_size(&mut payload, items.len());
+    for (kind, hash) in items {
+        payload.extend((kind | INV_WITNESS).to_le_bytes());
+        payload.extend(*hash);
+    }
+    payload
+}
+
+/// Internal byte order of a block hash shown in hex.
+fn hash_bytes(hash: &str) -> Option<[u8; 32]> {
+    let mut bytes: [u8; 32] = hex::decode(hash).ok()?.try_into().ok()?;
+    bytes.reverse();
+    Some(bytes)
+}
+
+fn peer_height(version: &[u8]) -> anyhow::Result<u32> {
+    let mut reader = Reader::new(version);
+    reader.bytes(4 + 8 + 8 + 26 * 2 + 8)?;
+    reader.var_bytes()?; // user agent
+    reader.u32()
+}
+
+/// Height from a block's coinbase script (BIP34).
+fn coinbase_height(raw: &[u8]) -> Option<u32> {
+    let mut reader = Reader::new(raw);
+    reader.bytes(80).ok()?; // header
+    reader.compact_size().ok()?; // transaction count
+    reader.u32().ok()?; // version
+    if reader.u8().ok()? == 0 {
+        reader.u8().ok()?; // segwit flag
+        reader.u8().ok()?; // input count
+    }
+    reader.bytes(36).ok()?; // null prevout
+    let script = reader.var_bytes().ok()?;
+    match *script.first()? {
+        op @ 0x51..=0x60 => Some(u32::from(op - 0x50)),
+        len @ 1..=4 => {
+            let bytes = script.get(1..1 + len as usize)?;
+            Some(bytes.iter().rev().fold(0, |n, b| n << 8 | u32::from(*b)))
+        }
+        _ => None,
+    }
+}
+
+type Connector = Box<dyn Fn() -> BoxFuture<'static, std::io::Result<Box<dyn ByteStream>>> + Send + Sync>;
+
+#[derive(Default)]
+struct TxCache {
+    txs: HashMap<String, Transaction>,
+    order: VecDeque<String>,
+}
+
+pub struct P2pSource {
+    name: String,
+    network: Network,
+    connect: Connector,
+    started: AtomicBool,
+    /// Best height the peer reported or sent; zero until connected.
+    tip: AtomicU32,
+    /// Last block the monitor processed before this source started.
+    after: Mutex<Option<u32>>,
+    cache: Mutex<TxCache>,
+    mempool: broadcast::Sender<Result<MempoolEvent, String>>,
+    blocks: broadcast::Sender<Result<BlockEvent, String>>,
+}
+
+impl P2pSource {
+    /// Connect over TCP to `addr`, reconnecting with backoff when the
+    /// connection drops.
+    pub fn connect(addr: String, network: Network) -> Self {
+        let target = addr.clone();
+        Self::with_connector(addr, network, move || tokio::net::TcpStream::connect(target.clone()))
+    }
+
+    pub fn from_config(backend: &BackendConfig) -> Self {
+        let has_port = backend.url.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+        let addr = match has_port {
+            true => backend.url.clone(),
+            false => format!("{}:{}", backend.url, default_port(backend.network)),
+        };
+        Self::connect(addr, backend.network)
+    }
+
+    /// Reach the peer through `connect`, which opens a fresh stream on
+    /// every (re)connect; `name` is shown as the source's URL.
+    pub fn with_connector<F, Fut, S>(name: impl Into<String>, network: Network, connect: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = std::io::Result<S>> + Send + 'static,
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let connect: Connector = Box::new(move || {
+            let stream = connect();
+            Box::pin(async move { Ok(Box::new(stream.await?) as Box<dyn ByteStream>) })
+        });
+        Self {
+            name: name.into(),
+            network,
+            connect,
+            started: AtomicBool::new(false),
+            tip: AtomicU32::new(0),
+            after: Mutex::new(None),
+            cache: Mutex::new(TxCache::default()),
+            mempool: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+            blocks: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+        }
+    }
+
+    /// Start the connection on first subscription.
+    fn start(self: &Arc<Self>) {
+        if !self.started.swap(true, Ordering::SeqCst) {
+            tokio::spawn(self.clone().run());
+        }
+    }
+
+    async fn run(self: Arc<Self>) {
+        let mut backoff = MIN_BACKOFF;
+        let mut chain = VecDeque::new();
+        loop {
+            let error = match (self.connect)().await {
+                Ok(stream) => self.session(Peer::new(stream, self.network), &mut chain, &mut backoff).await,
+                Err(e) => e.into(),
+            };
+            tracing::warn!(peer = %self.name, error = %error, retry_in = ?backoff, "Peer connection lost");
+            let _ = self.mempool.send(Err(error.to_string()));
+            let _ = self.blocks.send(Err(error.to_string()));
+
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Handle one connection's messages until it fails.
+    async fn session(&self, mut peer: Peer<Box<dyn ByteStream>>, chain: &mut VecDeque<(u32, String)>, backoff: &mut Duration) -> anyhow::Error {
+        let height = match tokio::time::timeout(HANDSHAKE_TIMEOUT, peer.handshake()).await {
+            Ok(Ok(height)) => height,
+            Ok(Err(e)) => return e,
+            Err(_) => return anyhow::anyhow!("Handshake timed out"),
+        };
+        self.tip.fetch_max(height, Ordering::SeqCst);
+        *backoff = MIN_BACKOFF;
+        tracing::info!(peer = %self.name, height, "Connected to peer");
+        if let Err(e) = self.catch_up(&mut peer, chain, height).await {
+            return e;
+        }
+
+        // Report the source synced every so often while the peer is alive,
+        // which the first tick does straight away
+        let mut synced = tokio::time::interval(SYNCED_INTERVAL);
+        let mut last_message = Instant::now();
+        loop {
+            let (command, payload) = tokio::select! {
+                message = peer.receive() => match message {
+                    Ok(message) => message,
+                    Err(e) => return e,
+                },
+                _ = synced.tick() => {
+                    if last_message.elapsed() > IDLE_TIMEOUT {
+                        return anyhow::anyhow!("Peer went quiet");
+                    }
+                    let _ = self.mempool.send(Ok(MempoolEvent::Synced));
+                    let _ = self.blocks.send(Ok(BlockEvent::Synced));
+                    continue;
+                }
+            };
+            last_message = Instant::now();
+            let handled = match command.as_str() {
+                "ping" => peer.send("pong", &payload).await,
+                "inv" => match self.wanted(&payload) {
+                    Ok(Some(request)) => peer.send("getdata", &request).await,
+                    Ok(None) => Ok(()),
+                    Err(e) => Err(e),
+                },
+                "headers" => self.receive_headers(&mut peer, &payload, chain).await,
+                "tx" => {
+                    self.receive_tx(&payload);
+                    Ok(())
+                }
+                "block" => {
+                    self.receive_block(&payload, chain);
+                    Ok(())
+                }
+                _ => Ok(()),
+            };
+            if let Err(e) = handled {
+                return e;
+            }
+        }
+    }
+
+    /// On reconnect, ask for the headers of blocks after the last one
+    /// seen, to fetch what was missed. Blocks from before the first
+    /// connection cannot be found without a known hash, so that gap is
+    /// only reported.
+    async fn catch_up(&self, peer: &mut Peer<Box<dyn ByteStream>>, chain: &VecDeque<(u32, String)>, height: u32) -> anyhow::Result<()> {
+        let missed_from = self.after.lock().unwrap().take().map(|after| after + 1);
+        if chain.is_empty() {
+            if let Some(from) = missed_from.filter(|from| *from <= height) {
+                tracing::warn!(
+                    peer = %self.name,
+                    from,
+                    to = height,
+                    "Blocks mined before the P2P source connected cannot be fetched from the peer and are not scanned",
+                );
+            }
+            return Ok(());
+        }
+
+        let locator: Vec<[u8; 32]> = chain.iter().rev().filter_map(|(_, hash)| hash_bytes(hash)).collect();
+        peer.send("getheaders", &getheaders_payload(&locator)).await
+    }
+
+    /// Request the blocks in a headers reply that are not in `chain`, and
+    /// the next headers if the reply was full.
+    async fn receive_headers(&self, peer: &mut Peer<Box<dyn ByteStream>>, payload: &[u8], chain: &VecDeque<(u32, String)>) -> anyhow::Result<()> {
+        let mut reader = Reader::new(payload);
+        let count = reader.compact_size()? as usize;
+        let mut hashes = Vec::with_capacity(count);
+        for _ in 0..count {
+            hashes.push(decode::sha256d(reader.bytes(80)?));
+            reader.compact_size()?; // transaction count, always zero
+        }
+
+        let unseen: Vec<(u32, &[u8])> = hashes
+            .iter()
+            .filter(|hash| !chain.iter().any(|(_, known)| *known == decode::hash_to_hex(hash.as_slice())))
+            .map(|hash| (INV_BLOCK, hash.as_slice()))
+            .collect();
+        if !unseen.is_empty() {
+            tracing::info!(peer = %self.name, blocks = unseen.len(), "Fetching blocks missed while disconnected");
+            peer.send("getdata", &getdata_payload(&unseen)).await?;
+        }
+        match hashes.last() {
+            Some(last) if count == MAX_HEADERS => peer.send("getheaders", &getheaders_payload(&[*last])).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// A getdata request for the announced transactions and blocks not
+    /// seen yet, with witnesses.
+    fn wanted(&self, inv: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut reader = Reader::new(inv);
+        let mut items = Vec::new();
+        for _ in 0..reader.compact_size()? {
+            let kind = reader.u32()?;
+            let hash = reader.bytes(32)?;
+            let known = kind == INV_TX && self.cache.lock().unwrap().txs.contains_key(&decode::hash_to_hex(hash));
+            if matches!(kind, INV_TX | INV_BLOCK) && !known {
+                items.push((kind, hash));
+            }
+        }
+        Ok((!items.is_empty()).then(|| getdata_payload(&items)))
+    }
+
+    fn remember(&self, tx: &Transaction) {
+        let mut cache = self.cache.lock().unwrap();
+        if cache.txs.insert(tx.txid.clone(), tx.clone()).is_none() {
+            cache.order.push_back(tx.txid.clone());
+        }
+        while cache.order.len() > TX_CACHE_SIZE {
+            if let Some(old) = cache.order.pop_front() {
+                cache.txs.remove(&old);
+            }
+        }
+    }
+
+    fn receive_tx(&self, raw: &[u8]) {
+        match decode::decode_transaction(raw, self.network) {
+            Ok(tx) => {
+                self.remember(&tx);
+                let _ = self.mempool.send(Ok(MempoolEvent::Added(tx)));
+            }
+            Err(e) => tracing::warn!(peer = %self.name, error = %e, "Undecodable transaction from peer"),
+        }
+    }
+
+    /// Connect a relayed block on top of the blocks seen this run, first
+    /// disconnecting any it replaces.
+    fn receive_block(&self, raw: &[u8], chain: &mut VecDeque<(u32, String)>) {
+        let height = coinbase_height(raw)
+            .or_else(|| chain.back().map(|(h, _)| h + 1))
+            .unwrap_or_else(|| self.tip.load(Ordering::SeqCst) + 1);
+        let (hash, mut transactions) = match decode::decode_block(raw, Some(height), self.network) {
+            Ok(block) => block,
+            Err(e) => {
+                tracing::warn!(peer = %self.name, error = %e, "Undecodable block from peer");
+                return;
+            }
+        };
+        if chain.iter().any(|(_, known)| *known == hash) {
+            return;
+        }
+
+        while let Some((stale_height, stale_hash)) = chain.back().filter(|(h, _)| *h >= height).cloned() {
+            chain.pop_back();
+            let _ = self.blocks.send(Ok(BlockEvent::Disconnected {
+                height: stale_height,
+                hash: stale_hash,
+            }));
+        }
+
+        let time = u32::from_le_bytes([raw[68], raw[69], raw[70], raw[71]]);
+        for tx in &mut transactions {
+            tx.status.block_time = Some(u64::from(time));
+            self.remember(tx);
+        }
+        chain.push_back((height, hash.clone()));
+        if chain.len() > REORG_DEPTH {
+            chain.pop_front();
+        }
+        self.tip.fetch_max(height, Ordering::SeqCst);
+
+        let _ = self.blocks.send(Ok(BlockEvent::Connected(Block { height, hash, transactions })));
+        let _ = self.blocks.send(Ok(BlockEvent::Synced));
+    }
+}
+
+/// Events from a channel as a stream; falling behind is reported as an error.
+fn events<T: Clone + Send + 'static>(rx: broadcast::Receiver<Result<T, String>>) -> EventStream<T> {
+    stream::unfold(rx, |mut rx| async move {
+        let event = match rx.recv().await {
+            Ok(event) => event.map_err(anyhow::Error::msg),
+            Err(broadcast::error::RecvError::Lagged(n)) => Err(anyhow::anyhow!("Fell behind the peer, {} events dropped", n)),
+            Err(broadcast::error::RecvError::Closed) => return None,
+        };
+        Some((event, rx))
+    })
+    .boxed()
+}
+
+#[async_trait]
+impl TransactionSource for P2pSource {
+    fn kind(&self) -> &'static str {
+        "p2p"
+    }
+
+    fn url(&self) -> &str {
+        &self.name
+    }
+
+    fn network(&self) -> Network {
+        self.network
+    }
+
+    /// Peers serve no txid lookups, so only recently relayed transactions
+    /// are found.
+    async fn get_transaction(&self, txid: &str) -> anyhow::Result<Transaction> {
+        let cache = self.cache.lock().unwrap();
+        cache.txs.get(txid).cloned().ok_or_else(|| SourceError::NotFound.into())
+    }
+
+    async fn tip_height(&self) -> anyhow::Result<u32> {
+        match self.tip.load(Ordering::SeqCst) {
+            0 => anyhow::bail!("Not connected to {} yet", self.name),
+            height => Ok(height),
+        }
+    }
+
+    /// Transactions the peer relays; there is no initial mempool pass, and
+    /// the source counts as synced while connected.
+    fn mempool_additions(self: Arc<Self>, _policy: ScanConfig) -> EventStream<MempoolEvent> {
+        let rx = self.mempool.subscribe();
+        self.start();
+        events(rx)
+    }
+
+    /// Blocks the peer announces while connected, and on reconnect those
+    /// missed while disconnected. Blocks after `after` mined before the
+    /// first connection are not fetched; a warning gives the gap.
+    fn block_events(self: Arc<Self>, _policy: ScanConfig, after: Option<u32>) -> EventStream<BlockEvent> {
+        *self.after.lock().unwrap() = after;
+        let rx = self.blocks.subscribe();
+        self.start();
+        events(rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::DuplexStream;
+
+    const NETWORK: Network = Network::Regtest;
+
+    /// A legacy transaction spending an outpoint made from `seed`.
+    fn raw_tx(seed: u8) -> Vec<u8> {
+        let mut tx = Vec::new();
+        tx.extend(2u32.to_le_bytes());
+        tx.push(1);
+        tx.extend([seed; 32]);
+        tx.extend(0u32.to_le_bytes());
+        tx.push(0); // script
+        tx.extend(u32::MAX.to_le_bytes());
+        tx.push(1);
+        tx.extend(546u64.to_le_bytes());
+        tx.extend([22, 0x00, 0x14]);
+        tx.extend([seed; 20]);
+        tx.extend(0u32.to_le_bytes());
+        tx
+    }
+
+    /// A coinbase whose script starts with `height_push`, in segwit form
+    /// if `segwit`.
+    fn coinbase(height_push: &[u8], segwit: bool) -> Vec<u8> {
+        let mut tx = Vec::new();
+        tx.extend(1u32.to_le_bytes());
+        if segwit {
+            tx.extend([0x00, 0x01]);
+        }
+        tx.push(1);
+        tx.extend([0u8; 32]);
+        tx.extend(u32::MAX.to_le_bytes());
+        tx.push(height_push.len() as u8);
+        tx.extend(height_push);
+        tx.extend(u32::MAX.to_le_bytes());
+        tx.push(1);
+        tx.extend(5_000_000_000u64.to_le_bytes());
+        tx.extend([1, 0x51]);
+        if segwit {
+            tx.extend([1, 32]);
+            tx.extend([0u8; 32]);
+        }
+        tx.extend(0u32.to_le_bytes());
+        tx
+    }
+
+    fn raw_block(prev: [u8; 32], transactions: &[Vec<u8>]) -> Vec<u8> {
+        let mut block = Vec::new();
+        block.extend(1u32.to_le_bytes());
+        block.extend(prev);
+        block.extend([0u8; 32]); // merkle root, not checked
+        block.extend(1_700_000_000u32.to_le_bytes());
+        block.extend(0x207f_ffffu32.to_le_bytes());
+        block.extend(0u32.to_le_bytes());
+        write_compact_size(&mut block, transactions.len());
+        for tx in transactions {
+            block.extend(tx);
+        }
+        block
+    }
+
+    fn inv(kind: u32, hash: &[u8; 32]) -> Vec<u8> {
+        let mut payload = vec![1];
+        payload.extend(kind.to_le_bytes());
+        payload.extend(hash);
+        payload
+    }
+
+    /// A source that connects to each of `streams` in turn.
+    fn source(streams: Vec<DuplexStream>) -> Arc<P2pSource> {
+        let streams = Mutex::new(VecDeque::from(streams));
+        Arc::new(P2pSource::with_connector("fake-peer", NETWORK, move || {
+            let stream = streams.lock().unwrap().pop_front();
+            async move { stream.ok_or_else(|| std::io::Error::other("no more connections")) }
+        }))
+    }
+
+    /// Play the peer's side of the handshake, reporting `height`.
+    async fn handshake(peer: &mut Peer<DuplexStream>, height: u32) {
+        assert_eq!(peer.receive().await.unwrap().0, "version");
+        let mut version = version_payload();
+        let at = version.len() - 5;
+        version[at..at + 4].copy_from_slice(&height.to_le_bytes());
+        peer.send("version", &version).await.unwrap();
+        peer.send("verack", &[]).await.unwrap();
+        assert_eq!(peer.receive().await.unwrap().0, "verack");
+    }
+
+    #[tokio::test]
+    async fn follows_a_scripted_peer() {
+        let (ours, theirs) = tokio::io::duplex(1 << 20);
+        let (ours_again, theirs_again) = tokio::io::duplex(1 << 20);
+        let source = source(vec![ours, ours_again]);
+        let mut mempool = source.clone().mempool_additions(ScanConfig::default());
+        let mut blocks = source.clone().block_events(ScanConfig::default(), None);
+
+        let mut peer = Peer::new(theirs, NETWORK);
+        handshake(&mut peer, 100).await;
+        assert!(matches!(mempool.next().await, Some(Ok(MempoolEvent::Synced))));
+        assert!(matches!(blocks.next().await, Some(Ok(BlockEvent::Synced))));
+        assert_eq!(source.tip_height().await.unwrap(), 100);
+
+        // An announced transaction is requested with its witness
+        let tx = raw_tx(7);
+        let txid = decode::sha256d(&tx);
+        peer.send("inv", &inv(INV_TX, &txid)).await.unwrap();
+        let (command, request) = peer.receive().await.unwrap();
+        assert_eq!(command, "getdata");
+        assert_eq!(request, [vec![1], (INV_TX | INV_WITNESS).to_le_bytes().to_vec(), txid.to_vec()].concat());
+
+        peer.send("tx", &tx).await.unwrap();
+        match mempool.next().await {
+            Some(Ok(MempoolEvent::Added(added))) => assert_eq!(added.txid, decode::hash_to_hex(&txid)),
+            other => panic!("expected a mempool addition, got {:?}", other.map(|e| e.is_ok())),
+        }
+        assert!(source.get_transaction(&decode::hash_to_hex(&txid)).await.is_ok());
+
+        let block = raw_block([0; 32], &[coinbase(&[1, 101], false), tx]);
+        let block_hash = decode::sha256d(&block[..80]);
+        peer.send("block", &block).await.unwrap();
+        match blocks.next().await {
+            Some(Ok(BlockEvent::Connected(connected))) => {
+                assert_eq!(connected.height, 101);
+                assert_eq!(connected.hash, decode::hash_to_hex(&block_hash));
+                assert_eq!(connected.transactions.len(), 2);
+            }
+            other => panic!("expected a connected block, got {:?}", other.map(|e| e.is_ok())),
+        }
+        assert!(matches!(blocks.next().await, Some(Ok(BlockEvent::Synced))));
+
+        // After a reconnect, blocks mined meanwhile are requested by header
+        drop(peer);
+        assert!(matches!(blocks.next().await, Some(Err(_))));
+        let mut peer = Peer::new(theirs_again, NETWORK);
+        handshake(&mut peer, 102).await;
+        let (command, request) = peer.receive().await.unwrap();
+        assert_eq!(command, "getheaders");
+        assert_eq!(request, getheaders_payload(&[block_hash]));
+
+        let missed = raw_block(block_hash, &[coinbase(&[1, 102], true)]);
+        let mut headers = vec![1];
+        headers.extend(&missed[..80]);
+        headers.push(0);
+        peer.send("headers", &headers).await.unwrap();
+        let (command, request) = peer.receive().await.unwrap();
+        assert_eq!(command, "getdata");
+        assert_eq!(request, getdata_payload(&[(INV_BLOCK, &decode::sha256d(&missed[..80]))]));
+
+        peer.send("block", &missed).await.unwrap();
+        let connected = loop {
+            match blocks.next().await {
+                Some(Ok(BlockEvent::Synced)) => continue,
+                Some(Ok(BlockEvent::Connected(block))) => break block,
+                other => panic!("expected a connected block, got {:?}", other.map(|e| e.is_ok())),
+            }
+        };
+        assert_eq!(connected.height, 102);
+    }
+
+    #[test]
+    fn coinbase_height_reads_bip34() {
+        let block = |push: &[u8], segwit| raw_block([0; 32], &[coinbase(push, segwit)]);
+        assert_eq!(coinbase_height(&block(&[3, 0x40, 0x0d, 0x03], false)), Some(200_000));
+        assert_eq!(coinbase_height(&block(&[3, 0x40, 0xd1, 0x0c], true)), Some(840_000));
+        assert_eq!(coinbase_height(&block(&[0x5a], false)), Some(10));
+        assert_eq!(coinbase_height(&block(&[0x00], false)), None);
+        assert_eq!(coinbase_height(&[0u8; 80]), None);
+    }
+
+    #[test]
+    fn wanted_skips_known_and_unknown_items() {
+        let source = source(Vec::new());
+        let tx = raw_tx(1);
+        source.receive_tx(&tx);
+        let known = decode::sha256d(&tx);
+        let (new_tx, block) = ([2u8; 32], [3u8; 32]);
+
+        let mut announcement = vec![4];
+        for (kind, hash) in [(INV_TX, known), (INV_TX, new_tx), (INV_BLOCK, block), (3, [4u8; 32])] {
+            announcement.extend(kind.to_le_bytes());
+            announcement.extend(hash);
+        }
+        let request = source.wanted(&announcement).unwrap().unwrap();
+        assert_eq!(request, getdata_payload(&[(INV_TX, &new_tx), (INV_BLOCK, &block)]));
+
+        assert_eq!(source.wanted(&inv(INV_TX, &known)).unwrap(), None);
+        assert!(source.wanted(&[1, 1, 0]).is_err());
+    }
+}
@@ -0,0 +1,125 @@
+// Transactions and blocks replayed from a fixture file
+use super::{Block, BlockEvent, EventStream, MempoolEvent, TransactionSource};
+use crate::config::ScanConfig;
+use crate::{SourceError, Transaction};
+use async_trait::async_trait;
+use futures_util::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Contents of a replay file: mempool transactions, then blocks in the
+/// order they are connected. A block at or below an earlier height
+/// disconnects the blocks it replaces, so reorgs can be replayed too.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Fixture {
+    #[serde(default)]
+    pub mempool: Vec<Transaction>,
+    #[serde(default)]
+    pub blocks: Vec<Block>,
+}
+
+pub struct ReplaySource {
+    name: String,
+    fixture: Fixture,
+    delay: Duration,
+}
+
+impl ReplaySource {
+    pub fn new(fixture: Fixture) -> Self {
+        Self {
+            name: "fixture".to_string(),
+            fixture,
+            delay: Duration::ZERO,
+        }
+    }
+
+    /// Load a JSON fixture.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let bytes = std::fs::read(path).map_err(|e| anyhow::anyhow!("Cannot read replay file {}: {}", path.display(), e))?;
+        let fixture = serde_json::from_slice(&bytes).map_err(|e| anyhow::anyhow!("Invalid replay file {}: {}", path.display(), e))?;
+        Ok(Self {
+            name: path.display().to_string(),
+            ..Self::new(fixture)
+        })
+    }
+
+    /// Pause between replayed events.
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    fn transactions(&self) -> impl Iterator<Item = &Transaction> {
+        self.fixture.mempool.iter().chain(self.fixture.blocks.iter().flat_map(|b| &b.transactions))
+    }
+}
+
+/// Emit `events` one at a time, `delay` apart, then stay open.
+fn replay<T: Send + 'static>(events: Vec<T>, delay: Duration) -> EventStream<T> {
+    stream::iter(events)
+        .then(move |event| async move {
+            tokio::time::sleep(delay).await;
+            Ok(event)
+        })
+        .chain(stream::pending())
+        .boxed()
+}
+
+#[async_trait]
+impl TransactionSource for ReplaySource {
+    fn kind(&self) -> &'static str {
+        "replay"
+    }
+
+    fn url(&self) -> &str {
+        &self.name
+    }
+
+    async fn get_transaction(&self, txid: &str) -> anyhow::Result<Transaction> {
+        self.transactions()
+            .find(|tx| tx.txid == txid)
+            .cloned()
+            .ok_or_else(|| SourceError::NotFound.into())
+    }
+
+    async fn tip_height(&self) -> anyhow::Result<u32> {
+        self.fixture
+            .blocks
+            .iter()
+            .map(|b| b.height)
+            .max()
+            .ok_or_else(|| anyhow::anyhow!("Replay file has no blocks"))
+    }
+
+    fn mempool_additions(self: Arc<Self>, _policy: ScanConfig) -> EventStream<MempoolEvent> {
+        let events = self
+            .fixture
+            .mempool
+            .iter()
+            .cloned()
+            .map(MempoolEvent::Added)
+            .chain([MempoolEvent::Synced])
+            .collect();
+        replay(events, self.delay)
+    }
+
+    /// Replays every block in the fixture, whatever was processed before.
+    fn block_events(self: Arc<Self>, _policy: ScanConfig, _after: Option<u32>) -> EventStream<BlockEvent> {
+        let mut events = Vec::new();
+        let mut chain: Vec<(u32, String)> = Vec::new();
+
+        for block in &self.fixture.blocks {
+            while let Some((height, hash)) = chain.last().filter(|(h, _)| *h >= block.height).cloned() {
+                events.push(BlockEvent::Disconnected { height, hash });
+                chain.pop();
+            }
+            chain.push((block.height, block.hash.clone()));
+            events.push(BlockEvent::Connected(block.clone()));
+        }
+        events.push(BlockEvent::Synced);
+
+        replay(events, self.delay)
+    }
+}
@@ -0,0 +1,234 @@
+// Bitcoin Core JSON-RPC, polled
+use super::{poll_blocks, poll_mempool, BlockEvent, EventStream, MempoolEvent, Poller, TransactionSource};
+use crate::config::{BackendConfig, Network, ScanConfig};
+use crate::{decode, is_valid_txid, metrics, Output, SourceError, Transaction, TxStatus};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// `RPC_INVALID_ADDRESS_OR_KEY`, returned for unknown transactions and blocks.
+const RPC_NOT_FOUND: i64 = -5;
+
+pub struct CoreRpcSource {
+    client: reqwest::Client,
+    url: String,
+    user: String,
+    pass: String,
+    network: Network,
+    next_id: AtomicU64,
+}
+
+impl CoreRpcSource {
+    pub fn new(url: &str, user: &str, pass: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            user: user.to_string(),
+            pass: pass.to_string(),
+            network: Network::Mainnet,
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    pub fn from_config(backend: &BackendConfig) -> anyhow::Result<Self> {
+        match (&backend.rpc_user, &backend.rpc_pass) {
+            (Some(user), Some(pass)) => Ok(Self {
+                network: backend.network,
+                ..Self::new(&backend.url, user, pass)
+            }),
+            _ => anyhow::bail!("backend.rpc_user and backend.rpc_pass are required for the rpc backend"),
+        }
+    }
+
+    /// Call an RPC method, recording request count, latency and errors.
+    #[tracing::instrument(level = "debug", skip(self, params), fields(backend = "rpc"))]
+    pub async fn call(&self, method: &'static str, params: Value) -> anyhow::Result<Value> {
+        let m = metrics::metrics();
+        let labels = ["rpc", method];
+        m.upstream_requests.with_label_values(&labels).inc();
+        let error = |kind: &str| m.upstream_errors.with_label_values(&["rpc", method, kind]).inc();
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let timer = m.upstream_duration.with_label_values(&labels).start_timer();
+        let result = self
+            .client
+            .post(&self.url)
+            .basic_auth(&self.user, Some(&self.pass))
+            .json(&json!({"jsonrpc": "1.0", "id": id, "method": method, "params": params}))
+            .send()
+            .await;
+        timer.observe_duration();
+
+        let resp = match result {
+            Ok(resp) => resp,
+            Err(e) => {
+                error(if e.is_timeout() { "timeout" } else { "network" });
+                return Err(SourceError::Unavailable(e.to_string()).into());
+            }
+        };
+        if resp.status() == reqwest::StatusCode::UNAUTHORIZED {
+            error("http_4xx");
+            return Err(SourceError::Unavailable("RPC authentication failed".to_string()).into());
+        }
+
+        // Core reports RPC errors in the body, with a 404 or 500 status
+        let status = resp.status();
+        let mut body: Value = match resp.json().await {
+            Ok(body) => body,
+            Err(_) => {
+                error(if status.is_server_error() { "http_5xx" } else { "http_4xx" });
+                return Err(SourceError::Unavailable(format!("HTTP {}", status)).into());
+            }
+        };
+        if let Some(rpc_error) = body.get("error").filter(|e| !e.is_null()) {
+            error("rpc_error");
+            if rpc_error["code"].as_i64() == Some(RPC_NOT_FOUND) {
+                return Err(SourceError::NotFound.into());
+            }
+            // Anything else is the node's failure (warming up, internal
+            // errors), not the caller's
+            return Err(SourceError::Unavailable(format!(
+                "RPC {} failed: {} (code {})",
+                method,
+                rpc_error["message"].as_str().unwrap_or("unknown error"),
+                rpc_error["code"]
+            ))
+            .into());
+        }
+
+        Ok(body["result"].take())
+    }
+}
+
+/// Core reports amounts in BTC.
+fn sats(btc: &Value) -> Option<u64> {
+    btc.as_f64().map(|btc| (btc * 100_000_000.0).round() as u64)
+}
+
+/// A spent output as reported by verbose `getrawtransaction`.
+fn prevout(entry: &Value) -> Option<Output> {
+    let script = entry["scriptPubKey"]["hex"].as_str()?;
+    Some(Output {
+        scriptpubkey: script.to_string(),
+        scriptpubkey_address: entry["scriptPubKey"]["address"].as_str().map(String::from),
+        value: sats(&entry["value"])?,
+    })
+}
+
+#[async_trait]
+impl Poller for CoreRpcSource {
+    /// Uses verbosity 2, which carries prevouts and the fee on Core 25 and
+    /// later; older nodes answer as for verbosity 1 and leave them unset.
+    /// Confirmed transactions cost a `getblockheader` call for the height.
+    async fn get_transaction(&self, txid: &str) -> anyhow::Result<Transaction> {
+        if !is_valid_txid(txid) {
+            return Err(SourceError::InvalidTxid.into());
+        }
+        let entry = self.call("getrawtransaction", json!([txid, 2])).await?;
+        let raw = entry["hex"].as_str().ok_or_else(|| anyhow::anyhow!("getrawtransaction returned no hex"))?;
+        let mut tx = decode::decode_transaction_hex(raw, self.network)?;
+
+        let inputs = entry["vin"].as_array().map(Vec::as_slice).unwrap_or_default();
+        for (input, entry) in tx.vin.iter_mut().zip(inputs) {
+            input.prevout = prevout(&entry["prevout"]);
+        }
+        tx.fee = sats(&entry["fee"]);
+
+        if let Some(hash) = entry["blockhash"].as_str() {
+            let header = self.call("getblockheader", json!([hash, true])).await?;
+            tx.status = TxStatus {
+                confirmed: true,
+                block_height: header["height"].as_u64().map(|h| h as u32),
+                block_time: entry["blocktime"].as_u64(),
+                block_index: None,
+            };
+        }
+        Ok(tx)
+    }
+
+    async fn recent_mempool_txids(&self, limit: usize) -> anyhow::Result<Vec<String>> {
+        let mempool = self.call("getrawmempool", json!([true])).await?;
+        let mut entries: Vec<(u64, String)> = mempool
+            .as_object()
+            .ok_or_else(|| anyhow::anyhow!("getrawmempool returned no entries"))?
+            .iter()
+            .map(|(txid, entry)| (entry["time"].as_u64().unwrap_or(0), txid.clone()))
+            .collect();
+        entries.sort_unstable_by(|a, b| b.cmp(a));
+        Ok(entries.into_iter().take(limit).map(|(_, txid)| txid).collect())
+    }
+
+    async fn mempool_txids(&self) -> anyhow::Result<Vec<String>> {
+        let mempool = self.call("getrawmempool", json!([false])).await?;
+        Ok(serde_json::from_value(mempool)?)
+    }
+
+    async fn tip(&self) -> anyhow::Result<(u32, String)> {
+        let info = self.call("getblockchaininfo", json!([])).await?;
+        let height = info["blocks"].as_u64().ok_or_else(|| anyhow::anyhow!("getblockchaininfo returned no height"))?;
+        let hash = info["bestblockhash"].as_str().ok_or_else(|| anyhow::anyhow!("getblockchaininfo returned no hash"))?;
+        Ok((height as u32, hash.to_string()))
+    }
+
+    async fn block_hash(&self, height: u32) -> anyhow::Result<String> {
+        let hash = self.call("getblockhash", json!([height])).await?;
+        hash.as_str().map(String::from).ok_or_else(|| anyhow::anyhow!("getblockhash returned no hash"))
+    }
+
+    /// Uses verbosity 2, which carries each transaction's hex and fee.
+    /// Prevouts are left unresolved.
+    async fn block_transactions(&self, hash: &str, height: u32, _policy: &ScanConfig) -> anyhow::Result<Vec<Transaction>> {
+        let block = self.call("getblock", json!([hash, 2])).await?;
+        let entries = block["tx"].as_array().ok_or_else(|| anyhow::anyhow!("getblock returned no transactions"))?;
+
+        entries
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| {
+                let raw = entry["hex"].as_str().ok_or_else(|| anyhow::anyhow!("getblock returned a transaction without hex"))?;
+                let mut tx = decode::decode_transaction_hex(raw, self.network)?;
+                tx.fee = sats(&entry["fee"]);
+                tx.status = TxStatus {
+                    confirmed: true,
+                    block_height: Some(height),
+                    block_time: block["time"].as_u64(),
+                    block_index: Some(index as u32),
+                };
+                Ok(tx)
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl TransactionSource for CoreRpcSource {
+    fn kind(&self) -> &'static str {
+        "rpc"
+    }
+
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn network(&self) -> Network {
+        self.network
+    }
+
+    async fn get_transaction(&self, txid: &str) -> anyhow::Result<Transaction> {
+        Poller::get_transaction(self, txid).await
+    }
+
+    async fn tip_height(&self) -> anyhow::Result<u32> {
+        let count = self.call("getblockcount", json!([])).await?;
+        count.as_u64().map(|h| h as u32).ok_or_else(|| anyhow::anyhow!("getblockcount returned no height"))
+    }
+
+    fn mempool_additions(self: Arc<Self>, policy: ScanConfig) -> EventStream<MempoolEvent> {
+        poll_mempool(self, policy)
+    }
+
+    fn block_events(self: Arc<Self>, policy: ScanConfig, after: Option<u32>) -> EventStream<BlockEvent> {
+        poll_blocks(self, policy, after)
+    }
+}
@@ -0,0 +1,74 @@
+// Persistence of monitor state between runs
+use crate::{LiveTransaction, ProtocolStats, WatchEntry};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Everything the monitor restores on startup. Token state is rebuilt by
+/// replaying `history`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub stats: HashMap<String, ProtocolStats>,
+    pub history: Vec<LiveTransaction>,
+    pub last_seq: u64,
+    pub synced_height: Option<u32>,
+    pub watchlist: HashMap<String, WatchEntry>,
+}
+
+pub trait Storage: Send + Sync {
+    /// The last saved snapshot, if there is one.
+    fn load(&self) -> anyhow::Result<Option<Snapshot>>;
+
+    fn save(&self, snapshot: &Snapshot) -> anyhow::Result<()>;
+}
+
+/// Keeps nothing; every run starts empty.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NullStorage;
+
+impl Storage for NullStorage {
+    fn load(&self) -> anyhow::Result<Option<Snapshot>> {
+        Ok(None)
+    }
+
+    fn save(&self, _snapshot: &Snapshot) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// A single JSON file, replaced atomically on each save.
+#[derive(Debug, Clone)]
+pub struct FileStorage {
+    path: PathBuf,
+}
+
+impl FileStorage {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+}
+
+impl Storage for FileStorage {
+    fn load(&self) -> anyhow::Result<Option<Snapshot>> {
+        match std::fs::read(&self.path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map(Some)
+                .map_err(|e| anyhow::anyhow!("Corrupt state file {}: {}", self.path.display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(anyhow::anyhow!("Cannot read state file {}: {}", self.path.display(), e)),
+        }
+    }
+
+    fn save(&self, snapshot: &Snapshot) -> anyhow::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec(snapshot)?)
+            .map_err(|e| anyhow::anyhow!("Cannot write state file {}: {}", tmp.display(), e))?;
+        std::fs::rename(&tmp, &self.path)
+            .map_err(|e| anyhow::anyhow!("Cannot replace state file {}: {}", self.path.display(), e))?;
+        Ok(())
+    }
+}
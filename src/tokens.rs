@@ -0,0 +1,494 @@
+// BRC-20 token and rune state built up from observed activity
+use crate::history::ActivityRecord;
+use crate::runestone::normalize_name;
+use crate::{Activity, LiveTransaction};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Number of recent activities kept per token.
+pub const RECENT_ACTIVITY: usize = 20;
+
+#[derive(Debug, Clone, Default)]
+pub struct Brc20Token {
+    pub tick: String,
+    pub deploy_txid: Option<String>,
+    pub deployed_at: Option<u64>,
+    pub max_supply: Option<f64>,
+    pub mint_limit: Option<f64>,
+    pub decimals: Option<u64>,
+    pub minted: f64,
+    pub mint_count: u64,
+    /// Mints above the mint limit or after the supply ran out, which add
+    /// nothing.
+    pub invalid_mints: u64,
+    /// Mints counted without checking the limits, because the deploy was
+    /// not seen.
+    pub unvalidated_mints: u64,
+    pub transfer_count: u64,
+    /// Distinct addresses seen receiving the token.
+    pub holders: HashSet<String>,
+    pub recent_activity: VecDeque<ActivityRecord>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RuneToken {
+    pub rune_id: Option<String>,
+    pub name: Option<String>,
+    pub etch_txid: Option<String>,
+    pub etched_at: Option<u64>,
+    pub divisibility: Option<u64>,
+    pub symbol: Option<String>,
+    pub premine: u128,
+    pub cap: Option<u128>,
+    pub mint_limit: Option<u128>,
+    pub terms: Option<serde_json::Value>,
+    pub mint_count: u64,
+    pub transfer_count: u64,
+    /// Distinct addresses seen receiving the rune.
+    pub holders: HashSet<String>,
+    pub recent_activity: VecDeque<ActivityRecord>,
+}
+
+/// Everything the monitor has learned about individual tokens. Supply and
+/// holder figures only cover transactions the monitor has actually seen.
+#[derive(Debug, Default)]
+pub struct TokenRegistry {
+    brc20: HashMap<String, Brc20Token>,
+    /// Keyed by rune id, or `name:<NAME>` for etchings not confirmed yet.
+    runes: HashMap<String, RuneToken>,
+    /// Normalized rune name to its key in `runes`.
+    rune_names: HashMap<String, String>,
+}
+
+pub(crate) fn number(value: Option<&serde_json::Value>) -> Option<f64> {
+    match value? {
+        serde_json::Value::Number(n) => n.as_f64(),
+        serde_json::Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+fn integer(value: Option<&serde_json::Value>) -> Option<u128> {
+    match value? {
+        serde_json::Value::Number(n) => n.as_u64().map(u128::from),
+        serde_json::Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+fn string(value: Option<&serde_json::Value>) -> Option<String> {
+    value?.as_str().map(String::from)
+}
+
+fn push_recent(recent: &mut VecDeque<ActivityRecord>, record: ActivityRecord) {
+    if recent.len() == RECENT_ACTIVITY {
+        recent.pop_back();
+    }
+    recent.push_front(record);
+}
+
+fn record(tx: &LiveTransaction, activity: &Activity) -> ActivityRecord {
+    ActivityRecord {
+        seq: tx.seq,
+        txid: tx.txid.clone(),
+        timestamp: tx.timestamp,
+        block_height: tx.block_height,
+        activity: activity.clone(),
+    }
+}
+
+impl Brc20Token {
+    /// How much a mint of `amount` adds under the BRC-20 rules: nothing
+    /// above the mint limit or once the max supply is reached, and only the
+    /// remainder for the mint that crosses it.
+    fn mint_amount(&self, amount: f64) -> Option<f64> {
+        if !amount.is_finite() || amount <= 0.0 || self.mint_limit.is_some_and(|limit| amount > limit) {
+            return None;
+        }
+        match self.max_supply {
+            Some(max) if self.minted >= max => None,
+            Some(max) => Some(amount.min(max - self.minted)),
+            None => Some(amount),
+        }
+    }
+
+    pub fn mint_progress(&self) -> Option<f64> {
+        let max = self.max_supply.filter(|max| *max > 0.0)?;
+        Some((self.minted / max * 100.0).min(100.0))
+    }
+
+    pub fn detail(&self) -> serde_json::Value {
+        serde_json::json!({
+            "tick": self.tick,
+            "deploy": {
+                "txid": self.deploy_txid,
+                "timestamp": self.deployed_at,
+                "max_supply": self.max_supply,
+                "limit": self.mint_limit,
+                "decimals": self.decimals,
+            },
+            "minted_supply": self.minted,
+            "mint_count": self.mint_count,
+            "invalid_mints": self.invalid_mints,
+            "unvalidated_mints": self.unvalidated_mints,
+            "transfer_count": self.transfer_count,
+            "mint_progress_pct": self.mint_progress(),
+            "holders": self.holders.len(),
+            "recent_activity": self.recent_activity,
+        })
+    }
+}
+
+impl RuneToken {
+    /// Fold in the entry kept for the same rune under its name.
+    fn merge(&mut self, other: RuneToken) {
+        self.name = self.name.take().or(other.name);
+        self.etch_txid = self.etch_txid.take().or(other.etch_txid);
+        self.etched_at = self.etched_at.or(other.etched_at);
+        self.divisibility = self.divisibility.or(other.divisibility);
+        self.symbol = self.symbol.take().or(other.symbol);
+        self.premine = self.premine.max(other.premine);
+        self.cap = self.cap.or(other.cap);
+        self.mint_limit = self.mint_limit.or(other.mint_limit);
+        self.terms = self.terms.take().or(other.terms);
+        self.mint_count += other.mint_count;
+        self.transfer_count += other.transfer_count;
+        self.holders.extend(other.holders);
+
+        let mut recent: Vec<ActivityRecord> = self.recent_activity.drain(..).chain(other.recent_activity).collect();
+        recent.sort_by_key(|record| std::cmp::Reverse(record.seq));
+        recent.truncate(RECENT_ACTIVITY);
+        self.recent_activity = recent.into();
+    }
+
+    pub fn minted_supply(&self) -> u128 {
+        let minted = self.mint_limit.unwrap_or(0).saturating_mul(self.mint_count as u128);
+        self.premine.saturating_add(minted)
+    }
+
+    pub fn mint_progress(&self) -> Option<f64> {
+        let cap = self.cap.filter(|cap| *cap > 0)?;
+        Some((self.mint_count as f64 / cap as f64 * 100.0).min(100.0))
+    }
+
+    pub fn detail(&self) -> serde_json::Value {
+        serde_json::json!({
+            "rune_id": self.rune_id,
+            "name": self.name,
+            "etching": {
+                "txid": self.etch_txid,
+                "timestamp": self.etched_at,
+                "divisibility": self.divisibility,
+                "symbol": self.symbol,
+                "premine": self.premine.to_string(),
+                "cap": self.cap.map(|c| c.to_string()),
+                "limit": self.mint_limit.map(|l| l.to_string()),
+                "terms": self.terms,
+            },
+            "minted_supply": self.minted_supply().to_string(),
+            "mint_count": self.mint_count,
+            "transfer_count": self.transfer_count,
+            "mint_progress_pct": self.mint_progress(),
+            "holders": self.holders.len(),
+            "recent_activity": self.recent_activity,
+        })
+    }
+}
+
+impl TokenRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn brc20(&self, tick: &str) -> Option<&Brc20Token> {
+        self.brc20.get(&tick.to_uppercase())
+    }
+
+    /// Look up a rune by id (`block:tx`) or by name, ignoring spacers.
+    pub fn rune(&self, name_or_id: &str) -> Option<&RuneToken> {
+        if name_or_id.contains(':') {
+            return self.runes.get(name_or_id);
+        }
+        self.runes.get(self.rune_names.get(&normalize_name(name_or_id))?)
+    }
+
+    pub fn brc20_count(&self) -> usize {
+        self.brc20.len()
+    }
+
+    pub fn rune_count(&self) -> usize {
+        self.runes.len()
+    }
+
+    pub fn apply(&mut self, tx: &LiveTransaction) {
+        for activity in &tx.activities {
+            match activity.protocol.as_str() {
+                "brc20" => self.apply_brc20(tx, activity),
+                "runes" => self.apply_rune(tx, activity),
+                _ => {}
+            }
+        }
+    }
+
+    fn apply_brc20(&mut self, tx: &LiveTransaction, activity: &Activity) {
+        let Some(tick) = string(activity.data.get("tick")) else { return };
+        let tick = tick.to_uppercase();
+        let token = self.brc20.entry(tick.clone()).or_insert_with(|| Brc20Token {
+            tick,
+            ..Default::default()
+        });
+
+        match activity.operation.as_str() {
+            // Only the first deploy of a tick counts
+            "deploy" if token.deploy_txid.is_some() => {}
+            "deploy" => {
+                token.deploy_txid = Some(tx.txid.clone());
+                token.deployed_at = Some(tx.timestamp);
+                token.max_supply = number(activity.data.get("max_supply"));
+                token.mint_limit = number(activity.data.get("limit"));
+                token.decimals = activity.data.get("decimals").and_then(|d| d.as_u64());
+            }
+            "mint" => {
+                let amount = number(activity.data.get("amount")).unwrap_or(0.0);
+                if token.deploy_txid.is_none() {
+                    token.unvalidated_mints += 1;
+                    token.mint_count += 1;
+                    token.minted += amount;
+                } else if let Some(amount) = token.mint_amount(amount) {
+                    token.mint_count += 1;
+                    token.minted += amount;
+                } else {
+                    token.invalid_mints += 1;
+                }
+            }
+            "transfer" => token.transfer_count += 1,
+            _ => {}
+        }
+
+        if let Some(address) = string(activity.data.get("address")) {
+            token.holders.insert(address);
+        }
+        push_recent(&mut token.recent_activity, record(tx, activity));
+    }
+
+    /// The entry for a rune, by id or else by name. Given both, as for a
+    /// confirmed etching, the name is linked to the id.
+    fn rune_entry(&mut self, rune_id: Option<String>, name: Option<String>) -> &mut RuneToken {
+        let normalized = name.as_deref().map(normalize_name);
+        let key = match (&rune_id, normalized) {
+            (Some(id), Some(normalized)) => {
+                self.link_rune(normalized, id);
+                id.clone()
+            }
+            (Some(id), None) => id.clone(),
+            (None, Some(normalized)) => self
+                .rune_names
+                .entry(normalized.clone())
+                .or_insert_with(|| format!("name:{}", normalized))
+                .clone(),
+            (None, None) => "name:".to_string(),
+        };
+
+        let token = self.runes.entry(key).or_default();
+        if token.rune_id.is_none() {
+            token.rune_id = rune_id;
+        }
+        if token.name.is_none() {
+            token.name = name;
+        }
+        token
+    }
+
+    /// Give a rune etched in the mempool the id its block assigned.
+    pub fn confirm_etching(&mut self, name: &str, id: &str) {
+        if name != "(reserved)" {
+            self.rune_entry(Some(id.to_string()), Some(name.to_string()));
+        }
+    }
+
+    /// Point `name` at the rune `id`, moving an entry kept under the name
+    /// (an etching seen in the mempool) to the id.
+    fn link_rune(&mut self, name: String, id: &str) {
+        let Some(previous) = self.rune_names.insert(name, id.to_string()) else { return };
+        if !previous.starts_with("name:") {
+            return;
+        }
+        if let Some(pending) = self.runes.remove(&previous) {
+            self.runes.entry(id.to_string()).or_default().merge(pending);
+        }
+    }
+
+    fn apply_rune(&mut self, tx: &LiveTransaction, activity: &Activity) {
+        let data = &activity.data;
+
+        match activity.operation.as_str() {
+            "etch" => {
+                // Unnamed etchings get a reserved name made from their id
+                let name = string(data.get("rune")).filter(|name| name != "(reserved)");
+                let token = self.rune_entry(string(data.get("rune_id")), name);
+                token.etch_txid = Some(tx.txid.clone());
+                token.etched_at = Some(tx.timestamp);
+                token.divisibility = data.get("divisibility").and_then(|d| d.as_u64());
+                token.symbol = string(data.get("symbol"));
+                token.premine = integer(data.get("premine")).unwrap_or(0);
+                token.cap = integer(data.get("cap"));
+                token.mint_limit = integer(data.get("limit"));
+                token.terms = data.get("terms").cloned();
+                if let Some(address) = string(data.get("address")) {
+                    token.holders.insert(address);
+                }
+                push_recent(&mut token.recent_activity, record(tx, activity));
+            }
+            "mint" => {
+                let token = self.rune_entry(string(data.get("rune_id")), string(data.get("rune")));
+                token.mint_count += 1;
+                if let Some(address) = string(data.get("address")) {
+                    token.holders.insert(address);
+                }
+                push_recent(&mut token.recent_activity, record(tx, activity));
+            }
+            "transfer" => {
+                let edicts = data.get("edicts").and_then(|e| e.as_array()).cloned().unwrap_or_default();
+
+                // Summarised transfers (e.g. demo mode) only carry a rune name
+                if edicts.is_empty() {
+                    if let Some(name) = string(data.get("rune")) {
+                        let token = self.rune_entry(None, Some(name));
+                        token.transfer_count += 1;
+                        push_recent(&mut token.recent_activity, record(tx, activity));
+                    }
+                    return;
+                }
+
+                let mut seen = HashSet::new();
+                for edict in &edicts {
+                    let Some(rune_id) = string(edict.get("rune_id")) else { continue };
+                    let token = self.rune_entry(Some(rune_id.clone()), None);
+                    if let Some(address) = string(edict.get("address")) {
+                        token.holders.insert(address);
+                    }
+                    if seen.insert(rune_id) {
+                        token.transfer_count += 1;
+                        push_recent(&mut token.recent_activity, record(tx, activity));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transaction(seq: u64, activity: Activity) -> LiveTransaction {
+        serde_json::from_value(serde_json::json!({
+            "seq": seq,
+            "txid": format!("{:064x}", seq),
+            "timestamp": 1_700_000_000 + seq,
+            "protocols": [activity.protocol],
+            "total_value": 546,
+            "activities": [activity],
+            "fee_rate": 1.0,
+            "size": 200,
+            "block_height": null,
+            "addresses": [],
+            "watched": [],
+        }))
+        .unwrap()
+    }
+
+    fn brc20(operation: &str, data: serde_json::Value) -> Activity {
+        activity("brc20", operation, data)
+    }
+
+    fn runes(operation: &str, data: serde_json::Value) -> Activity {
+        activity("runes", operation, data)
+    }
+
+    fn activity(protocol: &str, operation: &str, data: serde_json::Value) -> Activity {
+        serde_json::from_value(serde_json::json!({
+            "protocol": protocol,
+            "operation": operation,
+            "output": 0,
+            "data": data,
+            "changes": [],
+            "description": "",
+            "value_usd": null,
+            "importance": 5,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn brc20_mints_are_clamped_to_the_deploy() {
+        let mut tokens = TokenRegistry::new();
+        let deploy = brc20("deploy", serde_json::json!({ "tick": "ORDI", "max_supply": "2500", "limit": "1000", "decimals": 18 }));
+        tokens.apply(&transaction(1, deploy));
+        for (seq, amount) in [(2, "1000"), (3, "1001"), (4, "1000"), (5, "1000"), (6, "1000")] {
+            tokens.apply(&transaction(seq, brc20("mint", serde_json::json!({ "tick": "ordi", "amount": amount }))));
+        }
+
+        let token = tokens.brc20("ordi").unwrap();
+        assert_eq!(token.decimals, Some(18));
+        assert_eq!(token.minted, 2500.0);
+        assert_eq!(token.mint_count, 3);
+        assert_eq!(token.invalid_mints, 2);
+        assert_eq!(token.mint_progress(), Some(100.0));
+    }
+
+    #[test]
+    fn brc20_mints_without_deploy_are_unvalidated() {
+        let mut tokens = TokenRegistry::new();
+        tokens.apply(&transaction(1, brc20("mint", serde_json::json!({ "tick": "SATS", "amount": "5000" }))));
+
+        let token = tokens.brc20("SATS").unwrap();
+        assert_eq!(token.minted, 5000.0);
+        assert_eq!(token.unvalidated_mints, 1);
+    }
+
+    fn etching(rune_id: Option<&str>) -> Activity {
+        runes("etch", serde_json::json!({
+            "rune": "UNCOMMON•GOODS",
+            "rune_id": rune_id,
+            "divisibility": 2,
+            "premine": "1000",
+            "cap": "10",
+            "limit": "500",
+        }))
+    }
+
+    #[test]
+    fn rune_mints_and_edicts_reach_the_etched_rune() {
+        let mut tokens = TokenRegistry::new();
+        tokens.apply(&transaction(1, etching(Some("840000:7"))));
+        tokens.apply(&transaction(2, runes("mint", serde_json::json!({ "rune_id": "840000:7" }))));
+        let edicts = serde_json::json!({ "edicts": [{ "rune_id": "840000:7", "amount": "50", "output": 1, "address": "bc1qholder" }] });
+        tokens.apply(&transaction(3, runes("transfer", edicts)));
+
+        assert_eq!(tokens.rune_count(), 1);
+        let rune = tokens.rune("840000:7").unwrap();
+        assert!(std::ptr::eq(rune, tokens.rune("uncommon.goods").unwrap()));
+        assert_eq!(rune.mint_count, 1);
+        assert_eq!(rune.transfer_count, 1);
+        assert_eq!(rune.minted_supply(), 1500);
+        assert_eq!(rune.mint_progress(), Some(10.0));
+        assert_eq!(rune.divisibility, Some(2));
+    }
+
+    #[test]
+    fn confirmed_etching_moves_the_mempool_entry_to_its_id() {
+        let mut tokens = TokenRegistry::new();
+        tokens.apply(&transaction(1, etching(None)));
+        assert_eq!(tokens.rune("UNCOMMONGOODS").and_then(|r| r.rune_id.clone()), None);
+
+        tokens.apply(&transaction(2, runes("mint", serde_json::json!({ "rune_id": "840000:7" }))));
+        tokens.apply(&transaction(3, etching(Some("840000:7"))));
+
+        assert_eq!(tokens.rune_count(), 1);
+        let rune = tokens.rune("UNCOMMONGOODS").unwrap();
+        assert_eq!(rune.rune_id.as_deref(), Some("840000:7"));
+        assert_eq!(rune.mint_limit, Some(500));
+        assert_eq!(rune.mint_count, 1);
+    }
+}